@@ -0,0 +1,53 @@
+//! Build script that embeds details about the build into the binary: the git commit the bot was
+//! built from, the version of the irc crate from Cargo.lock, and the version of the compiler.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/**
+ * Run the given program and return the first line of its output, if it succeeded.
+ */
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()?.lines().next().map(String::from)
+}
+
+/**
+ * Find the version of the given package in Cargo.lock.
+ */
+fn locked_version(package: &str) -> Option<String> {
+    let lock = fs::read_to_string("Cargo.lock").ok()?;
+    let mut lines = lock.lines();
+    lines.find(|line| *line == format!("name = \"{}\"", package))?;
+    lines.next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(String::from)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    // Branches may only be recorded in packed-refs, which not every checkout has; a missing file
+    // would make cargo run this script on every build
+    if Path::new(".git/packed-refs").exists() {
+        println!("cargo:rerun-if-changed=.git/packed-refs");
+    }
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    if let Some(commit) = output("git", &["rev-parse", "--short", "HEAD"]) {
+        println!("cargo:rustc-env=METARBOT_GIT_COMMIT={}", commit);
+    }
+    if let Some(version) = locked_version("irc") {
+        println!("cargo:rustc-env=METARBOT_IRC_VERSION={}", version);
+    }
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=METARBOT_RUSTC_VERSION={}", version);
+    }
+}
@@ -4,6 +4,7 @@
 #![deny(missing_docs)]
 #![recursion_limit="512"]
 
+extern crate chrono;
 extern crate clap;
 extern crate futures;
 extern crate irc;
@@ -15,18 +16,40 @@ extern crate reqwest;
 #[macro_use] extern crate log;
 
 use irc::client::prelude::*;
+use irc::proto::{CapSubCommand, message::Tag};
 use futures::{
     prelude::*,
+    channel::mpsc,
+    future,
     future::FutureExt,
-    stream::FuturesUnordered,
+    future::LocalBoxFuture,
+    stream::{FuturesUnordered, LocalBoxStream, SelectAll},
     select,
 };
 
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::panic;
+use std::sync::{Arc, Mutex, RwLock};
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
 use std::vec::Vec;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Semaphore;
 
 use metarbot::{
     BotCommand,
+    BotCommandResult,
+    BotError,
+    ErrorLog,
+    InvocationContext,
+    ObserveParameters,
+    Stats,
+    VerboseChannels,
     BotParameters,
     BotResponse,
     modules,
@@ -35,7 +58,183 @@ use metarbot::{
 
 static EMPTY_LEADERS: Vec<char> = vec![];
 
-fn handle_response(client: &Client, response: BotResponse) -> irc::error::Result<()> {
+/**
+ * The events the main loop waits for.
+ */
+enum Event {
+    /** A message from the server, or None once the connection has been closed */
+    Message(Option<irc::error::Result<Message>>),
+
+    /** The result of a command that has completed, and how to deliver it */
+    Result((Delivery, BotCommandResult)),
+
+    /** An interim response sent by a command that is still running or by observe(), and how to deliver it */
+    Interim((Delivery, BotResponse)),
+
+    /** Time to check whether the connection is still alive */
+    Watchdog,
+
+    /** The process received SIGINT or SIGTERM */
+    Shutdown,
+}
+
+/**
+ * A future that completes once the process has received SIGINT or SIGTERM. It is shared by all
+ * networks, so every one of them can quit.
+ */
+type Shutdown = future::Shared<LocalBoxFuture<'static, ()>>;
+
+/**
+ * Wait for the given delay, returning true if the bot was told to shut down in the meantime.
+ */
+async fn delay_or_shutdown(delay: Duration, shutdown: Shutdown) -> bool {
+    select! {
+        _ = tokio::time::delay_for(delay).fuse() => false,
+        _ = shutdown.fuse() => true,
+    }
+}
+
+/**
+ * The most recent raw lines received from the server, kept for post-mortem debugging and dumped to
+ * the log when the bot panics or exits with an error.
+ */
+struct RecentMessages {
+    network: String,
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentMessages {
+    fn new(network: &str, capacity: usize) -> RecentMessages {
+        RecentMessages { network: network.to_string(), lines: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn dump(&self) {
+        error!("last {} messages received from {}:", self.lines.len(), self.network);
+        for line in &self.lines {
+            error!("  {}", line);
+        }
+    }
+}
+
+/**
+ * Responses of commands that opted into caching using BotCommand::cache_key, keyed by the trigger
+ * and the command's cache key, along with the instant at which they expire.
+ */
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<(&'static str, String), (Instant, BotResponse)>,
+}
+
+impl ResponseCache {
+    fn get(&mut self, trigger: &'static str, key: &str) -> Option<BotResponse> {
+        let now = Instant::now();
+        self.entries.retain(|_, (expires, _)| *expires > now);
+        self.entries.get(&(trigger, key.to_string())).map(|(_, response)| response.clone())
+    }
+
+    fn insert(&mut self, trigger: &'static str, key: String, ttl: Duration, response: BotResponse) {
+        self.entries.insert((trigger, key), (Instant::now() + ttl, response));
+    }
+}
+
+/**
+ * What to do with an invocation according to the RateLimiter.
+ */
+#[derive(Debug, PartialEq)]
+enum Throttle {
+    /** Run the command */
+    Allow,
+    /** Drop the invocation, and tell the user once per cooldown */
+    Notify,
+    /** Drop the invocation silently */
+    Drop,
+}
+
+/**
+ * Enforces a minimum interval between invocations of the same command by the same user, so a
+ * single user cannot flood the channel or the APIs used by commands.
+ */
+struct RateLimiter {
+    interval: Duration,
+    last: HashMap<(String, &'static str), (Instant, bool)>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> RateLimiter {
+        RateLimiter { interval, last: HashMap::new() }
+    }
+
+    /**
+     * Decide whether the given user may invoke the command with the given trigger now. Only
+     * allowed invocations restart the cooldown.
+     */
+    fn check(&mut self, nickname: &str, trigger: &'static str, now: Instant) -> Throttle {
+        let interval = self.interval;
+        self.last.retain(|_, (last, _)| now.duration_since(*last) < interval);
+        match self.last.get_mut(&(nickname.to_lowercase(), trigger)) {
+            Some((_, notified)) if *notified =>
+                Throttle::Drop,
+            Some((_, notified)) => {
+                *notified = true;
+                Throttle::Notify
+            },
+            None => {
+                self.last.insert((nickname.to_lowercase(), trigger), (now, false));
+                Throttle::Allow
+            },
+        }
+    }
+}
+
+/**
+ * Serializes the commands of each user, so a user's command only starts once their previous one
+ * has completed and responses arrive in the order of the invocations. Commands of different users
+ * still run concurrently.
+ */
+#[derive(Default)]
+struct UserQueues {
+    queues: HashMap<String, Rc<Semaphore>>,
+}
+
+impl UserQueues {
+    /**
+     * The queue of the given user. A command must hold a permit of it while it runs.
+     */
+    fn queue(&mut self, nickname: &str) -> Rc<Semaphore> {
+        // Queues that nobody holds or waits for are dropped, so the map does not grow forever
+        self.queues.retain(|_, queue| Rc::strong_count(queue) > 1);
+        Rc::clone(self.queues.entry(nickname.to_lowercase()).or_insert_with(|| Rc::new(Semaphore::new(1))))
+    }
+}
+
+/**
+ * Send a privmsg or notice, split into several messages at word boundaries if the text would be
+ * truncated by the server otherwise, unless split_long_messages is disabled.
+ */
+fn send_split<F>(target: &str, text: &str, options: &HashMap<String, String>, send: F) -> irc::error::Result<()>
+    where F: Fn(&str) -> irc::error::Result<()>
+{
+    if options.get("split_long_messages").map(String::as_str) == Some("false") {
+        return send(text);
+    }
+    for line in util::split_message(text, util::max_message_length(target, options)) {
+        send(&line)?;
+    }
+    Ok(())
+}
+
+fn handle_response(client: &Client, options: &HashMap<String, String>, response: BotResponse) -> irc::error::Result<()> {
     match response {
         BotResponse::Ignore =>
             Ok(()),
@@ -45,90 +244,2184 @@ fn handle_response(client: &Client, response: BotResponse) -> irc::error::Result
             client.send(Command::PART(channel, part_message)),
         BotResponse::Join(channel) =>
             client.send_join(channel),
+        BotResponse::JoinKeyed(channels, keys) =>
+            client.send(Command::JOIN(channels, keys, None)),
+        BotResponse::Kick(channel, nickname, reason) =>
+            client.send(Command::KICK(channel, nickname, reason)),
+        BotResponse::Topic(channel, topic) =>
+            client.send(Command::TOPIC(channel, topic)),
+        BotResponse::Invite(nickname, channel) =>
+            client.send_invite(nickname, channel),
+        BotResponse::Nick(nickname) =>
+            client.send(Command::NICK(nickname)),
         BotResponse::Privmsg(target, message) =>
-            client.send_privmsg(target, message),
+            send_split(&target, &message, options, |line| client.send_privmsg(&target, line)),
         BotResponse::Notice(target, message) =>
-            client.send_notice(target, message),
+            send_split(&target, &message, options, |line| client.send_notice(&target, line)),
+        BotResponse::Action(target, message) =>
+            client.send_privmsg(target, format!("\u{1}ACTION {}\u{1}", message)),
+        BotResponse::Raw(command) =>
+            client.send(command),
+        BotResponse::Multi(responses) => {
+            for response in responses {
+                if let Err(e) = handle_response(client, options, response) {
+                    warn!("error handling response: {:?}", e);
+                }
+            }
+            Ok(())
+        },
+        BotResponse::Reply(msgid, response) => {
+            let send = |command: Command| client.send(Message {
+                tags: Some(vec![Tag("+draft/reply".to_string(), Some(msgid.clone()))]),
+                prefix: None,
+                command,
+            });
+            match *response {
+                BotResponse::Privmsg(target, message) =>
+                    send_split(&target, &message, options, |line| send(Command::PRIVMSG(target.clone(), line.to_string()))),
+                BotResponse::Notice(target, message) =>
+                    send_split(&target, &message, options, |line| send(Command::NOTICE(target.clone(), line.to_string()))),
+                BotResponse::Action(target, message) =>
+                    send(Command::PRIVMSG(target, format!("\u{1}ACTION {}\u{1}", message))),
+                response =>
+                    handle_response(client, options, response),
+            }
+        },
+    }
+}
+
+/**
+ * Initialize logging. RUST_LOG takes precedence if it is set; otherwise the given filter from the
+ * configuration is used, which takes the same syntax as RUST_LOG (e.g. "metarbot=debug,irc=warn").
+ */
+fn init_logger(filter: Option<&str>) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Some(filter) = log_filter(env::var("RUST_LOG").ok(), filter) {
+        builder.parse_filters(&filter);
+    }
+    builder.init();
+}
+
+/**
+ * The log filter to use given the value of RUST_LOG and the filter from the configuration, if any.
+ */
+fn log_filter(rust_log: Option<String>, configured: Option<&str>) -> Option<String> {
+    rust_log.or_else(|| configured.map(String::from))
+}
+
+/**
+ * Notifies the configured users whenever the bot has finished connecting to a server, at most once
+ * per debounce interval so that a reconnect loop does not spam them.
+ */
+struct ConnectNotifier {
+    recipients: Vec<String>,
+    debounce: Duration,
+    last_connect: Option<Instant>,
+    last_notification: Option<Instant>,
+}
+
+impl ConnectNotifier {
+    fn new(config: &Config) -> ConnectNotifier {
+        ConnectNotifier {
+            recipients: config.get_option("notify_on_connect").unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|recipient| !recipient.is_empty())
+                .map(String::from)
+                .collect(),
+            debounce: Duration::from_secs(
+                config.get_option("notify_debounce_secs").and_then(|secs| secs.parse().ok()).unwrap_or(300)),
+            last_connect: None,
+            last_notification: None,
+        }
+    }
+
+    fn on_connect(&mut self, server: &str) -> Vec<BotResponse> {
+        let now = Instant::now();
+        let since_last_connect = self.last_connect.map(|last_connect| now - last_connect);
+        self.last_connect = Some(now);
+
+        if self.recipients.is_empty() || self.last_notification.map_or(false, |last| now - last < self.debounce) {
+            return vec![];
+        }
+        self.last_notification = Some(now);
+
+        let text = match since_last_connect {
+            Some(since) => format!("Connected to {}, {}s after the previous connection", server, since.as_secs()),
+            None => format!("Connected to {}", server),
+        };
+        self.recipients.iter()
+            .map(|recipient| BotResponse::Notice(recipient.to_string(), text.clone()))
+            .collect()
+    }
+}
+
+/**
+ * Decide how to react to an INVITE: owners get the bot to join the channel they invited it to,
+ * everybody else is ignored, or told so if invite_notice is enabled.
+ */
+fn invite_response(message: &Message, channel: &str, owners: &[Prefix], owner_accounts: &[String], options: &HashMap<String, String>) -> BotResponse {
+    if !util::is_public(channel) {
+        BotResponse::Ignore
+    } else if util::is_message_from_owner(message, owners, owner_accounts, util::OwnerMatching::from_options(options)) {
+        BotResponse::Join(channel.to_string())
+    } else {
+        match (message.source_nickname(), options.get("invite_notice").map(String::as_str)) {
+            (Some(source_nickname), Some("true")) =>
+                BotResponse::Notice(
+                    source_nickname.to_string(),
+                    "You are not authorized to invite me".to_string()),
+            _ =>
+                BotResponse::Ignore,
+        }
+    }
+}
+
+/**
+ * Relay a WALLOPS to the admin channel if one is configured, or log it at info level otherwise.
+ */
+fn wallops_response(message: &Message, text: &str, wallops_channel: Option<&str>, network: &str) -> BotResponse {
+    let source = message.source_nickname().unwrap_or("server");
+    match wallops_channel {
+        Some(channel) =>
+            BotResponse::Privmsg(channel.to_string(), format!("WALLOPS from {}: {}", source, text)),
+        None => {
+            info!("{}: WALLOPS from {}: {}", network, source, text);
+            BotResponse::Ignore
+        },
+    }
+}
+
+/**
+ * Extract the message from the payload of a caught panic, if it has one.
+ */
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<no message>"
+    }
+}
+
+/**
+ * A trigger registered by more than one module. The command of the module that registered it first
+ * is kept, the later one is dropped.
+ */
+struct Collision {
+    trigger: &'static str,
+    kept: &'static str,
+    dropped: &'static str,
+}
+
+/**
+ * The commands created from the modules, as returned by build_registry.
+ */
+struct Registry {
+    /** The commands, keyed by trigger */
+    commands: HashMap<&'static str, Box<dyn BotCommand>>,
+    /** The names of the modules and the sorted triggers of the commands each of them provides */
+    modules: Vec<(&'static str, Vec<&'static str>)>,
+    /** The triggers that were registered more than once */
+    collisions: Vec<Collision>,
+}
+
+/**
+ * Create the commands of the given modules, keeping track of the module each command came from and
+ * of the collisions of triggers that were registered more than once.
+ */
+fn build_registry(modules: &[(&'static str, modules::ModuleFactory)]) -> Registry {
+    let mut commands: HashMap<&'static str, Box<dyn BotCommand>> = HashMap::new();
+    let mut origins: HashMap<&'static str, &'static str> = HashMap::new();
+    let mut collisions = Vec::new();
+    let mut module_list = Vec::new();
+    for (name, module) in modules {
+        let mut triggers = Vec::new();
+        for command in module() {
+            match origins.get(command.trigger()) {
+                Some(kept) =>
+                    collisions.push(Collision { trigger: command.trigger(), kept, dropped: name }),
+                None => {
+                    origins.insert(command.trigger(), name);
+                    triggers.push(command.trigger());
+                    commands.insert(command.trigger(), command);
+                },
+            }
+        }
+        triggers.sort();
+        module_list.push((*name, triggers));
+    }
+    Registry { commands, modules: module_list, collisions }
+}
+
+/**
+ * Select the modules that are not named in the comma-separated disabled list, warning about names
+ * that match no module, e.g. because of a typo.
+ */
+fn enabled_modules(modules: &[(&'static str, modules::ModuleFactory)], disabled: &str) -> Vec<(&'static str, modules::ModuleFactory)> {
+    let disabled: Vec<&str> = disabled.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in &disabled {
+        if !modules.iter().any(|(module, _)| module == name) {
+            warn!("cannot disable unknown module {}", name);
+        }
+    }
+    modules.iter()
+        .filter(|(module, _)| !disabled.contains(module))
+        .cloned()
+        .collect()
+}
+
+/**
+ * Parse the per-channel leaders configured with options such as "leaders.#weather" = "!", keyed by
+ * the lowercase channel name.
+ */
+fn parse_channel_leaders(options: &HashMap<String, String>) -> HashMap<String, Vec<char>> {
+    options.iter()
+        .filter_map(|(key, leaders)| key.strip_prefix("leaders.")
+            .map(|channel| (channel.to_lowercase(), leaders.chars().collect())))
+        .collect()
+}
+
+/**
+ * The leaders that trigger commands in the given channel: its own leaders if configured, and the
+ * global leaders otherwise.
+ */
+fn leaders_for<'a>(channel: &str, leaders: &'a Vec<char>, channel_leaders: &'a HashMap<String, Vec<char>>) -> &'a Vec<char> {
+    channel_leaders.get(&channel.to_lowercase()).unwrap_or(leaders)
+}
+
+/**
+ * Normalize a command phrase or the words a user typed for lookup: collapse whitespace and, unless
+ * case_sensitive is set, convert to lowercase.
+ */
+fn normalize_phrase(phrase: &str, case_sensitive: bool) -> String {
+    let phrase = phrase.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if case_sensitive {
+        phrase
+    } else {
+        phrase.to_lowercase()
+    }
+}
+
+/**
+ * A phrase registered by more than one command after normalization, e.g. "Metar" and "metar". The
+ * command with the first trigger in alphabetical order keeps the phrase.
+ */
+struct PhraseCollision {
+    phrase: String,
+    kept: &'static str,
+    dropped: &'static str,
+}
+
+/**
+ * Map the normalized phrases of all commands to their triggers, along with the phrases that collide
+ * after normalization. Only one of the commands is reachable through such a phrase.
+ */
+fn command_phrases(commands: &HashMap<&'static str, Box<dyn BotCommand>>, case_sensitive: bool) -> (HashMap<String, &'static str>, Vec<PhraseCollision>) {
+    let mut sorted: Vec<&Box<dyn BotCommand>> = commands.values().collect();
+    sorted.sort_by_key(|command| command.trigger());
+
+    let mut phrases: HashMap<String, &'static str> = HashMap::new();
+    let mut collisions = Vec::new();
+    for command in sorted {
+        for phrase in command.triggers() {
+            let normalized = normalize_phrase(phrase, case_sensitive);
+            match phrases.get(&normalized) {
+                Some(&kept) if kept != command.trigger() =>
+                    collisions.push(PhraseCollision { phrase: normalized, kept, dropped: command.trigger() }),
+                Some(_) =>
+                    (),
+                None => {
+                    phrases.insert(normalized, command.trigger());
+                },
+            }
+        }
+    }
+    (phrases, collisions)
+}
+
+/**
+ * Resolve the aliases configured with options such as "aliases.w" = "metar" to the triggers of the
+ * commands they stand for, following aliases of aliases. Aliases that would shadow a command,
+ * that are cyclic or that do not lead to a command are ignored with a warning.
+ */
+fn resolve_aliases(options: &HashMap<String, String>, phrases: &HashMap<String, &'static str>, case_sensitive: bool) -> HashMap<String, &'static str> {
+    let aliases: HashMap<String, String> = options.iter()
+        .filter_map(|(key, target)| key.strip_prefix("aliases.")
+            .map(|alias| (normalize_phrase(alias, case_sensitive), normalize_phrase(target, case_sensitive))))
+        .collect();
+
+    let mut resolved = HashMap::new();
+    for (alias, target) in &aliases {
+        if phrases.contains_key(alias) {
+            warn!("ignoring alias {}, which would shadow a command", alias);
+            continue
+        }
+
+        let mut visited = vec![alias.as_str()];
+        let mut target = target.as_str();
+        loop {
+            if let Some(trigger) = phrases.get(target) {
+                resolved.insert(alias.to_string(), *trigger);
+                break
+            }
+            if visited.contains(&target) {
+                warn!("ignoring alias {}, which is cyclic", alias);
+                break
+            }
+            match aliases.get(target) {
+                Some(next) => {
+                    visited.push(target);
+                    target = next;
+                },
+                None => {
+                    warn!("ignoring alias {} for unknown command {}", alias, target);
+                    break
+                },
+            }
+        }
+    }
+    resolved
+}
+
+/**
+ * Find the command invoked by the given tokens of a message, trying the longest phrase first, and
+ * return its trigger along with the remaining tokens, which are the arguments of the command.
+ */
+fn lookup_command<'a>(phrases: &HashMap<String, &'static str>, tokens: &'a [String], case_sensitive: bool) -> Option<(&'static str, &'a [String])> {
+    let longest = phrases.keys().map(|phrase| phrase.split(' ').count()).max().unwrap_or(1);
+    (1..=longest.min(tokens.len())).rev()
+        .find_map(|words| phrases.get(&normalize_phrase(&tokens[..words].join(" "), case_sensitive))
+            .map(|trigger| (*trigger, &tokens[words..])))
+}
+
+/**
+ * The maximum edit distance between an unknown command and a known one for it to be suggested.
+ */
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/**
+ * Find the single-word command phrase closest to the given unknown command, if it is at most
+ * MAX_SUGGESTION_DISTANCE edits away, and return it along with the trigger of its command.
+ */
+fn suggest_command<'a>(phrases: &'a HashMap<String, &'static str>, word: &str) -> Option<(&'a str, &'static str)> {
+    let word = word.to_lowercase();
+    phrases.iter()
+        .filter(|(phrase, _)| !phrase.contains(' '))
+        .map(|(phrase, trigger)| (util::edit_distance(&word, &phrase.to_lowercase()), phrase.as_str(), *trigger))
+        .filter(|(distance, _, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min()
+        .map(|(_, phrase, trigger)| (phrase, trigger))
+}
+
+/**
+ * The key under which the RateLimiter throttles suggestions, shared by all unknown commands.
+ */
+const SUGGESTION_KEY: &str = "did-you-mean";
+
+/**
+ * The notice suggesting the command closest to the unknown command word typed with the given leader
+ * in the given channel message, if there is one that is enabled in the channel. Suggestions are
+ * throttled per user like commands, so repeated typos do not flood anybody with notices.
+ */
+fn suggestion(
+    phrases: &HashMap<String, &'static str>,
+    word: &str,
+    leader: char,
+    message: &Message,
+    options: &HashMap<String, String>,
+    rate_limiter: &mut RateLimiter,
+    now: Instant,
+) -> Option<BotResponse> {
+    let channel = match message.command {
+        Command::PRIVMSG(ref target, _) => target.as_str(),
+        _ => return None,
+    };
+    let nickname = message.source_nickname()?;
+    let (phrase, trigger) = suggest_command(phrases, word)?;
+    if !util::is_command_enabled(Some(channel), trigger, options) {
+        return None;
+    }
+    match rate_limiter.check(nickname, SUGGESTION_KEY, now) {
+        Throttle::Allow =>
+            Some(BotResponse::Notice(nickname.to_string(), format!("Did you mean {}{}?", leader, phrase))),
+        Throttle::Notify | Throttle::Drop =>
+            None,
     }
 }
 
+/**
+ * Return the targets a command invoked by the given message may respond to: the channel it was
+ * invoked in, and the invoking user.
+ */
+fn allowed_targets(message: &Message) -> Vec<String> {
+    let mut targets = Vec::new();
+    if let Command::PRIVMSG(ref target, _) = message.command {
+        if util::is_public(target) {
+            targets.push(target.to_string());
+        }
+    }
+    if let Some(source_nickname) = message.source_nickname() {
+        targets.push(source_nickname.to_string());
+    }
+    targets
+}
+
+/**
+ * Return the targets the given command invoked by the given message may respond to if the guard is
+ * enabled and the command did not opt into cross-target sending, or None if it may respond anywhere.
+ */
+fn response_guard(command: &dyn BotCommand, message: &Message, enabled: bool) -> Option<Vec<String>> {
+    if enabled && !command.cross_target() {
+        Some(allowed_targets(message))
+    } else {
+        None
+    }
+}
+
+/**
+ * Suppress privmsgs and notices to targets that are not in the list of allowed targets.
+ */
+fn guard_response_target(trigger: &str, response: BotResponse, allowed_targets: &[String]) -> BotResponse {
+    match response {
+        BotResponse::Privmsg(ref target, _) | BotResponse::Notice(ref target, _) | BotResponse::Action(ref target, _)
+            if !allowed_targets.iter().any(|allowed| allowed.eq_ignore_ascii_case(target)) => {
+            warn!("suppressing response of {} to {}, which is outside of the invocation context", trigger, target);
+            BotResponse::Ignore
+        },
+        BotResponse::Multi(responses) =>
+            BotResponse::Multi(responses.into_iter()
+                .map(|response| guard_response_target(trigger, response, allowed_targets))
+                .collect()),
+        BotResponse::Reply(msgid, response) =>
+            BotResponse::Reply(msgid, Box::new(guard_response_target(trigger, *response, allowed_targets))),
+        response =>
+            response,
+    }
+}
+
+/**
+ * Turn the privmsgs, notices and actions of a response into replies to the message with the given
+ * message ID, leaving all other responses as they are.
+ */
+fn reply_to(msgid: &str, response: BotResponse) -> BotResponse {
+    match response {
+        BotResponse::Privmsg(..) | BotResponse::Notice(..) | BotResponse::Action(..) =>
+            BotResponse::Reply(msgid.to_string(), Box::new(response)),
+        BotResponse::Multi(responses) =>
+            BotResponse::Multi(responses.into_iter().map(|response| reply_to(msgid, response)).collect()),
+        response =>
+            response,
+    }
+}
+
+/**
+ * How the responses to a message are sent, both the result of a command and the interim responses it
+ * or observe() sends through its responder: restricted to the allowed targets, if given, and as
+ * replies to the message, if it has a message ID.
+ */
+#[derive(Clone, Debug, Default)]
+struct Delivery {
+    trigger: &'static str,
+    allowed_targets: Option<Vec<String>>,
+    msgid: Option<String>,
+}
+
+impl Delivery {
+    /**
+     * Prepare the given response for sending.
+     */
+    fn prepare(&self, response: BotResponse) -> BotResponse {
+        let response = match self.allowed_targets {
+            Some(ref allowed_targets) => guard_response_target(self.trigger, response, allowed_targets),
+            None => response,
+        };
+        match self.msgid {
+            Some(ref msgid) => reply_to(msgid, response),
+            None => response,
+        }
+    }
+}
+
+/**
+ * The interim responses of all invocations, each along with how to deliver it.
+ */
+type InterimResponses = SelectAll<LocalBoxStream<'static, (Delivery, BotResponse)>>;
+
+/**
+ * Create the responder of an invocation, or of a call to observe(), whose responses are delivered
+ * as given. Its responses are added to the interim responses.
+ */
+fn responder_for(delivery: Delivery, interim_responses: &mut InterimResponses) -> mpsc::UnboundedSender<BotResponse> {
+    let (responder, responses) = mpsc::unbounded();
+    interim_responses.push(responses.map(move |response| (delivery.clone(), response)).boxed_local());
+    responder
+}
+
+/**
+ * Send the result of an invocation or one of the interim responses, as given by its delivery.
+ */
+fn deliver(client: &Client, network: &str, options: &HashMap<String, String>, delivery: &Delivery, result: BotCommandResult) {
+    handle_result(client, network, options, result.map(|response| delivery.prepare(response)));
+}
+
+/**
+ * Run the handler of a command once the previous command of the user has completed, if user_queue is
+ * given, and its concurrency limit permits, catching any panic it raises. If the command opted into
+ * caching and a response is cached under its key, the handler is not called at all.
+ * Successful responses are cached if the command opted into caching. Errors are recorded, and
+ * reported to the channel if verbose errors are enabled there.
+ */
+async fn run_command(
+    command: &dyn BotCommand,
+    params: BotParameters<'_>,
+    cache_key: Option<(String, Duration)>,
+    response_cache: &RefCell<ResponseCache>,
+    user_queue: Option<Rc<Semaphore>>,
+    concurrency_limit: Option<&Semaphore>,
+) -> BotCommandResult {
+    let (options, errors, verbose, network) = (params.options, params.errors, params.verbose, params.network);
+    let channel = match params.message.command {
+        Command::PRIVMSG(ref target, _) if util::is_public(target) => Some(target.to_string()),
+        _ => None,
+    };
+    let response_target = params.message.response_target().map(String::from);
+
+    let cached = match cache_key {
+        Some((ref key, _)) => response_cache.borrow_mut().get(command.trigger(), key),
+        None => None,
+    };
+    let result = match cached {
+        Some(response) =>
+            Ok(response),
+        None => {
+            // Wait for the user's previous command before taking one of the command's concurrency slots
+            let _queued = match user_queue {
+                Some(ref queue) => Some(queue.acquire().await),
+                None => None,
+            };
+            let _permit = match concurrency_limit {
+                Some(semaphore) => Some(semaphore.acquire().await),
+                None => None,
+            };
+
+            let result = match AssertUnwindSafe(command.handle(params)).catch_unwind().await {
+                Ok(result) => result,
+                Err(payload) => {
+                    error!("{}: handler for {} panicked: {}", network, command.trigger(), panic_message(&*payload));
+                    Err(BotError::Panicked(command.trigger()))
+                },
+            };
+
+            // Only successful responses are cached, before errors are turned into notices below
+            if let (Some((key, ttl)), Ok(response)) = (cache_key, &result) {
+                response_cache.borrow_mut().insert(command.trigger(), key, ttl, response.clone());
+            }
+            result
+        },
+    };
+
+    // Messages meant for the user are not failures of the command, so they are not recorded
+    let result = match (result, response_target) {
+        (Err(BotError::UserMessage(text)), Some(response_target)) =>
+            Ok(BotResponse::Notice(response_target, text)),
+        (result, _) =>
+            result,
+    };
+
+    if let Err(ref err) = result {
+        errors.record(command.trigger(), err, options);
+    }
+
+    match (result, channel) {
+        (Err(err), Some(channel)) if verbose.is_verbose(&channel) => {
+            warn!("{}: error running {}: {:?}", network, command.trigger(), err);
+            Ok(BotResponse::Notice(
+                channel,
+                util::redact(&format!("Error running {}: {}", command.trigger(), err), options)))
+        },
+        (result, _) =>
+            result,
+    }
+}
+
+/**
+ * Return true iff sending the given response makes the bot quit, including a quit in a Multi.
+ */
+fn is_quit(response: &BotResponse) -> bool {
+    match response {
+        BotResponse::Quit(_) => true,
+        BotResponse::Multi(responses) => responses.iter().any(is_quit),
+        BotResponse::Reply(_, response) => is_quit(response),
+        _ => false,
+    }
+}
+
+/**
+ * The reply to the CTCP VERSION, PING or TIME request in the given message, if any. Replies are
+ * notices to the nick that sent the request, also for requests sent to a channel.
+ */
+fn ctcp_reply(message: &Message, version: &str) -> Option<BotResponse> {
+    let text = match message.command {
+        Command::PRIVMSG(_, ref text) => text,
+        _ => return None,
+    };
+    let (request, params) = util::ctcp_request(text)?;
+    let reply = match request.to_uppercase().as_str() {
+        "VERSION" => format!("VERSION {}", version),
+        "PING" => format!("PING {}", params),
+        "TIME" => format!("TIME {}", chrono::Local::now().to_rfc2822()),
+        _ => return None,
+    };
+    let nickname = message.source_nickname()?.to_string();
+    // Sent raw, so the reply is never split and keeps its framing
+    Some(BotResponse::Raw(Command::NOTICE(nickname, format!("\u{1}{}\u{1}", reply))))
+}
+
+fn handle_result(client: &Client, network: &str, options: &HashMap<String, String>, result: BotCommandResult) {
+    match result {
+        Err(e) => warn!("{}: error running command: {:?}", network, e),
+        Ok(response) =>
+            match handle_response(client, options, response) {
+                Ok(()) => (),
+                Err(e) => warn!("{}: error handling response: {:?}", network, e),
+            },
+    };
+}
+
+/**
+ * Wait for the in-flight command futures to complete and send their responses, so that users whose
+ * commands were still running when the bot was asked to quit get a reply. Each result is passed to
+ * handle. Futures that have not completed within drain_time are abandoned.
+ */
+async fn drain<F, T, H>(futures: &mut FuturesUnordered<F>, drain_time: Duration, mut handle: H)
+    where F: Future<Output = T>,
+          H: FnMut(T)
+{
+    let drained = tokio::time::timeout(drain_time, async {
+        while let Some(result) = futures.next().await {
+            handle(result);
+        }
+    }).await;
+
+    if drained.is_err() {
+        warn!("abandoning {} in-flight commands after waiting {:?}", futures.len(), drain_time);
+    }
+}
+
+/**
+ * Load the configuration files given on the command line. A directory stands for all .toml files
+ * in it, in alphabetical order. Versions that are not configured default to the metarbot version.
+ */
+fn load_configs<'a>(paths: impl Iterator<Item = &'a str>) -> Result<Vec<Config>, failure::Error> {
+    let mut files = Vec::new();
+    for path in paths {
+        if std::path::Path::new(path).is_dir() {
+            let pattern = std::path::Path::new(path).join("*.toml");
+            let mut entries = glob::glob(&pattern.to_string_lossy())?.collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(std::path::PathBuf::from(path));
+        }
+    }
+
+    files.iter()
+        .map(|file| {
+            let mut config = Config::load(file)?;
+            if config.version.is_none() {
+                config.version = Some(metarbot::version());
+            }
+            Ok(config)
+        })
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), failure::Error> {
     let args = clap::App::new("metarbot")
         .arg(
             clap::Arg::with_name("config-file")
                 .long("config-file")
+                .help("Configuration file, or directory of configuration files, of a network; may be given several times")
+                .multiple(true)
+                .number_of_values(1)
                 .default_value("config.toml"),
         )
         .get_matches();
 
-    pretty_env_logger::init();
+    let configs = load_configs(args.values_of("config-file").expect("default missing?"))?;
+    if configs.is_empty() {
+        return Err(failure::err_msg("no configuration files found"));
+    }
+    init_logger(configs[0].get_option("log_filter"));
 
-    let config = Config::load(args.value_of("config-file").expect("default missing?")).unwrap();
-    let leaders: Vec<char> = config.get_option("leaders").unwrap_or("&").chars().collect();
-    let owners: Vec<Prefix> = config.get_option("owners").unwrap_or(&"".to_string()).split(";").map(Prefix::new_from_str).collect();
+    let recent_messages: Vec<Arc<Mutex<RecentMessages>>> = configs.iter()
+        .map(|config| Arc::new(Mutex::new(RecentMessages::new(
+            config.server().unwrap_or(""),
+            config.get_option("recent_messages").and_then(|count| count.parse().ok()).unwrap_or(50)))))
+        .collect();
+    let default_panic_hook = panic::take_hook();
+    let panic_recent_messages = recent_messages.clone();
+    panic::set_hook(Box::new(move |info| {
+        default_panic_hook(info);
+        for recent_messages in &panic_recent_messages {
+            // try_lock, since the panic may have happened while the lock was held
+            if let Ok(recent_messages) = recent_messages.try_lock() {
+                recent_messages.dump();
+            }
+        }
+    }));
 
-    let mut commands : HashMap<&'static str, Box<dyn BotCommand>> = HashMap::new();
-    for module in modules::ALL {
-        for command in module() {
-            commands.insert(command.trigger(), command);
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let shutdown: Shutdown = async move {
+        select! {
+            _ = tokio::signal::ctrl_c().fuse() => (),
+            _ = terminate.recv().fuse() => (),
         }
+        info!("received signal, shutting down");
+    }.boxed_local().shared();
+
+    // Each network runs in a task of its own with its own client, commands and state, so a network
+    // that fails does not take the others down; the process ends once all have quit. The tasks are
+    // local, since the dispatch loop keeps its state in Rc and RefCell.
+    let names: Vec<String> = configs.iter().map(|config| config.server().unwrap_or("").to_string()).collect();
+    let local = tokio::task::LocalSet::new();
+    let tasks: Vec<_> = configs.into_iter()
+        .zip(recent_messages)
+        .map(|(config, recent_messages)| local.spawn_local(run_network(config, recent_messages, shutdown.clone())))
+        .collect();
+    let results = local.run_until(future::join_all(tasks)).await;
+
+    let mut failed = 0;
+    for (network, result) in names.iter().zip(results) {
+        // A task that panicked fails as well
+        if let Err(err) = result.map_err(failure::Error::from).and_then(|result| result) {
+            error!("{}: {}", network, err);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(failure::err_msg(format!("{} of {} networks failed", failed, names.len())));
+    }
+    Ok(())
+}
+
+/**
+ * Connect to the network of the given configuration and dispatch commands until the bot is told to
+ * quit or shut down, reconnecting whenever the connection is lost.
+ */
+async fn run_network(config: Config, recent_messages: Arc<Mutex<RecentMessages>>, shutdown: Shutdown) -> Result<(), failure::Error> {
+    let network = config.server().unwrap_or("").to_string();
+    let leaders: Vec<char> = config.get_option("leaders").unwrap_or("&").chars().collect();
+    let channel_leaders = parse_channel_leaders(&config.options);
+    let owners = RwLock::new(util::load_owners(&config.options)?);
+    let owner_accounts = util::load_owner_accounts(&config.options);
+    let ignored = RwLock::new(util::load_ignored(&config.options));
+    let leader_escape = config.get_option("leader_escape") == Some("true");
+    let strip_trailing_punctuation = config.get_option("strip_trailing_punctuation") == Some("true");
+    let wallops_channel = config.get_option("wallops_channel");
+    let guard_response_targets = config.get_option("guard_response_targets") != Some("false");
+    let drain_time = Duration::from_secs(
+        config.get_option("shutdown_drain_secs").and_then(|secs| secs.parse().ok()).unwrap_or(5));
+    let shutdown_timeout = Duration::from_secs(
+        config.get_option("shutdown_timeout_secs").and_then(|secs| secs.parse().ok()).unwrap_or(5));
+    let shutdown_message = config.get_option("shutdown_message").unwrap_or("Shutting down").to_string();
+
+    let Registry { commands, modules: module_list, collisions } = build_registry(&enabled_modules(
+        modules::ALL, config.get_option("disabled_modules").unwrap_or("")));
+    for collision in &collisions {
+        warn!("modules {} and {} both provide the {} command, using the one from {}",
+            collision.kept, collision.dropped, collision.trigger, collision.kept);
+    }
+    if !collisions.is_empty() && config.get_option("fatal_command_collisions") == Some("true") {
+        return Err(failure::err_msg("several modules provide the same command"));
     }
+    let mut command_list: Vec<(&'static str, &'static str)> = commands.values()
+        .map(|command| (command.trigger(), command.description()))
+        .collect();
+    command_list.sort();
+    let case_sensitive_commands = config.get_option("case_sensitive_commands") == Some("true");
+    let suggest_commands = config.get_option("suggest_commands") == Some("true");
+    let (mut phrases, phrase_collisions) = command_phrases(&commands, case_sensitive_commands);
+    for collision in &phrase_collisions {
+        warn!("commands {} and {} both register {}, only {} will be invoked",
+            collision.kept, collision.dropped, collision.phrase, collision.kept);
+    }
+    let aliases = resolve_aliases(&config.options, &phrases, case_sensitive_commands);
+    phrases.extend(aliases);
+    let concurrency_limits: HashMap<&'static str, Semaphore> = commands.iter()
+        .filter_map(|(trigger, command)| command.max_concurrency().map(|limit| (*trigger, Semaphore::new(limit))))
+        .collect();
+
+    let mut rate_limiter = RateLimiter::new(Duration::from_secs(
+        config.get_option("rate_limit_secs").and_then(|secs| secs.parse().ok()).unwrap_or(3)));
+    let rate_limit_notice = config.get_option("rate_limit_notice") == Some("true");
+    let mut connect_notifier = ConnectNotifier::new(&config);
+    let serialize_user_commands = config.get_option("serialize_user_commands") == Some("true");
+    let reply_threading = config.get_option("reply_threading") == Some("true");
+    let mut user_queues = UserQueues::default();
 
-    let mut client = Client::from_config(config.clone()).await?;
-    client.identify()?;
+    let reconnect_base = Duration::from_secs(
+        config.get_option("reconnect_base_secs").and_then(|secs| secs.parse().ok()).unwrap_or(5));
+    let reconnect_max = Duration::from_secs(
+        config.get_option("reconnect_max_secs").and_then(|secs| secs.parse().ok()).unwrap_or(300));
+    let mut reconnect_delay = reconnect_base;
 
-    let mut stream = client.stream()?;
-    let mut futures = FuturesUnordered::new();
+    // The connection is considered dead if nothing arrives within watchdog_timeout_secs of a PING,
+    // which is sent once nothing has been received for watchdog_idle_secs
+    let watchdog_idle = Duration::from_secs(
+        config.get_option("watchdog_idle_secs").and_then(|secs| secs.parse().ok()).unwrap_or(300));
+    let watchdog_timeout = Duration::from_secs(
+        config.get_option("watchdog_timeout_secs").and_then(|secs| secs.parse().ok()).unwrap_or(60));
+    let watchdog_period = (watchdog_idle.min(watchdog_timeout) / 2).max(Duration::from_secs(1));
+
+    let response_cache = RefCell::new(ResponseCache::default());
+    let error_log = ErrorLog::default();
+    let stats = Stats::default();
+    let verbose_channels = VerboseChannels::default();
+    // Kept across reconnects, so e.g. reminders are still sent after the connection was lost
+    let mut interim_responses = InterimResponses::new();
 
     loop {
-        select! {
-            maybe_message = stream.next() => {
-                if let Some(message) = maybe_message.transpose()? {
-                    if let Command::PRIVMSG(ref target, ref text) = message.command {
-                        let mut leader: Option<char> = None;
-                        let leader_required = util::is_public(target);
-                        if leader_required {
-                            let first_char = text.chars().next();
-                            if first_char.is_none() || !leaders.contains(&first_char.unwrap()) {
+        let connection = async {
+            let mut client = Client::from_config(config.clone()).await?;
+            // Without the capability, the server does not send the account tag owner_accounts rely on
+            let mut capabilities = Vec::new();
+            if !owner_accounts.is_empty() {
+                capabilities.push(Capability::AccountTag);
+            }
+            if reply_threading {
+                capabilities.push(Capability::Custom("message-tags"));
+            }
+            if !capabilities.is_empty() {
+                client.send_cap_req(&capabilities)?;
+            }
+            client.identify()?;
+            let stream = client.stream()?;
+            Ok::<_, irc::error::Error>((client, stream))
+        };
+        let (client, mut stream) = match connection.await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("{}: error connecting, retrying in {:?}: {}", network, reconnect_delay, err);
+                if delay_or_shutdown(reconnect_delay, shutdown.clone()).await {
+                    return Ok(());
+                }
+                reconnect_delay = (reconnect_delay * 2).min(reconnect_max);
+                continue
+            },
+        };
+        let mut futures = FuturesUnordered::new();
+        let mut quitting = false;
+        let mut watchdog = tokio::time::interval(watchdog_period).fuse();
+        let mut last_received = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+        let mut shutdown_signal = shutdown.clone().fuse();
+        // Replies are only tagged once the server has acknowledged the message-tags capability
+        let mut message_tags = false;
+        let version = config.version.clone().unwrap_or_else(metarbot::version);
+
+        let disconnect = loop {
+            let event = select! {
+                maybe_message = stream.next() => Event::Message(maybe_message),
+                result = futures.select_next_some() => Event::Result(result),
+                response = interim_responses.select_next_some() => Event::Interim(response),
+                _ = watchdog.select_next_some() => Event::Watchdog,
+                _ = shutdown_signal => Event::Shutdown,
+                complete => break None,
+            };
+
+            match event {
+                Event::Message(None) =>
+                    break None,
+                Event::Message(Some(maybe_message)) => {
+                    let message = match maybe_message {
+                        Ok(message) => message,
+                        Err(err) => {
+                            recent_messages.lock().unwrap_or_else(|err| err.into_inner()).dump();
+                            break Some(err);
+                        },
+                    };
+                    // Any message, not only the PONG, shows that the connection is alive
+                    last_received = Instant::now();
+                    ping_sent = None;
+                    recent_messages.lock().unwrap_or_else(|err| err.into_inner())
+                        .push(util::redact(message.to_string().trim_end(), &config.options));
+
+                    match message.command {
+                        Command::PRIVMSG(ref target, ref text) => {
+                            if util::is_own_message(&message, client.current_nickname()) {
                                 continue
                             }
-                            leader = first_char;
-                        }
-                        let tokens : Vec<String> = match leader {
-                            None => text,
-                            Some(first_char) => text.trim_start_matches(first_char),
-                        }.split_whitespace().map(String::from).collect();
-
-                        if let Some((ref cmd, ref args)) = tokens.split_first() {
-                            if let Some(command) = commands.get(cmd.to_lowercase().as_str()) {
-                                futures.push(command.handle(BotParameters {
-                                    message: message,
-                                    leaders: if leader_required { &leaders } else { &EMPTY_LEADERS },
-                                    owners: &owners,
-                                    args: args.to_vec(),
+
+                            // Owners cannot be ignored, so they can always undo an ignore
+                            let prefix = message.prefix.clone().unwrap_or_else(|| Prefix::new_from_str(""));
+                            let matching = util::OwnerMatching::from_options(&config.options);
+                            if util::is_owner_with(&prefix, &ignored.read().unwrap_or_else(|err| err.into_inner()), matching)
+                                    && !util::is_message_from_owner(&message, &owners.read().unwrap_or_else(|err| err.into_inner()), &owner_accounts, matching) {
+                                continue
+                            }
+
+                            if util::ctcp_request(text).is_some() {
+                                if let Some(reply) = ctcp_reply(&message, &version) {
+                                    handle_result(&client, &network, &config.options, Ok(reply));
+                                }
+                                continue
+                            }
+
+                            let msgid = util::message_tag(&message, "msgid")
+                                .filter(|_| message_tags)
+                                .map(String::from);
+                            let leaders = if util::is_public(target) { leaders_for(target, &leaders, &channel_leaders) } else { &leaders };
+                            for command in commands.values() {
+                                let delivery = Delivery {
+                                    trigger: command.trigger(),
+                                    allowed_targets: response_guard(command.as_ref(), &message, guard_response_targets),
+                                    msgid: msgid.clone(),
+                                };
+                                command.observe(ObserveParameters {
+                                    message: &message,
+                                    leaders,
                                     options: &config.options,
-                                }).fuse());
+                                    responder: responder_for(delivery, &mut interim_responses),
+                                }).await;
                             }
-                        }
+
+                            // Commands can also be sent using /me, e.g. "/me &metar KSFO"
+                            let text = util::strip_action(text);
+
+                            let mut leader: Option<char> = None;
+                            let leader_required = util::is_public(target);
+                            if leader_required {
+                                let first_char = text.chars().next();
+                                if first_char.is_none() || !leaders.contains(&first_char.unwrap()) {
+                                    continue
+                                }
+                                if leader_escape && util::is_escaped_leader(text, leaders) {
+                                    continue
+                                }
+                                leader = first_char;
+                            }
+                            let text = match leader {
+                                None => text,
+                                Some(first_char) => text.trim_start_matches(first_char),
+                            };
+                            // Quoted arguments may contain spaces; unterminated quotes are taken literally
+                            let mut tokens : Vec<String> = util::tokenize(text)
+                                .unwrap_or_else(|| text.split_whitespace().map(String::from).collect());
+
+                            // Allow natural phrasing such as "metar KSFO?"
+                            if strip_trailing_punctuation {
+                                util::strip_trailing_punctuation(&mut tokens);
+                            }
+
+                            if let Some((trigger, args)) = lookup_command(&phrases, &tokens, case_sensitive_commands) {
+                                if let Some(command) = commands.get(trigger) {
+                                    let channel = if leader_required { Some(target.as_str()) } else { None };
+                                    if !util::is_command_enabled(channel, command.trigger(), &config.options) {
+                                        continue
+                                    }
+
+                                    let is_owner = util::is_message_from_owner(&message, &owners.read().unwrap_or_else(|err| err.into_inner()), &owner_accounts, util::OwnerMatching::from_options(&config.options));
+                                    let logged_args = if command.redact_args() {
+                                        "<redacted>".to_string()
+                                    } else {
+                                        format!("{:?}", args)
+                                    };
+                                    if command.owner_only() {
+                                        info!("command network={} nick={} trigger={} target={} args={} authorized={}",
+                                            network, message.source_nickname().unwrap_or(""), command.trigger(), target, logged_args, is_owner);
+                                    } else {
+                                        info!("command network={} nick={} trigger={} target={} args={}",
+                                            network, message.source_nickname().unwrap_or(""), command.trigger(), target, logged_args);
+                                    }
+                                    if command.owner_only() && !is_owner {
+                                        if let Some(nickname) = message.source_nickname() {
+                                            handle_result(&client, &network, &config.options, Ok(BotResponse::Notice(
+                                                nickname.to_string(),
+                                                format!("You are not authorized to use the {} command", command.trigger()))));
+                                        }
+                                        continue
+                                    }
+                                    if let (Some(nickname), false) = (message.source_nickname(), is_owner) {
+                                        match rate_limiter.check(nickname, command.trigger(), Instant::now()) {
+                                            Throttle::Allow =>
+                                                (),
+                                            Throttle::Notify if rate_limit_notice => {
+                                                handle_result(&client, &network, &config.options, Ok(BotResponse::Notice(
+                                                    nickname.to_string(),
+                                                    format!("Please wait a few seconds before using {} again", command.trigger()))));
+                                                continue
+                                            },
+                                            Throttle::Notify | Throttle::Drop =>
+                                                continue,
+                                        }
+                                    }
+
+                                    stats.record(command.trigger());
+                                    let message_nickname = message.source_nickname().map(String::from);
+                                    let context = InvocationContext::new(&message, leader);
+                                    let delivery = Delivery {
+                                        trigger: command.trigger(),
+                                        allowed_targets: response_guard(command.as_ref(), &message, guard_response_targets),
+                                        msgid: msgid.clone(),
+                                    };
+                                    let params = BotParameters {
+                                        message,
+                                        leaders: if leader_required { leaders } else { &EMPTY_LEADERS },
+                                        owners: &owners,
+                                        owner_accounts: &owner_accounts,
+                                        ignored: &ignored,
+                                        args: args.to_vec(),
+                                        options: &config.options,
+                                        responder: responder_for(delivery.clone(), &mut interim_responses),
+                                        errors: &error_log,
+                                        verbose: &verbose_channels,
+                                        stats: &stats,
+                                        network: &network,
+                                        current_nick: client.current_nickname().to_string(),
+                                        channels: client.list_channels().unwrap_or_default(),
+                                        commands: &command_list,
+                                        modules: &module_list,
+                                        context,
+                                    };
+
+                                    let cache_key = match (command.cache_key(&params), command.cache_ttl()) {
+                                        (Some(key), Some(ttl)) => Some((key, ttl)),
+                                        _ => None,
+                                    };
+
+                                    let user_queue = match (serialize_user_commands, message_nickname) {
+                                        (true, Some(ref nickname)) => Some(user_queues.queue(nickname)),
+                                        _ => None,
+                                    };
+                                    let future: LocalBoxFuture<(Delivery, BotCommandResult)> =
+                                        run_command(
+                                            command.as_ref(),
+                                            params,
+                                            cache_key,
+                                            &response_cache,
+                                            user_queue,
+                                            concurrency_limits.get(command.trigger()))
+                                        .map(move |result| (delivery, result))
+                                        .boxed_local();
+                                    futures.push(future.fuse());
+                                }
+                            } else if let (true, Some(leader), Some(word)) = (suggest_commands, leader, tokens.first()) {
+                                if let Some(suggestion) = suggestion(&phrases, word, leader, &message, &config.options, &mut rate_limiter, Instant::now()) {
+                                    handle_result(&client, &network, &config.options, Ok(suggestion));
+                                }
+                            }
+                        },
+                        Command::CAP(_, CapSubCommand::ACK, ref first, ref second) => {
+                            let acknowledged = [first, second].iter()
+                                .filter_map(|capabilities| capabilities.as_deref())
+                                .flat_map(str::split_whitespace)
+                                .any(|capability| capability == "message-tags");
+                            message_tags |= acknowledged;
+                        },
+                        Command::INVITE(_, ref channel) =>
+                            handle_result(&client, &network, &config.options, Ok(invite_response(&message, channel, &owners.read().unwrap_or_else(|err| err.into_inner()), &owner_accounts, &config.options))),
+                        Command::Response(Response::RPL_ENDOFMOTD, _) | Command::Response(Response::ERR_NOMOTD, _) => {
+                            reconnect_delay = reconnect_base;
+                            if wallops_channel.is_some() {
+                                let wallops = vec![Mode::Plus(UserMode::Wallops, None)];
+                                if let Err(e) = client.send(Command::UserMODE(client.current_nickname().to_string(), wallops)) {
+                                    warn!("{}: error requesting wallops: {:?}", network, e);
+                                }
+                            }
+                            for notification in connect_notifier.on_connect(&network) {
+                                handle_result(&client, &network, &config.options, Ok(notification));
+                            }
+                        },
+                        Command::WALLOPS(ref text) =>
+                            handle_result(&client, &network, &config.options, Ok(wallops_response(&message, text, wallops_channel, &network))),
+                        _ =>
+                            (),
                     }
-                } else {
-                    break;
-                }
-            },
-            result = futures.select_next_some() => {
-                match result {
-                    Err(e) => warn!("error running command: {:?}", e),
-                    Ok(response) =>
-                        match handle_response(&client, response) {
-                            Ok(()) => (),
-                            Err(e) => warn!("error handling response: {:?}", e),
+                },
+                Event::Result((delivery, result)) => {
+                    if result.as_ref().map_or(false, is_quit) {
+                        quitting = true;
+                        drain(&mut futures, drain_time,
+                            |(delivery, result)| deliver(&client, &network, &config.options, &delivery, result)).await;
+                    }
+                    deliver(&client, &network, &config.options, &delivery, result);
+                },
+                Event::Interim((delivery, response)) => {
+                    quitting |= is_quit(&response);
+                    deliver(&client, &network, &config.options, &delivery, Ok(response));
+                },
+                Event::Shutdown => {
+                    drain(&mut futures, drain_time,
+                        |(delivery, result)| deliver(&client, &network, &config.options, &delivery, result)).await;
+                    handle_result(&client, &network, &config.options, Ok(BotResponse::Quit(Some(shutdown_message.clone()))));
+                    // The QUIT is only sent while the stream is polled; the server closes the connection after it
+                    let flushed = tokio::time::timeout(shutdown_timeout, async {
+                        while stream.next().await.is_some() {}
+                    }).await;
+                    if flushed.is_err() {
+                        warn!("{}: server did not close the connection within {:?}", network, shutdown_timeout);
+                    }
+                    return Ok(());
+                },
+                Event::Watchdog =>
+                    match ping_sent {
+                        Some(sent) if sent.elapsed() >= watchdog_timeout => {
+                            recent_messages.lock().unwrap_or_else(|err| err.into_inner()).dump();
+                            break Some(irc::error::Error::PingTimeout);
                         },
-                };
-            },
-            complete => break,
+                        Some(_) =>
+                            (),
+                        None if last_received.elapsed() >= watchdog_idle => {
+                            if let Err(e) = client.send(Command::PING(network.clone(), None)) {
+                                warn!("{}: error sending watchdog PING: {:?}", network, e);
+                            }
+                            ping_sent = Some(Instant::now());
+                        },
+                        None =>
+                            (),
+                    },
+            }
+        };
+
+        // A deliberate quit ends the process, losing the connection for any other reason reconnects
+        if quitting {
+            return Ok(());
+        }
+        match disconnect {
+            Some(err) => warn!("{}: connection lost, reconnecting in {:?}: {}", network, reconnect_delay, err),
+            None => warn!("{}: connection closed, reconnecting in {:?}", network, reconnect_delay),
         }
+        if delay_or_shutdown(reconnect_delay, shutdown.clone()).await {
+            return Ok(());
+        }
+        reconnect_delay = (reconnect_delay * 2).min(reconnect_max);
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /**
+     * The state a command invocation borrows from the dispatcher.
+     */
+    struct Fixture {
+        leaders: Vec<char>,
+        owners: RwLock<Vec<Prefix>>,
+        owner_accounts: Vec<String>,
+        ignored: RwLock<Vec<Prefix>>,
+        options: HashMap<String, String>,
+        errors: ErrorLog,
+        verbose: VerboseChannels,
+        stats: Stats,
+        responder: mpsc::UnboundedSender<BotResponse>,
+    }
+
+    impl Fixture {
+        fn new(options: &[(&str, &str)]) -> Fixture {
+            Fixture {
+                leaders: vec!['&'],
+                owners: RwLock::new(vec![Prefix::new_from_str("owner!*@*")]),
+                owner_accounts: Vec::new(),
+                ignored: RwLock::new(Vec::new()),
+                options: self::options(options),
+                errors: ErrorLog::default(),
+                verbose: VerboseChannels::default(),
+                stats: Stats::default(),
+                responder: mpsc::unbounded().0,
+            }
+        }
+
+        fn params(&self, raw: &str) -> BotParameters<'_> {
+            let message: Message = raw.parse().unwrap();
+            let context = InvocationContext::new(&message, Some('&'));
+            BotParameters {
+                message,
+                leaders: &self.leaders,
+                owners: &self.owners,
+                owner_accounts: &self.owner_accounts,
+                ignored: &self.ignored,
+                args: Vec::new(),
+                options: &self.options,
+                responder: self.responder.clone(),
+                errors: &self.errors,
+                verbose: &self.verbose,
+                stats: &self.stats,
+                network: "irc.example.org",
+                current_nick: "metarbot".to_string(),
+                channels: Vec::new(),
+                commands: &[],
+                modules: &[],
+                context,
+            }
+        }
+    }
+
+    static INVOCATION: &str = ":alice!alice@example.org PRIVMSG #weather :&count";
+
+    /**
+     * A command that counts its invocations and answers with the count, or fails with the given
+     * error.
+     */
+    struct CountingCommand {
+        count: AtomicUsize,
+        error: Option<fn() -> BotError>,
+    }
+
+    impl CountingCommand {
+        fn new(error: Option<fn() -> BotError>) -> CountingCommand {
+            CountingCommand { count: AtomicUsize::new(0), error }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BotCommand for CountingCommand {
+        fn trigger(&self) -> &'static str {
+            "count"
+        }
+
+        async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+            let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+            match self.error {
+                Some(error) => Err(error()),
+                None => Ok(BotResponse::Privmsg(params.message.response_target().unwrap().to_string(), count.to_string())),
+            }
+        }
+    }
+
+    async fn run_cached(command: &CountingCommand, fixture: &Fixture, cache: &RefCell<ResponseCache>) -> BotCommandResult {
+        let cache_key = Some(("#weather".to_string(), Duration::from_secs(60)));
+        run_command(command, fixture.params(INVOCATION), cache_key, cache, None, None).await
+    }
+
+    #[test]
+    fn response_cache_hit_and_expiry() {
+        let mut cache = ResponseCache::default();
+        cache.insert("metar", "#weather EDDF".to_string(), Duration::from_secs(60), BotResponse::Privmsg("#weather".to_string(), "EDDF".to_string()));
+        cache.insert("taf", "#weather EDDF".to_string(), Duration::from_millis(10), BotResponse::Privmsg("#weather".to_string(), "EDDF".to_string()));
+
+        assert!(matches!(cache.get("metar", "#weather EDDF"), Some(BotResponse::Privmsg(_, ref text)) if text == "EDDF"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get("taf", "#weather EDDF").is_none());
+        assert!(cache.get("metar", "#weather EDDF").is_some());
+    }
+
+    #[test]
+    fn response_cache_keeps_targets_apart() {
+        let mut cache = ResponseCache::default();
+        cache.insert("metar", "#weather EDDF".to_string(), Duration::from_secs(60), BotResponse::Privmsg("#weather".to_string(), "EDDF".to_string()));
+
+        assert!(cache.get("metar", "#aviation EDDF").is_none());
+        assert!(cache.get("metar", "alice EDDF").is_none());
+        assert!(cache.get("taf", "#weather EDDF").is_none());
+    }
+
+    #[tokio::test]
+    async fn cache_hit_does_not_call_handler() {
+        let fixture = Fixture::new(&[]);
+        let cache = RefCell::new(ResponseCache::default());
+        let command = CountingCommand::new(None);
+
+        assert!(matches!(run_cached(&command, &fixture, &cache).await, Ok(BotResponse::Privmsg(_, ref text)) if text == "1"));
+        assert!(matches!(run_cached(&command, &fixture, &cache).await, Ok(BotResponse::Privmsg(_, ref text)) if text == "1"));
+        assert_eq!(command.count.load(Ordering::SeqCst), 1);
+    }
+
+    /**
+     * A command that records how many of its invocations run at the same time.
+     */
+    #[derive(Default)]
+    struct ExclusiveCommand {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl BotCommand for ExclusiveCommand {
+        fn trigger(&self) -> &'static str {
+            "exclusive"
+        }
+
+        fn max_concurrency(&self) -> Option<usize> {
+            Some(1)
+        }
+
+        async fn handle(&self, _: BotParameters<'_>) -> BotCommandResult {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(BotResponse::Privmsg("#weather".to_string(), "done".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrency_queues_invocations() {
+        let fixture = Fixture::new(&[]);
+        let cache = RefCell::new(ResponseCache::default());
+        let command = ExclusiveCommand::default();
+        let limit = Semaphore::new(command.max_concurrency().unwrap());
+
+        let (first, second) = futures::join!(
+            run_command(&command, fixture.params(INVOCATION), None, &cache, None, Some(&limit)),
+            run_command(&command, fixture.params(INVOCATION), None, &cache, None, Some(&limit)));
+
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(command.max_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrency_is_unlimited_by_default() {
+        let fixture = Fixture::new(&[]);
+        let cache = RefCell::new(ResponseCache::default());
+        let command = ExclusiveCommand::default();
+
+        let (first, second) = futures::join!(
+            run_command(&command, fixture.params(INVOCATION), None, &cache, None, None),
+            run_command(&command, fixture.params(INVOCATION), None, &cache, None, None));
+
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(command.max_running.load(Ordering::SeqCst), 2);
+    }
+
+    /**
+     * A command that responds to the invoking channel and to another channel.
+     */
+    struct LeakyCommand {
+        cross_target: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl BotCommand for LeakyCommand {
+        fn trigger(&self) -> &'static str {
+            "leaky"
+        }
+
+        fn cross_target(&self) -> bool {
+            self.cross_target
+        }
+
+        async fn handle(&self, _: BotParameters<'_>) -> BotCommandResult {
+            Ok(BotResponse::Multi(vec![
+                BotResponse::Privmsg("#weather".to_string(), "here".to_string()),
+                BotResponse::Privmsg("#secret".to_string(), "elsewhere".to_string()),
+                BotResponse::Notice("alice".to_string(), "to the user".to_string()),
+            ]))
+        }
+    }
+
+    async fn run_guarded(command: &LeakyCommand, fixture: &Fixture) -> Vec<BotResponse> {
+        let cache = RefCell::new(ResponseCache::default());
+        let params = fixture.params(INVOCATION);
+        let delivery = Delivery {
+            trigger: command.trigger(),
+            allowed_targets: response_guard(command, &params.message, true),
+            msgid: None,
+        };
+        match run_command(command, params, None, &cache, None, None).await.map(|response| delivery.prepare(response)) {
+            Ok(BotResponse::Multi(responses)) => responses,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_context_target_is_suppressed() {
+        let fixture = Fixture::new(&[]);
+        let responses = run_guarded(&LeakyCommand { cross_target: false }, &fixture).await;
+
+        assert!(matches!(responses[0], BotResponse::Privmsg(ref target, _) if target == "#weather"));
+        assert!(matches!(responses[1], BotResponse::Ignore));
+        assert!(matches!(responses[2], BotResponse::Notice(ref target, _) if target == "alice"));
+    }
+
+    #[tokio::test]
+    async fn cross_target_command_may_send_anywhere() {
+        let fixture = Fixture::new(&[]);
+        let responses = run_guarded(&LeakyCommand { cross_target: true }, &fixture).await;
+
+        assert!(matches!(responses[1], BotResponse::Privmsg(ref target, _) if target == "#secret"));
+        assert!(response_guard(&LeakyCommand { cross_target: false }, &INVOCATION.parse().unwrap(), false).is_none());
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_recorded() {
+        let fixture = Fixture::new(&[]);
+        let cache = RefCell::new(ResponseCache::default());
+        let command = CountingCommand::new(Some(|| BotError::Unconfigured("avwx_apikey")));
+
+        assert!(run_command(&command, fixture.params(INVOCATION), None, &cache, None, None).await.is_err());
+        let recorded = fixture.errors.get("count");
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].message, "A required configuration option is missing: avwx_apikey");
+    }
+
+    fn connect_notifier(options: &[(&str, &str)]) -> ConnectNotifier {
+        ConnectNotifier::new(&Config { options: self::options(options), ..Config::default() })
+    }
+
+    #[test]
+    fn configured_users_are_notified_on_connect() {
+        let mut notifier = connect_notifier(&[("notify_on_connect", "alice, bob"), ("notify_debounce_secs", "0")]);
+
+        let notifications = notifier.on_connect("irc.example.org");
+        assert_eq!(notifications.len(), 2);
+        assert!(matches!(notifications[0], BotResponse::Notice(ref target, ref text)
+            if target == "alice" && text == "Connected to irc.example.org"));
+        assert!(matches!(notifications[1], BotResponse::Notice(ref target, _) if target == "bob"));
+
+        let notifications = notifier.on_connect("irc.example.org");
+        assert!(matches!(notifications[0], BotResponse::Notice(_, ref text)
+            if text == "Connected to irc.example.org, 0s after the previous connection"));
+    }
+
+    #[test]
+    fn connect_notifications_are_debounced() {
+        let mut notifier = connect_notifier(&[("notify_on_connect", "alice")]);
+        assert_eq!(notifier.on_connect("irc.example.org").len(), 1);
+        assert!(notifier.on_connect("irc.example.org").is_empty());
+
+        assert!(connect_notifier(&[]).on_connect("irc.example.org").is_empty());
+    }
+
+    #[test]
+    fn wallops_are_relayed_to_the_admin_channel() {
+        let message: Message = ":oper!o@staff.example.org WALLOPS :Rebooting hub in 5 minutes".parse().unwrap();
+        let text = match message.command {
+            Command::WALLOPS(ref text) => text.clone(),
+            ref command => panic!("unexpected command {:?}", command),
+        };
+
+        assert!(matches!(wallops_response(&message, &text, Some("#metarbot-admin"), "example"),
+            BotResponse::Privmsg(ref channel, ref relayed)
+                if channel == "#metarbot-admin" && relayed == "WALLOPS from oper: Rebooting hub in 5 minutes"));
+        assert!(matches!(wallops_response(&message, &text, None, "example"), BotResponse::Ignore));
+
+        let message: Message = ":hub.example.org WALLOPS :Netsplit".parse().unwrap();
+        assert!(matches!(wallops_response(&message, "Netsplit", Some("#metarbot-admin"), "example"),
+            BotResponse::Privmsg(_, ref relayed) if relayed == "WALLOPS from server: Netsplit"));
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_not_cached() {
+        let fixture = Fixture::new(&[]);
+        fixture.verbose.set("#weather", true);
+        let cache = RefCell::new(ResponseCache::default());
+
+        let command = CountingCommand::new(Some(|| BotError::UserMessage("Airport not found".to_string())));
+        assert!(matches!(run_cached(&command, &fixture, &cache).await, Ok(BotResponse::Notice(..))));
+        assert!(matches!(run_cached(&command, &fixture, &cache).await, Ok(BotResponse::Notice(..))));
+        assert_eq!(command.count.load(Ordering::SeqCst), 2);
+
+        let command = CountingCommand::new(Some(|| BotError::Unconfigured("avwx_apikey")));
+        assert!(matches!(run_cached(&command, &fixture, &cache).await, Ok(BotResponse::Notice(..))));
+        assert!(matches!(run_cached(&command, &fixture, &cache).await, Ok(BotResponse::Notice(..))));
+        assert_eq!(command.count.load(Ordering::SeqCst), 2);
+        assert!(cache.borrow_mut().get("count", "#weather").is_none());
+    }
+
+    #[tokio::test]
+    async fn errors_are_shown_in_verbose_channels_only() {
+        let fixture = Fixture::new(&[("avwx_apikey", "s3cr3t")]);
+        let cache = RefCell::new(ResponseCache::default());
+        let command = CountingCommand::new(Some(|| BotError::UserMessage("s3cr3t".to_string())));
+        let command_error = CountingCommand::new(Some(|| BotError::Unconfigured("s3cr3t")));
+
+        assert!(run_command(&command_error, fixture.params(INVOCATION), None, &cache, None, None).await.is_err());
+
+        fixture.verbose.set("#weather", true);
+        assert!(matches!(run_command(&command_error, fixture.params(INVOCATION), None, &cache, None, None).await,
+            Ok(BotResponse::Notice(ref channel, ref text))
+                if channel == "#weather" && text == "Error running count: A required configuration option is missing: <redacted>"));
+
+        // Messages for the user are shown regardless of the verbose setting
+        fixture.verbose.set("#weather", false);
+        assert!(matches!(run_command(&command, fixture.params(INVOCATION), None, &cache, None, None).await,
+            Ok(BotResponse::Notice(ref channel, _)) if channel == "#weather"));
+    }
+
+    #[test]
+    fn recent_messages_evict_the_oldest_lines() {
+        let mut recent_messages = RecentMessages::new("example", 3);
+        for i in 0..5 {
+            recent_messages.push(format!(":alice!a@example.org PRIVMSG #weather :line {}", i));
+        }
+
+        assert_eq!(recent_messages.lines.len(), 3);
+        assert_eq!(recent_messages.lines.front().unwrap(), ":alice!a@example.org PRIVMSG #weather :line 2");
+        assert_eq!(recent_messages.lines.back().unwrap(), ":alice!a@example.org PRIVMSG #weather :line 4");
+    }
+
+    #[test]
+    fn recent_messages_can_be_disabled() {
+        let mut recent_messages = RecentMessages::new("example", 0);
+        recent_messages.push(":alice!a@example.org PRIVMSG #weather :hello".to_string());
+        assert!(recent_messages.lines.is_empty());
+    }
+
+    #[test]
+    fn rate_limiter_enforces_cooldown() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(3));
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("alice", "metar", start), Throttle::Allow);
+        assert_eq!(limiter.check("Alice", "metar", start + Duration::from_secs(1)), Throttle::Notify);
+        assert_eq!(limiter.check("alice", "metar", start + Duration::from_secs(2)), Throttle::Drop);
+        assert_eq!(limiter.check("alice", "metar", start + Duration::from_secs(3)), Throttle::Allow);
+    }
+
+    #[test]
+    fn rate_limiter_keeps_users_and_commands_apart() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(3));
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("alice", "metar", start), Throttle::Allow);
+        assert_eq!(limiter.check("alice", "taf", start), Throttle::Allow);
+        assert_eq!(limiter.check("bob", "metar", start), Throttle::Allow);
+        assert_eq!(limiter.check("alice", "metar", start), Throttle::Notify);
+    }
+
+    #[test]
+    fn rate_limiter_can_be_disabled() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(0));
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("alice", "metar", start), Throttle::Allow);
+        assert_eq!(limiter.check("alice", "metar", start), Throttle::Allow);
+    }
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn longest_phrase_wins() {
+        let phrases: HashMap<String, &'static str> = vec![
+            ("airport".to_string(), "airport"),
+            ("airport info".to_string(), "airport info"),
+            ("metar".to_string(), "metar"),
+        ].into_iter().collect();
+
+        let words = tokens("Airport  INFO EDDF");
+        assert_eq!(lookup_command(&phrases, &words, false), Some(("airport info", &words[2..])));
+        let words = tokens("airport EDDF info");
+        assert_eq!(lookup_command(&phrases, &words, false), Some(("airport", &words[1..])));
+        let words = tokens("airport");
+        assert_eq!(lookup_command(&phrases, &words, false), Some(("airport", &words[1..])));
+        let words = tokens("Airport info");
+        assert_eq!(lookup_command(&phrases, &words, true), None);
+        assert_eq!(lookup_command(&phrases, &[], false), None);
+        let words = tokens("info EDDF");
+        assert_eq!(lookup_command(&phrases, &words, false), None);
+    }
+
+    #[test]
+    fn metar_check_key_is_an_owner_only_command() {
+        let registry = build_registry(modules::ALL);
+        let (phrases, collisions) = command_phrases(&registry.commands, false);
+        assert!(collisions.is_empty());
+        let tokens: Vec<String> = vec!["Metar".to_string(), "check-key".to_string()];
+
+        let (trigger, args) = lookup_command(&phrases, &tokens, false).unwrap();
+        assert_eq!(trigger, "check-key");
+        assert!(args.is_empty());
+        assert!(registry.commands[trigger].owner_only());
+    }
+
+    async fn slow_command(delay: Duration, text: &str) -> BotCommandResult {
+        tokio::time::delay_for(delay).await;
+        Ok(BotResponse::Privmsg("#weather".to_string(), text.to_string()))
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_slow_command() {
+        let mut futures = FuturesUnordered::new();
+        futures.push(slow_command(Duration::from_millis(50), "done").boxed_local());
+
+        let mut results = Vec::new();
+        drain(&mut futures, Duration::from_secs(5), |result| results.push(result)).await;
+
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Ok(BotResponse::Privmsg(_, ref text)) if text == "done"));
+        assert!(futures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_abandons_commands_after_drain_time() {
+        let mut futures = FuturesUnordered::new();
+        futures.push(slow_command(Duration::from_millis(10), "fast").boxed_local());
+        futures.push(slow_command(Duration::from_secs(60), "slow").boxed_local());
+
+        let mut results = Vec::new();
+        drain(&mut futures, Duration::from_millis(200), |result| results.push(result)).await;
+
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Ok(BotResponse::Privmsg(_, ref text)) if text == "fast"));
+        assert_eq!(futures.len(), 1);
+    }
+
+    /**
+     * A command that answers with an interim response right away and with its result after a delay.
+     */
+    struct SlowCommand {}
+
+    #[async_trait::async_trait]
+    impl BotCommand for SlowCommand {
+        fn trigger(&self) -> &'static str {
+            "slow"
+        }
+
+        async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+            let _ = params.responder.unbounded_send(BotResponse::Privmsg("#weather".to_string(), "working on it".to_string()));
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            Ok(BotResponse::Privmsg("#weather".to_string(), "done".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn interim_response_arrives_before_result() {
+        let mut fixture = Fixture::new(&[]);
+        let (responder, mut interim_responses) = mpsc::unbounded();
+        fixture.responder = responder;
+        let cache = RefCell::new(ResponseCache::default());
+
+        let mut futures = FuturesUnordered::new();
+        futures.push(run_command(&SlowCommand{}, fixture.params(INVOCATION), None, &cache, None, None).boxed_local());
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            let text = select! {
+                result = futures.select_next_some() => format!("result {:?}", result.unwrap()),
+                response = interim_responses.select_next_some() => format!("interim {:?}", response),
+            };
+            events.push(text);
+        }
+        assert_eq!(events, vec![
+            r##"interim Privmsg("#weather", "working on it")"##,
+            r##"result Privmsg("#weather", "done")"##,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_delivered_like_results() {
+        let mut interim_responses = InterimResponses::new();
+        let mut responders = vec![
+            responder_for(Delivery { msgid: Some("abc".to_string()), ..Delivery::default() }, &mut interim_responses),
+            responder_for(Delivery::default(), &mut interim_responses),
+        ];
+        responders[0].unbounded_send(BotResponse::Privmsg("#weather".to_string(), "working on it".to_string())).unwrap();
+        responders[1].unbounded_send(BotResponse::Privmsg("#weather".to_string(), "still working".to_string())).unwrap();
+        responders.clear();
+
+        let mut lines = Vec::new();
+        while let Some((delivery, response)) = interim_responses.next().await {
+            lines.extend(sent_lines(delivery.prepare(response), &[]).await);
+        }
+        lines.sort();
+        assert_eq!(lines, vec![
+            "@+draft/reply=abc PRIVMSG #weather :working on it",
+            "PRIVMSG #weather :still working",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn interim_response_to_other_channel_is_dropped() {
+        let message: Message = INVOCATION.parse().unwrap();
+        let command = LeakyCommand { cross_target: false };
+        let delivery = Delivery {
+            trigger: command.trigger(),
+            allowed_targets: response_guard(&command, &message, true),
+            msgid: None,
+        };
+        let mut interim_responses = InterimResponses::new();
+        let responder = responder_for(delivery, &mut interim_responses);
+        responder.unbounded_send(BotResponse::Privmsg("#secret".to_string(), "elsewhere".to_string())).unwrap();
+        responder.unbounded_send(BotResponse::Privmsg("#weather".to_string(), "here".to_string())).unwrap();
+        drop(responder);
+
+        let mut lines = Vec::new();
+        while let Some((delivery, response)) = interim_responses.next().await {
+            let response = delivery.prepare(response);
+            if !matches!(response, BotResponse::Ignore) {
+                lines.extend(sent_lines(response, &[]).await);
+            }
+        }
+        assert_eq!(lines, vec!["PRIVMSG #weather here"]);
+    }
+
+    /**
+     * Accept a single connection on the given listener and play the given server lines, each once
+     * the client has sent a line starting with the expected text. Returns all lines the client sent
+     * until it sent the last expected line.
+     */
+    async fn mock_server(mut listener: tokio::net::TcpListener, script: Vec<(&'static str, &'static str)>) -> Vec<String> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = tokio::io::split(socket);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let mut received = Vec::new();
+        for (expected, reply) in script {
+            loop {
+                let line = lines.next_line().await.unwrap().expect("client closed the connection");
+                received.push(line.clone());
+                if line.starts_with(expected) {
+                    break
+                }
+            }
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn nickname_is_recovered_after_nickname_in_use() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_server(listener, vec![
+            ("USER", ":irc.example.org 433 * metarbot :Nickname is already in use\r\n"),
+            ("NICK tafbot", ":irc.example.org 376 tafbot :End of /MOTD command\r\n"),
+            ("NICK metarbot", ""),
+        ]));
+
+        let config = Config {
+            nickname: Some("metarbot".to_string()),
+            alt_nicks: vec!["tafbot".to_string()],
+            nick_password: Some("secret".to_string()),
+            should_ghost: true,
+            server: Some("127.0.0.1".to_string()),
+            port: Some(port),
+            use_tls: Some(false),
+            ..Config::default()
+        };
+        let mut client = Client::from_config(config).await.unwrap();
+        client.identify().unwrap();
+        let mut stream = client.stream().unwrap().fuse();
+        let mut server = server.fuse();
+
+        // The stream has to be polled for the client to send anything
+        let received = loop {
+            select! {
+                received = server => break received.unwrap(),
+                message = stream.next() => assert!(!matches!(message, Some(Err(_))), "connection failed: {:?}", message),
+            }
+        };
+        let ghost = received.iter().position(|line| line == "NICKSERV GHOST metarbot secret");
+        let recovered = received.iter().rposition(|line| line == "NICK metarbot");
+        assert!(received.contains(&"NICK tafbot".to_string()), "{:?}", received);
+        assert!(ghost.is_some() && recovered.is_some() && ghost < recovered, "{:?}", received);
+    }
+
+    /**
+     * Send the given response using a client with the given options that is connected to a mock
+     * server, and return the lines the server received.
+     */
+    async fn sent_lines(response: BotResponse, options: &[(&str, &str)]) -> Vec<String> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_server(listener, vec![("QUIT", "")]));
+
+        let config = Config {
+            nickname: Some("metarbot".to_string()),
+            server: Some("127.0.0.1".to_string()),
+            port: Some(port),
+            use_tls: Some(false),
+            options: self::options(options),
+            ..Config::default()
+        };
+        let mut client = Client::from_config(config.clone()).await.unwrap();
+        handle_response(&client, &config.options, response).unwrap();
+        client.send_quit("done").unwrap();
+        let mut stream = client.stream().unwrap().fuse();
+        let mut server = server.fuse();
+
+        let mut received = loop {
+            select! {
+                received = server => break received.unwrap(),
+                message = stream.next() => assert!(!matches!(message, Some(Err(_))), "connection failed: {:?}", message),
+            }
+        };
+        assert_eq!(received.pop().as_deref(), Some("QUIT done"));
+        received
+    }
+
+    #[tokio::test]
+    async fn action_is_sent_with_ctcp_framing() {
+        let response = BotResponse::Action("#weather".to_string(), "fetches the weather for EDDF".to_string());
+        assert!(matches!(response, BotResponse::Action(ref target, ref text)
+            if target == "#weather" && text == "fetches the weather for EDDF"));
+
+        assert_eq!(sent_lines(response.clone(), &[]).await, vec!["PRIVMSG #weather :\u{1}ACTION fetches the weather for EDDF\u{1}"]);
+        assert_eq!(sent_lines(reply_to("abc", response), &[]).await,
+            vec!["@+draft/reply=abc PRIVMSG #weather :\u{1}ACTION fetches the weather for EDDF\u{1}"]);
+    }
+
+    #[tokio::test]
+    async fn raw_mode_command_is_sent() {
+        let response = BotResponse::Raw(Command::ChannelMODE(
+            "#weather".to_string(),
+            vec![Mode::Plus(ChannelMode::Voice, Some("alice".to_string()))]));
+        assert_eq!(sent_lines(response, &[]).await, vec!["MODE #weather +v alice"]);
+    }
+
+    struct PanickingCommand {}
+
+    #[async_trait::async_trait]
+    impl BotCommand for PanickingCommand {
+        fn trigger(&self) -> &'static str {
+            "panic"
+        }
+
+        async fn handle(&self, _params: BotParameters<'_>) -> BotCommandResult {
+            panic!("deliberate panic")
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_command_does_not_stop_the_bot() {
+        let fixture = Fixture::new(&[]);
+        let cache = RefCell::new(ResponseCache::default());
+
+        let result = run_command(&PanickingCommand{}, fixture.params(INVOCATION), None, &cache, None, None).await;
+        assert!(matches!(result, Err(BotError::Panicked("panic"))));
+        assert_eq!(fixture.errors.get("panic").len(), 1);
+        assert_eq!(fixture.errors.get("panic")[0].message, "The handler for the panic command panicked");
+
+        let command = CountingCommand::new(None);
+        let result = run_command(&command, fixture.params(INVOCATION), None, &cache, None, None).await;
+        assert!(matches!(result, Ok(BotResponse::Privmsg(_, ref text)) if text == "1"));
+    }
+
+    #[test]
+    fn panic_message_is_extracted() {
+        assert_eq!(panic_message(&"static message"), "static message");
+        assert_eq!(panic_message(&"formatted message".to_string()), "formatted message");
+        assert_eq!(panic_message(&42), "<no message>");
+    }
+
+    #[test]
+    fn configured_log_filter_applies_without_rust_log() {
+        let filter = log_filter(None, Some("metarbot=debug,irc=warn")).unwrap();
+        let logger = pretty_env_logger::formatted_builder().parse_filters(&filter).build();
+        let enabled = |target, level| log::Log::enabled(&logger, &log::Metadata::builder().target(target).level(level).build());
+
+        assert!(enabled("metarbot", log::Level::Debug));
+        assert!(!enabled("irc::client", log::Level::Info));
+        assert!(enabled("irc::client", log::Level::Warn));
+    }
+
+    #[test]
+    fn rust_log_takes_precedence_over_configured_filter() {
+        assert_eq!(log_filter(Some("trace".to_string()), Some("metarbot=debug")), Some("trace".to_string()));
+        assert_eq!(log_filter(None, None), None);
+    }
+
+    fn options(options: &[(&str, &str)]) -> HashMap<String, String> {
+        options.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn owner_invite_joins() {
+        let owners = vec![Prefix::new_from_str("owner!*@*")];
+        let message: Message = ":owner!o@example.org INVITE metarbot #weather".parse().unwrap();
+        assert!(matches!(invite_response(&message, "#weather", &owners, &[], &options(&[])),
+            BotResponse::Join(ref channel) if channel == "#weather"));
+
+        let message: Message = "@account=alice :alice!a@example.org INVITE metarbot #weather".parse().unwrap();
+        assert!(matches!(invite_response(&message, "#weather", &owners, &["alice".to_string()], &options(&[])),
+            BotResponse::Join(_)));
+    }
+
+    #[test]
+    fn non_owner_invite_is_ignored() {
+        let owners = vec![Prefix::new_from_str("owner!*@*")];
+        let message: Message = ":mallory!m@example.org INVITE metarbot #weather".parse().unwrap();
+        assert!(matches!(invite_response(&message, "#weather", &owners, &[], &options(&[])), BotResponse::Ignore));
+        assert!(matches!(invite_response(&message, "#weather", &owners, &[], &options(&[("invite_notice", "true")])),
+            BotResponse::Notice(ref nickname, _) if nickname == "mallory"));
+    }
+
+    fn alias_phrases() -> HashMap<String, &'static str> {
+        vec![("metar", "metar"), ("taf", "taf"), ("metar check-key", "check-key")].into_iter()
+            .map(|(phrase, trigger)| (phrase.to_string(), trigger))
+            .collect()
+    }
+
+    #[test]
+    fn aliases_resolve_to_commands() {
+        let aliases = resolve_aliases(
+            &options(&[("aliases.w", "metar"), ("aliases.ww", "w"), ("aliases.K", "metar  check-key"), ("prefix", "metar")]),
+            &alias_phrases(),
+            false);
+
+        assert_eq!(aliases.len(), 3, "{:?}", aliases);
+        assert_eq!(aliases.get("w"), Some(&"metar"));
+        assert_eq!(aliases.get("ww"), Some(&"metar"));
+        assert_eq!(aliases.get("k"), Some(&"check-key"));
+    }
+
+    #[test]
+    fn aliases_keep_case_with_case_sensitive_commands() {
+        let aliases = resolve_aliases(&options(&[("aliases.W", "metar"), ("aliases.t", "TAF")]), &alias_phrases(), true);
+        assert_eq!(aliases.get("W"), Some(&"metar"));
+        assert_eq!(aliases.get("w"), None);
+        assert_eq!(aliases.get("t"), None);
+    }
+
+    #[test]
+    fn shadowing_cyclic_and_dangling_aliases_are_ignored() {
+        let aliases = resolve_aliases(
+            &options(&[
+                ("aliases.taf", "metar"),
+                ("aliases.self", "self"),
+                ("aliases.ping", "pong"),
+                ("aliases.pong", "ping"),
+                ("aliases.loop", "ping"),
+                ("aliases.nothing", "wx"),
+            ]),
+            &alias_phrases(),
+            false);
+        assert!(aliases.is_empty(), "{:?}", aliases);
+    }
+
+    #[tokio::test]
+    async fn ctcp_requests_are_answered_to_the_requesting_nick() {
+        let reply = ctcp_reply(&":alice!a@example.org PRIVMSG #weather :\u{1}VERSION\u{1}".parse().unwrap(), "metarbot 1.0").unwrap();
+        assert_eq!(sent_lines(reply, &[]).await, vec!["NOTICE alice :\u{1}VERSION metarbot 1.0\u{1}"]);
+
+        let reply = ctcp_reply(&":alice!a@example.org PRIVMSG metarbot :\u{1}PING 1602662400 42\u{1}".parse().unwrap(), "metarbot 1.0").unwrap();
+        assert_eq!(sent_lines(reply, &[]).await, vec!["NOTICE alice :\u{1}PING 1602662400 42\u{1}"]);
+
+        match ctcp_reply(&":alice!a@example.org PRIVMSG #weather :\u{1}TIME\u{1}".parse().unwrap(), "metarbot 1.0") {
+            Some(BotResponse::Raw(Command::NOTICE(ref nickname, ref text))) =>
+                assert!(nickname == "alice" && text.starts_with("\u{1}TIME ") && text.ends_with('\u{1}'), "{}", text),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn actions_and_unknown_ctcp_requests_are_not_answered() {
+        assert!(ctcp_reply(&":alice!a@example.org PRIVMSG #weather :\u{1}ACTION waves\u{1}".parse().unwrap(), "metarbot").is_none());
+        assert!(ctcp_reply(&":alice!a@example.org PRIVMSG #weather :\u{1}FINGER\u{1}".parse().unwrap(), "metarbot").is_none());
+        assert!(ctcp_reply(&":alice!a@example.org PRIVMSG #weather :VERSION".parse().unwrap(), "metarbot").is_none());
+        assert!(ctcp_reply(&":alice!a@example.org NOTICE metarbot :\u{1}VERSION irssi\u{1}".parse().unwrap(), "metarbot").is_none());
+    }
+
+    #[tokio::test]
+    async fn long_messages_are_split_keeping_their_spacing() {
+        let response = BotResponse::Privmsg("#weather".to_string(), "TAF EDDF  140500Z 1406/1512".to_string());
+        assert_eq!(sent_lines(response.clone(), &[]).await, vec!["PRIVMSG #weather :TAF EDDF  140500Z 1406/1512"]);
+        assert_eq!(sent_lines(response.clone(), &[("max_line_length", "10")]).await,
+            vec!["PRIVMSG #weather :TAF EDDF ", "PRIVMSG #weather 140500Z", "PRIVMSG #weather 1406/1512"]);
+        assert_eq!(sent_lines(response, &[("max_line_length", "10"), ("split_long_messages", "false")]).await,
+            vec!["PRIVMSG #weather :TAF EDDF  140500Z 1406/1512"]);
+
+        let response = BotResponse::Notice("alice".to_string(), "wind  27010KT".to_string());
+        assert_eq!(sent_lines(response, &[("max_line_length", "8")]).await, vec!["NOTICE alice :wind ", "NOTICE alice 27010KT"]);
+    }
+
+    /**
+     * A command that only has a trigger.
+     */
+    struct TriggerCommand(&'static str);
+
+    #[async_trait::async_trait]
+    impl BotCommand for TriggerCommand {
+        fn trigger(&self) -> &'static str {
+            self.0
+        }
+
+        async fn handle(&self, _: BotParameters<'_>) -> BotCommandResult {
+            Ok(BotResponse::Ignore)
+        }
+    }
+
+    fn trigger_commands(triggers: &[&'static str]) -> HashMap<&'static str, Box<dyn BotCommand>> {
+        triggers.iter()
+            .map(|trigger| (*trigger, Box::new(TriggerCommand(trigger)) as Box<dyn BotCommand>))
+            .collect()
+    }
+
+    #[test]
+    fn phrases_colliding_after_normalization_are_reported() {
+        let (phrases, collisions) = command_phrases(&trigger_commands(&["metar", "Metar", "taf"]), false);
+        assert_eq!(phrases.len(), 2);
+        assert_eq!(phrases.get("metar"), Some(&"Metar"));
+        assert_eq!(collisions.len(), 1);
+        assert_eq!((collisions[0].phrase.as_str(), collisions[0].kept, collisions[0].dropped), ("metar", "Metar", "metar"));
+
+        let (phrases, collisions) = command_phrases(&trigger_commands(&["metar", "Metar", "taf"]), true);
+        assert_eq!(phrases.len(), 3);
+        assert!(collisions.is_empty());
+    }
+
+    fn weather_module() -> Vec<Box<dyn BotCommand>> {
+        vec![Box::new(TriggerCommand("metar")), Box::new(TriggerCommand("taf"))]
+    }
+
+    fn other_weather_module() -> Vec<Box<dyn BotCommand>> {
+        vec![Box::new(TriggerCommand("winds")), Box::new(TriggerCommand("metar"))]
+    }
+
+    #[test]
+    fn registry_keeps_the_first_command_of_colliding_triggers() {
+        let registry = build_registry(&[("weather", weather_module), ("other", other_weather_module)]);
+
+        let mut triggers: Vec<&str> = registry.commands.keys().cloned().collect();
+        triggers.sort();
+        assert_eq!(triggers, vec!["metar", "taf", "winds"]);
+        assert_eq!(registry.modules, vec![("weather", vec!["metar", "taf"]), ("other", vec!["winds"])]);
+        assert_eq!(registry.collisions.len(), 1);
+        let collision = &registry.collisions[0];
+        assert_eq!((collision.trigger, collision.kept, collision.dropped), ("metar", "weather", "other"));
+    }
+
+    #[test]
+    fn registry_of_all_modules_has_no_collisions() {
+        let registry = build_registry(modules::ALL);
+        assert!(registry.collisions.is_empty());
+        assert_eq!(registry.modules.len(), modules::ALL.len());
+    }
+
+    #[test]
+    fn close_commands_are_suggested() {
+        let phrases = command_phrases(&trigger_commands(&["metar", "taf", "winds"]), false).0;
+        assert_eq!(suggest_command(&phrases, "mtear"), Some(("metar", "metar")));
+        assert_eq!(suggest_command(&phrases, "METR"), Some(("metar", "metar")));
+        assert_eq!(suggest_command(&phrases, "tafs"), Some(("taf", "taf")));
+        assert_eq!(suggest_command(&phrases, "weather"), None);
+    }
+
+    #[test]
+    fn suggestions_are_throttled_per_user() {
+        let phrases = command_phrases(&trigger_commands(&["metar", "taf"]), false).0;
+        let options = options(&[("channel_commands.#quiet", "taf")]);
+        let mut limiter = RateLimiter::new(Duration::from_secs(3));
+        let now = Instant::now();
+        let mut suggest = |word: &str, nickname: &str, channel: &str, now: Instant| {
+            let message: Message = format!(":{}!u@example.org PRIVMSG {} :&{}", nickname, channel, word).parse().unwrap();
+            suggestion(&phrases, word, '&', &message, &options, &mut limiter, now)
+        };
+
+        assert!(matches!(suggest("mtar", "alice", "#weather", now),
+            Some(BotResponse::Notice(ref nickname, ref text)) if nickname == "alice" && text == "Did you mean &metar?"));
+        assert!(suggest("tf", "alice", "#weather", now + Duration::from_secs(1)).is_none());
+        assert!(suggest("mtar", "Alice", "#weather", now + Duration::from_secs(2)).is_none());
+        assert!(suggest("mtar", "bob", "#weather", now + Duration::from_secs(2)).is_some());
+        assert!(suggest("tf", "alice", "#weather", now + Duration::from_secs(4)).is_some());
+
+        // Suggestions of commands disabled in the channel are not made and do not count
+        assert!(suggest("mtar", "carol", "#quiet", now).is_none());
+        assert!(suggest("tf", "carol", "#quiet", now).is_some());
+    }
+
+    /**
+     * A command that records when each invocation starts and ends, by the nickname of its user.
+     */
+    #[derive(Default)]
+    struct RecordingCommand {
+        events: Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl BotCommand for RecordingCommand {
+        fn trigger(&self) -> &'static str {
+            "record"
+        }
+
+        async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+            let nickname = params.message.source_nickname().unwrap_or("").to_string();
+            self.events.lock().unwrap().push(format!("start {}", nickname));
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            self.events.lock().unwrap().push(format!("end {}", nickname));
+            Ok(BotResponse::Ignore)
+        }
+    }
+
+    #[tokio::test]
+    async fn user_queues_serialize_commands_of_each_user() {
+        let fixture = Fixture::new(&[]);
+        let cache = RefCell::new(ResponseCache::default());
+        let command = RecordingCommand::default();
+        let mut queues = UserQueues::default();
+        let (alice, alice_again, bob) = (queues.queue("alice"), queues.queue("Alice"), queues.queue("bob"));
+        assert!(Rc::ptr_eq(&alice, &alice_again));
+
+        let bob_invocation = ":bob!bob@example.org PRIVMSG #weather :&record";
+        let _ = futures::join!(
+            run_command(&command, fixture.params(INVOCATION), None, &cache, Some(alice), None),
+            run_command(&command, fixture.params(INVOCATION), None, &cache, Some(alice_again), None),
+            run_command(&command, fixture.params(bob_invocation), None, &cache, Some(bob), None));
+
+        // Bob's command runs alongside Alice's first one, and her second waits for her first
+        let events = command.events.lock().unwrap().clone();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[..2], ["start alice", "start bob"]);
+        let first_end = events.iter().position(|event| event == "end alice").unwrap();
+        let second_start = events.iter().rposition(|event| event == "start alice").unwrap();
+        assert!(first_end < second_start, "{:?}", events);
+    }
+
+    #[test]
+    fn idle_user_queues_are_dropped() {
+        let mut queues = UserQueues::default();
+        let alice = queues.queue("alice");
+        drop(queues.queue("bob"));
+        let _ = queues.queue("carol");
+        assert!(queues.queues.contains_key("alice"));
+        assert!(!queues.queues.contains_key("bob"));
+        drop(alice);
+        let _ = queues.queue("dave");
+        assert_eq!(queues.queues.keys().collect::<Vec<&String>>(), vec!["dave"]);
+    }
+
+    #[test]
+    fn channel_leaders_override_the_global_leaders() {
+        let channel_leaders = parse_channel_leaders(&options(&[
+            ("leaders", "&"),
+            ("leaders.#Weather", "!"),
+            ("leaders.#aviation", "!?"),
+            ("leaders.#quiet", ""),
+        ]));
+        assert_eq!(channel_leaders.len(), 3);
+        let leaders = vec!['&'];
+
+        assert_eq!(leaders_for("#weather", &leaders, &channel_leaders), &vec!['!']);
+        assert_eq!(leaders_for("#WEATHER", &leaders, &channel_leaders), &vec!['!']);
+        assert_eq!(leaders_for("#aviation", &leaders, &channel_leaders), &vec!['!', '?']);
+        assert_eq!(leaders_for("#other", &leaders, &channel_leaders), &vec!['&']);
+        // A channel without leaders takes no commands at all
+        assert!(leaders_for("#quiet", &leaders, &channel_leaders).is_empty());
+
+        assert!(parse_channel_leaders(&options(&[("leaders", "&")])).is_empty());
+    }
+
+    fn module_names(modules: &[(&'static str, modules::ModuleFactory)]) -> Vec<&'static str> {
+        modules.iter().map(|(name, _)| *name).collect()
+    }
+
+    #[test]
+    fn disabled_modules_are_left_out() {
+        let modules: &[(&'static str, modules::ModuleFactory)] = &[("weather", weather_module), ("other", other_weather_module)];
+        assert_eq!(module_names(&enabled_modules(modules, "")), vec!["weather", "other"]);
+        assert_eq!(module_names(&enabled_modules(modules, "other")), vec!["weather"]);
+        assert_eq!(module_names(&enabled_modules(modules, " weather , ,other ")), Vec::<&str>::new());
+        assert_eq!(module_names(&enabled_modules(modules, "Weather,typo")), vec!["weather", "other"]);
+    }
+
+    #[test]
+    fn every_module_can_be_disabled_by_name() {
+        for (name, _) in modules::ALL {
+            let enabled = module_names(&enabled_modules(modules::ALL, name));
+            assert_eq!(enabled.len(), modules::ALL.len() - 1);
+            assert!(!enabled.contains(name));
+        }
+    }
 }
@@ -13,11 +13,14 @@ extern crate pretty_env_logger;
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error;
 use std::fmt;
 use std::result::Result;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use futures::channel::mpsc;
 use futures::future;
 
 use irc::client;
@@ -28,6 +31,34 @@ pub mod modules;
 /// Utility functions to help write IRC bot commands.
 pub mod util;
 
+/// Helpers shared by the tests of the modules.
+#[cfg(test)]
+mod testing;
+
+/// The version of the bot, from Cargo.toml.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The abbreviated git commit the bot was built from, if it was built from a git checkout.
+pub const GIT_COMMIT: Option<&str> = option_env!("METARBOT_GIT_COMMIT");
+
+/// The version of the irc crate the bot was built with, if Cargo.lock was available.
+pub const IRC_VERSION: Option<&str> = option_env!("METARBOT_IRC_VERSION");
+
+/// The version of the compiler the bot was built with, e.g. "rustc 1.45.2 (d3fb005a3 2020-07-31)".
+/// The bot requires at least the rust-version declared in Cargo.toml.
+pub const RUSTC_VERSION: Option<&str> = option_env!("METARBOT_RUSTC_VERSION");
+
+/**
+ * The name and version of the bot along with the git commit, if known, e.g. "metarbot 0.1.0
+ * (1a2b3c4)".
+ */
+pub fn version() -> String {
+    match GIT_COMMIT {
+        Some(commit) => format!("metarbot {} ({})", VERSION, commit),
+        None => format!("metarbot {}", VERSION),
+    }
+}
+
 /// An error type for the IRC bot; wraps other errors where needed.
 #[derive(Debug)]
 pub enum BotError {
@@ -38,8 +69,29 @@ pub enum BotError {
     /** The bot has been asked to leave the current channel outside of a channel. */
     NoChannelToPart,
 
+    /** The bot has been asked to kick a user from the current channel outside of a channel. */
+    NoChannelToKick,
+
+    /** The bot has been asked to change the topic of the current channel outside of a channel. */
+    NoChannelForTopic,
+
+    /** The bot has been asked to invite a user to the current channel outside of a channel. */
+    NoChannelToInvite,
+
     /** A required configuration option is not set for the module. */
     Unconfigured(&'static str),
+
+    /** The command needs the list of channels the bot is in, but it is not available. */
+    NoChannelList,
+
+    /** The handler of the command with the given trigger panicked. */
+    Panicked(&'static str),
+
+    /**
+     * The command failed with a message that should be shown to the user that invoked it. The
+     * dispatcher sends it as a notice to the response target of the invoking message.
+     */
+    UserMessage(String),
 }
 
 /// Implementation of the Display trait for BotError, so it can be converted to a string.
@@ -50,8 +102,20 @@ impl fmt::Display for BotError {
                 write!(f, "Ignoring message since no response target is set"),
             BotError::NoChannelToPart =>
                 write!(f, "Requested to part the current channel outside of a channel"),
+            BotError::NoChannelToKick =>
+                write!(f, "Requested to kick from the current channel outside of a channel"),
+            BotError::NoChannelForTopic =>
+                write!(f, "Requested to change the topic of the current channel outside of a channel"),
+            BotError::NoChannelToInvite =>
+                write!(f, "Requested to invite to the current channel outside of a channel"),
             BotError::Unconfigured(message) =>
                 write!(f, "A required configuration option is missing: {}", message),
+            BotError::NoChannelList =>
+                write!(f, "The list of joined channels is not available"),
+            BotError::Panicked(trigger) =>
+                write!(f, "The handler for the {} command panicked", trigger),
+            BotError::UserMessage(ref message) =>
+                write!(f, "{}", message),
         }
     }
 }
@@ -65,7 +129,13 @@ impl error::Error for BotError {
         match *self {
             BotError::NoResponseTarget => None,
             BotError::NoChannelToPart => None,
+            BotError::NoChannelToKick => None,
+            BotError::NoChannelForTopic => None,
+            BotError::NoChannelToInvite => None,
             BotError::Unconfigured(_) => None,
+            BotError::NoChannelList => None,
+            BotError::Panicked(_) => None,
+            BotError::UserMessage(_) => None,
         }
     }
 }
@@ -73,7 +143,7 @@ impl error::Error for BotError {
 /**
  * Various actions the bot can trigger in response to a command. Each enum specifies one response.
  */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BotResponse {
     /**
      * Do nothing and just ignore the command.
@@ -96,6 +166,38 @@ pub enum BotResponse {
      */
     Join(String),
 
+    /**
+     * Join channels that may be protected by a key (mode +k). The first argument is a channel or a
+     * comma-separated list of channels, the second the matching key or comma-separated list of keys,
+     * in the same order. Channels without key must come last, since the server assigns the keys to
+     * the channels from the front of the list.
+     */
+    JoinKeyed(String, Option<String>),
+
+    /**
+     * Kick a user from a channel. The first argument is the channel, the second the nickname of the
+     * user to kick, and the third an optional reason.
+     */
+    Kick(String, String, Option<String>),
+
+    /**
+     * Invite a user to a channel. The first argument is the nickname of the user, the second the
+     * channel.
+     */
+    Invite(String, String),
+
+    /**
+     * Change the nickname of the bot. If the nickname is in use or rejected, the server reports an
+     * error, and the bot keeps its current nickname.
+     */
+    Nick(String),
+
+    /**
+     * Set the topic of the given channel to the second argument, or query the current topic if the
+     * second argument is None.
+     */
+    Topic(String, Option<String>),
+
     /**
      * Send a privmsg. This is probably what you will use most of the time. The first parameter is
      * the target of the privmsg (e.g. a channel name or a nickname), the second argument is the
@@ -108,6 +210,35 @@ pub enum BotResponse {
      * argument is the notice text.
      */
     Notice(String, String),
+
+    /**
+     * Send the wrapped privmsgs, notices and actions as replies to the message with the given
+     * IRCv3 message ID, using the +draft/reply client tag, so clients can show them as a thread.
+     * The dispatcher wraps the responses of commands in this if the server supports message tags;
+     * other responses are sent as usual.
+     */
+    Reply(String, Box<BotResponse>),
+
+    /**
+     * Send a CTCP ACTION, i.e. what a user would send using /me. The first parameter is the target
+     * of the action, the second argument is the text. The text must not be CTCP-wrapped already,
+     * the \x01ACTION ...\x01 framing is added when sending.
+     */
+    Action(String, String),
+
+    /**
+     * Send an arbitrary IRC command, e.g. a MODE or WHO that none of the other responses cover.
+     * This bypasses all of the bot's own checks, including the response target guard, so it must
+     * only be returned by trusted commands that are restricted to owners.
+     */
+    Raw(irc::proto::command::Command),
+
+    /**
+     * Send several responses in order, e.g. a privmsg followed by a part. An empty list behaves
+     * like Ignore. If one of the responses fails to send, the error is logged and the remaining
+     * responses are still sent.
+     */
+    Multi(Vec<BotResponse>),
 }
 
 /**
@@ -131,9 +262,23 @@ pub struct BotParameters<'a> {
      * A list of IRC prefixes (i.e. nick, username, hostname tuples) that are considered owners of
      * this bot. Each of the components will be evaluated as glob expressions against the prefix of
      * the user invoking a privileged command. Note that empty strings will implicitly match
-     * everything, unless all three parts are empty, in which case the entry is ignored.
+     * everything, unless all three parts are empty, in which case the entry is ignored. The list
+     * can be replaced at runtime, e.g. by reloading the owners_file.
      */
-    pub owners: &'a Vec<client::prelude::Prefix>,
+    pub owners: &'a RwLock<Vec<client::prelude::Prefix>>,
+
+    /**
+     * A list of services account names that are considered owners of this bot, matched against
+     * the account tag of the message if the server sends it. A user is an owner if either the
+     * prefix matches one of the owners or the account is one of the owner_accounts.
+     */
+    pub owner_accounts: &'a Vec<String>,
+
+    /**
+     * A list of IRC prefixes of users whose messages the bot ignores, matched like the owners. The
+     * list can be changed at runtime using the ignore and unignore commands.
+     */
+    pub ignored: &'a RwLock<Vec<client::prelude::Prefix>>,
 
     /**
      * A list of arguments given to the command, split at whitespaces.
@@ -144,6 +289,272 @@ pub struct BotParameters<'a> {
      * A map of additional options stored in the configuration.
      */
     pub options: &'a HashMap<String, String>,
+
+    /**
+     * A channel for responses that should be sent before the command completes, e.g. a "working
+     * on it" message from a command that takes a while. Responses sent here are dispatched right
+     * away, independent of the final result returned from the handler. Using it is optional.
+     */
+    pub responder: mpsc::UnboundedSender<BotResponse>,
+
+    /**
+     * The most recent errors returned by each command, recorded by the dispatcher.
+     */
+    pub errors: &'a ErrorLog,
+
+    /**
+     * The channels in which errors of commands are reported to the channel rather than only logged.
+     */
+    pub verbose: &'a VerboseChannels,
+
+    /**
+     * The uptime of the bot and the number of invocations of each command.
+     */
+    pub stats: &'a Stats,
+
+    /**
+     * The network the command was invoked on, i.e. the server of its configuration.
+     */
+    pub network: &'a str,
+
+    /**
+     * The current nickname of the bot.
+     */
+    pub current_nick: String,
+
+    /**
+     * The channels the bot is currently in. Empty if the channel list is not available.
+     */
+    pub channels: Vec<String>,
+
+    /**
+     * The triggers and descriptions of all registered commands, sorted by trigger.
+     */
+    pub commands: &'a [(&'static str, &'static str)],
+
+    /**
+     * The names of all loaded modules along with the sorted triggers of the commands they provide.
+     */
+    pub modules: &'a [(&'static str, Vec<&'static str>)],
+
+    /**
+     * Details about this particular invocation of the command, resolved by the dispatcher.
+     */
+    pub context: InvocationContext,
+}
+
+/**
+ * Details about a single invocation of a command that are derived from the received message, so
+ * commands do not each have to parse them from the message again.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct InvocationContext {
+    /**
+     * The services account of the user that invoked the command, if the server sent it in an
+     * account message tag.
+     */
+    pub account: Option<String>,
+
+    /**
+     * Whether the command was sent as a CTCP ACTION, i.e. using /me.
+     */
+    pub is_action: bool,
+
+    /**
+     * The leader character used to invoke the command, None if it was invoked without a leader in
+     * a query.
+     */
+    pub invoked_with: Option<char>,
+
+    /**
+     * The channel the command was invoked in, None if it was invoked in a query.
+     */
+    pub channel: Option<String>,
+}
+
+impl InvocationContext {
+    /**
+     * Resolve the context of an invocation from the received message and the leader character used
+     * to invoke the command, if any.
+     */
+    pub fn new(message: &irc::proto::message::Message, invoked_with: Option<char>) -> InvocationContext {
+        let account = util::message_tag(message, "account").map(str::to_string);
+
+        let (is_action, channel) = match message.command {
+            irc::proto::command::Command::PRIVMSG(ref target, ref text) =>
+                (text.starts_with("\u{1}ACTION "),
+                 if util::is_public(target) { Some(target.to_string()) } else { None }),
+            _ =>
+                (false, None),
+        };
+
+        InvocationContext { account, is_action, invoked_with, channel }
+    }
+}
+
+/**
+ * Parameters passed to BotCommand::observe for every received PRIVMSG.
+ */
+#[derive(Debug)]
+pub struct ObserveParameters<'a> {
+    /**
+     * The received PRIVMSG.
+     */
+    pub message: &'a irc::proto::message::Message,
+
+    /**
+     * The leader characters configured for channels. Messages starting with one of them are
+     * commands to the bot.
+     */
+    pub leaders: &'a Vec<char>,
+
+    /**
+     * A map of additional options stored in the configuration.
+     */
+    pub options: &'a HashMap<String, String>,
+
+    /**
+     * A channel for responses, e.g. a reaction to the observed message. Responses sent here are
+     * dispatched right away.
+     */
+    pub responder: mpsc::UnboundedSender<BotResponse>,
+}
+
+/**
+ * The set of channels in which the dispatcher reports the errors of commands to the channel in a
+ * notice, for live debugging. The setting is kept in memory only and reset when the bot restarts.
+ */
+#[derive(Debug, Default)]
+pub struct VerboseChannels {
+    channels: Mutex<HashSet<String>>,
+}
+
+impl VerboseChannels {
+    /**
+     * Enable or disable verbose error reporting for the given channel.
+     */
+    pub fn set(&self, channel: &str, verbose: bool) {
+        let mut channels = self.channels.lock().unwrap_or_else(|err| err.into_inner());
+        if verbose {
+            channels.insert(channel.to_lowercase());
+        } else {
+            channels.remove(&channel.to_lowercase());
+        }
+    }
+
+    /**
+     * Return true iff verbose error reporting is enabled for the given channel.
+     */
+    pub fn is_verbose(&self, channel: &str) -> bool {
+        let channels = self.channels.lock().unwrap_or_else(|err| err.into_inner());
+        channels.contains(&channel.to_lowercase())
+    }
+}
+
+/**
+ * The number of errors kept per command in the ErrorLog.
+ */
+const ERRORS_PER_COMMAND: usize = 5;
+
+/**
+ * A single error recorded in the ErrorLog.
+ */
+#[derive(Debug, Clone)]
+pub struct ErrorRecord {
+    /**
+     * The time at which the error was recorded.
+     */
+    pub time: Instant,
+
+    /**
+     * The error message, with secrets from the configuration redacted.
+     */
+    pub message: String,
+}
+
+/**
+ * A log of the errors most recently returned by each command, so they can be inspected without
+ * access to the bot's logs. Only the last few errors per command are kept.
+ */
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    entries: Mutex<HashMap<&'static str, VecDeque<ErrorRecord>>>,
+}
+
+impl ErrorLog {
+    /**
+     * Record an error returned by the command with the given trigger. The values of options that
+     * look like secrets (API keys, passwords and tokens) are redacted from the message.
+     */
+    pub fn record(&self, trigger: &'static str, error: &BotError, options: &HashMap<String, String>) {
+        let message = util::redact(&error.to_string(), options);
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        let errors = entries.entry(trigger).or_default();
+        if errors.len() >= ERRORS_PER_COMMAND {
+            errors.pop_front();
+        }
+        errors.push_back(ErrorRecord { time: Instant::now(), message });
+    }
+
+    /**
+     * Return the recorded errors of the command with the given trigger, oldest first.
+     */
+    pub fn get(&self, trigger: &str) -> Vec<ErrorRecord> {
+        let entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        entries.get(trigger).map_or_else(Vec::new, |errors| errors.iter().cloned().collect())
+    }
+
+    /**
+     * Return the triggers of all commands with recorded errors along with the number of errors.
+     */
+    pub fn counts(&self) -> Vec<(&'static str, usize)> {
+        let entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        let mut counts: Vec<(&'static str, usize)> = entries.iter().map(|(trigger, errors)| (*trigger, errors.len())).collect();
+        counts.sort();
+        counts
+    }
+}
+
+/**
+ * Statistics about the commands handled since the bot was started.
+ */
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    counts: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats { started: Instant::now(), counts: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Stats {
+    /**
+     * Record an invocation of the command with the given trigger.
+     */
+    pub fn record(&self, trigger: &'static str) {
+        *self.counts.lock().unwrap_or_else(|err| err.into_inner()).entry(trigger).or_default() += 1;
+    }
+
+    /**
+     * The time elapsed since the bot was started.
+     */
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /**
+     * Return the triggers of all invoked commands along with their number of invocations, most
+     * frequently invoked first.
+     */
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        let counts = self.counts.lock().unwrap_or_else(|err| err.into_inner());
+        let mut counts: Vec<(&'static str, u64)> = counts.iter().map(|(trigger, count)| (*trigger, *count)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
 }
 
 /**
@@ -157,7 +568,7 @@ pub type BotCommandResult = Result<BotResponse, BotError>;
  */
 pub type BotCommandFutureResult<'a> = future::BoxFuture<'a, BotCommandResult>;
 
-/**
+/*
  * A box containing an async function that will be invoked to handle a command.
  */
 //type BotCommandHandler = Box<dyn Fn(BotParameters) -> BotCommandFutureResult<'static>>;
@@ -166,14 +577,174 @@ pub type BotCommandFutureResult<'a> = future::BoxFuture<'a, BotCommandResult>;
  * A trait implementing a command.
  */
 #[async_trait::async_trait]
-pub trait BotCommand {
+pub trait BotCommand: Sync {
     /**
-     * The trigger string for this bot command, must be a single-word string.
+     * The name of this bot command, which identifies it in e.g. the help, the statistics, the error
+     * log, and the per-command options, and which must be unique among all commands. Users invoke the
+     * command with any of its triggers(), which default to just this name, but may instead be phrases
+     * of several words, e.g. "metar check-key" for the check-key command.
      */
     fn trigger(&self) -> &'static str;
 
+    /**
+     * A one-line description of what this command does, shown by the help command. Defaults to an
+     * empty string.
+     */
+    fn description(&self) -> &'static str {
+        ""
+    }
+
+    /**
+     * All phrases that invoke this command, which may consist of several words, e.g. "airport
+     * info". When a message matches several phrases of different commands, the longest phrase
+     * wins. Defaults to just the trigger().
+     */
+    fn triggers(&self) -> Vec<&'static str> {
+        vec![self.trigger()]
+    }
+
     /**
      * Handler for this bot command, will be invoked when the trigger word has been seen.
+     *
+     * If the handler panics, the panic is caught, logged and turned into BotError::Panicked, so the
+     * bot keeps running. Since the handler may be interrupted at any point, it must be unwind-safe:
+     * a panic must not leave state shared with other invocations (e.g. caches behind a Mutex) in
+     * an inconsistent state.
      */
     async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult;
+
+    /**
+     * Observe a PRIVMSG, called for every PRIVMSG the bot receives before commands are dispatched,
+     * whether or not it invokes this command. Since messages are observed one after another, this
+     * must not block for long; slow work should be spawned and send its results through the
+     * responder. Defaults to doing nothing.
+     */
+    async fn observe(&self, _params: ObserveParameters<'_>) {
+    }
+
+    /**
+     * Opt into response caching by returning a key for the given invocation. Successful responses
+     * are cached under this key for cache_ttl(), and later invocations with the same key are
+     * answered from the cache without calling handle(). Since the cached response includes its
+     * target, the key must cover everything the response depends on, including the response
+     * target. Errors are never cached. Defaults to no caching.
+     */
+    fn cache_key(&self, _params: &BotParameters<'_>) -> Option<String> {
+        None
+    }
+
+    /**
+     * How long responses cached under cache_key() remain valid. Caching is only enabled if both
+     * this and cache_key() return a value.
+     */
+    fn cache_ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /**
+     * Whether only owners of the bot may use this command. The dispatcher checks this before
+     * calling handle(), and tells other users that they are not authorized. Defaults to false.
+     */
+    fn owner_only(&self) -> bool {
+        false
+    }
+
+    /**
+     * Whether the arguments of this command must not appear in the log of command invocations,
+     * e.g. because they contain passwords. Defaults to false.
+     */
+    fn redact_args(&self) -> bool {
+        false
+    }
+
+    /**
+     * The maximum number of invocations of this command that may run at the same time. Further
+     * invocations wait until a running one has completed. Defaults to no limit.
+     */
+    fn max_concurrency(&self) -> Option<usize> {
+        None
+    }
+
+    /**
+     * Whether this command may send privmsgs and notices to targets other than the channel it was
+     * invoked in or the user that invoked it. Unless this returns true, the dispatcher suppresses
+     * such responses, so a bug or a crafted message cannot make a command leak information into
+     * another channel. Defaults to false.
+     */
+    fn cross_target(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, message};
+
+    #[test]
+    fn context_of_channel_invocation() {
+        let context = InvocationContext::new(
+            &message("@account=alice :alice!a@example.org PRIVMSG #weather :&metar KSFO"), Some('&'));
+        assert_eq!(context.account.as_deref(), Some("alice"));
+        assert!(!context.is_action);
+        assert_eq!(context.invoked_with, Some('&'));
+        assert_eq!(context.channel.as_deref(), Some("#weather"));
+    }
+
+    #[test]
+    fn context_of_query_invocation() {
+        let context = InvocationContext::new(&message(":alice!a@example.org PRIVMSG metarbot :metar KSFO"), None);
+        assert_eq!(context.account, None);
+        assert!(!context.is_action);
+        assert_eq!(context.invoked_with, None);
+        assert_eq!(context.channel, None);
+    }
+
+    #[test]
+    fn context_of_action_invocation() {
+        let context = InvocationContext::new(
+            &message(":alice!a@example.org PRIVMSG #weather :\u{1}ACTION &metar KSFO\u{1}"), Some('&'));
+        assert!(context.is_action);
+        assert_eq!(context.channel.as_deref(), Some("#weather"));
+    }
+
+    /**
+     * A command that answers with the bot's nickname and the channels it is in.
+     */
+    struct WhereAmICommand {}
+
+    #[async_trait::async_trait]
+    impl BotCommand for WhereAmICommand {
+        fn trigger(&self) -> &'static str {
+            "whereami"
+        }
+
+        async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+            Ok(BotResponse::Privmsg(
+                params.message.response_target().ok_or(BotError::NoResponseTarget)?.to_string(),
+                format!("{} in {}", params.current_nick, params.channels.join(", "))))
+        }
+    }
+
+    #[test]
+    fn command_reads_current_nick_and_channels() {
+        let fixture = Fixture {
+            channels: vec!["#weather".to_string(), "#aviation".to_string()],
+            ..Fixture::default()
+        };
+        let params = fixture.params(":alice!a@example.org PRIVMSG #weather :&whereami", &[]);
+
+        assert!(matches!(futures::executor::block_on(WhereAmICommand{}.handle(params)),
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "#weather" && text == "metarbot in #weather, #aviation"));
+    }
+
+    #[test]
+    fn version_is_not_empty() {
+        assert!(!VERSION.is_empty());
+        assert!(version().starts_with(&format!("metarbot {}", VERSION)), "{}", version());
+        match GIT_COMMIT {
+            Some(commit) => assert!(!commit.is_empty() && version().ends_with(&format!(" ({})", commit))),
+            None => assert_eq!(version(), format!("metarbot {}", VERSION)),
+        }
+    }
 }
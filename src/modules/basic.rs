@@ -0,0 +1,183 @@
+//! Module that provides basic commands to check whether the bot is alive and how it is doing
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+extern crate chrono;
+
+use std::time::Duration;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+    IRC_VERSION,
+    RUSTC_VERSION,
+    util::message_tag,
+    version,
+};
+
+struct PingCommand {}
+struct StatsCommand {}
+struct VersionCommand {}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(PingCommand{}),
+        Box::new(StatsCommand{}),
+        Box::new(VersionCommand{}),
+    ]
+}
+
+/**
+ * Format a duration in days, hours and minutes, e.g. "3d 4h 12m", leaving out leading zero units.
+ */
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    match (minutes / 1440, minutes % 1440 / 60, minutes % 60) {
+        (0, 0, minutes) => format!("{}m", minutes),
+        (0, hours, minutes) => format!("{}h {}m", hours, minutes),
+        (days, hours, minutes) => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for PingCommand {
+    fn trigger(&self) -> &'static str {
+        "ping"
+    }
+
+    fn description(&self) -> &'static str {
+        "Check whether the bot is responding, with the latency if the server sends message times"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        // The time tag is only sent by servers that support the IRCv3 server-time capability
+        let latency = message_tag(&params.message, "time")
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| chrono::Utc::now().signed_duration_since(time));
+
+        Ok(BotResponse::Privmsg(
+            response_target,
+            match latency {
+                Some(latency) => format!("pong ({}ms)", latency.num_milliseconds()),
+                None => "pong".to_string(),
+            }))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for StatsCommand {
+    fn trigger(&self) -> &'static str {
+        "stats"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the uptime of the bot and how often each command was used"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        // This invocation has already been counted, so there is at least one command
+        let counts = params.stats.counts();
+        let total: u64 = counts.iter().map(|(_, count)| count).sum();
+        Ok(BotResponse::Privmsg(
+            response_target,
+            format!("Up {}, {} commands handled: {}",
+                format_uptime(params.stats.uptime()),
+                total,
+                counts.iter()
+                    .map(|(trigger, count)| format!("{} {}", trigger, count))
+                    .collect::<Vec<String>>()
+                    .join(", "))))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for VersionCommand {
+    fn trigger(&self) -> &'static str {
+        "version"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the version of the bot and what it was built with"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let mut parts = vec![version()];
+        if let Some(irc_version) = IRC_VERSION {
+            parts.push(format!("irc {}", irc_version));
+        }
+        if let Some(rustc_version) = RUSTC_VERSION {
+            parts.push(rustc_version.to_string());
+        }
+        Ok(BotResponse::Privmsg(response_target, parts.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::testing::Fixture;
+
+    #[test]
+    fn uptime_is_formatted_in_days_hours_and_minutes() {
+        assert_eq!(format_uptime(Duration::from_secs(0)), "0m");
+        assert_eq!(format_uptime(Duration::from_secs(59)), "0m");
+        assert_eq!(format_uptime(Duration::from_secs(60)), "1m");
+        assert_eq!(format_uptime(Duration::from_secs(3600)), "1h 0m");
+        assert_eq!(format_uptime(Duration::from_secs(86399)), "23h 59m");
+        assert_eq!(format_uptime(Duration::from_secs(86400)), "1d 0h 0m");
+        assert_eq!(format_uptime(Duration::from_secs(3 * 86400 + 4 * 3600 + 12 * 60 + 30)), "3d 4h 12m");
+        assert_eq!(format_uptime(Duration::from_secs(400 * 86400)), "400d 0h 0m");
+    }
+
+    #[test]
+    fn stats_count_commands_most_frequent_first() {
+        let fixture = Fixture::default();
+        for trigger in &["metar", "taf", "metar", "stats", "metar", "taf"] {
+            fixture.stats.record(trigger);
+        }
+
+        let result = block_on(StatsCommand{}.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&stats", &[])));
+        assert!(matches!(result, Ok(BotResponse::Privmsg(ref target, ref text))
+            if target == "#weather" && text == "Up 0m, 6 commands handled: metar 3, taf 2, stats 1"), "{:?}", result);
+    }
+
+    #[test]
+    fn version_names_the_bot_and_its_build() {
+        let fixture = Fixture::default();
+        let result = block_on(VersionCommand{}.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&version", &[])));
+        let text = match result {
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "#weather" => text.to_string(),
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert!(text.starts_with(&version()), "{}", text);
+        if let Some(irc_version) = IRC_VERSION {
+            assert!(text.contains(&format!("irc {}", irc_version)), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Module that rolls dice given in the notation common in role-playing games, e.g. 2d6+3, and makes
+//! random choices
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+};
+
+static MAX_DICE: u32 = 100;
+static MAX_SIDES: u32 = 1000;
+static MAX_MODIFIER: i64 = 1_000_000;
+
+struct RollCommand {
+    rng: Mutex<XorShift>,
+}
+
+struct ChooseCommand {
+    rng: Mutex<XorShift>,
+}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(RollCommand{ rng: Mutex::new(XorShift::new()) }),
+        Box::new(ChooseCommand{ rng: Mutex::new(XorShift::new()) }),
+    ]
+}
+
+/**
+ * A small xorshift64* pseudo-random number generator. Dice rolls do not need cryptographic
+ * randomness, so this avoids pulling in a dependency.
+ */
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    /**
+     * Create a generator seeded from the random keys the standard library uses for HashMaps.
+     */
+    fn new() -> XorShift {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        XorShift::with_seed(hasher.finish())
+    }
+
+    /**
+     * Create a generator with the given seed, which always produces the same sequence.
+     */
+    fn with_seed(seed: u64) -> XorShift {
+        // The state must never be zero, or the generator only produces zeros
+        XorShift { state: seed | 1 }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /**
+     * A value between 1 and sides, inclusive.
+     */
+    fn roll(&mut self, sides: u32) -> u32 {
+        (self.next() % u64::from(sides)) as u32 + 1
+    }
+}
+
+/**
+ * A parsed dice expression: roll count dice with the given number of sides and add modifier.
+ */
+#[derive(Debug, PartialEq)]
+struct Dice {
+    count: u32,
+    sides: u32,
+    modifier: i64,
+}
+
+/**
+ * Parse dice notation such as "2d6+3", "d20" or "4d8-1". The count defaults to 1 and the modifier
+ * to 0. Returns None if the expression is malformed or the modifier exceeds MAX_MODIFIER, so the
+ * total cannot overflow.
+ */
+fn parse_dice(expression: &str) -> Option<Dice> {
+    let expression = expression.to_lowercase();
+    let (count, rest) = expression.split_at(expression.find('d')?);
+    let rest = &rest[1..];
+
+    let (sides, modifier): (&str, i64) = match rest.find(['+', '-']) {
+        Some(idx) => (&rest[..idx], rest[idx..].parse().ok()?),
+        None => (rest, 0),
+    };
+
+    let count = if count.is_empty() { 1 } else { count.parse().ok()? };
+    let sides = sides.parse().ok()?;
+    if count == 0 || sides == 0 || !(-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier) {
+        return None;
+    }
+
+    Some(Dice { count, sides, modifier })
+}
+
+/**
+ * Split the arguments of the choose command into options. If any argument contains a comma, the
+ * arguments are joined and split at the commas, so "pizza, fried rice" offers two options;
+ * otherwise, every argument is an option. Empty options are dropped.
+ */
+fn parse_options(args: &[String]) -> Vec<String> {
+    let options: Vec<String> = if args.iter().any(|arg| arg.contains(',')) {
+        args.join(" ").split(',').map(|option| option.trim().to_string()).collect()
+    } else {
+        args.to_vec()
+    };
+    options.into_iter().filter(|option| !option.is_empty()).collect()
+}
+
+#[async_trait::async_trait]
+impl BotCommand for RollCommand {
+    fn trigger(&self) -> &'static str {
+        "roll"
+    }
+
+    fn description(&self) -> &'static str {
+        "Roll dice in the usual notation, e.g. 2d6+3"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let dice = match params.args.first().and_then(|expression| parse_dice(expression)) {
+            Some(dice) => dice,
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <dice, e.g. 2d6+3, d20 or 4d8-1>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        if dice.count > MAX_DICE || dice.sides > MAX_SIDES {
+            return Ok(BotResponse::Privmsg(
+                response_target,
+                format!("Please roll at most {} dice with at most {} sides", MAX_DICE, MAX_SIDES)));
+        }
+
+        let rolls: Vec<u32> = {
+            let mut rng = self.rng.lock().unwrap_or_else(|err| err.into_inner());
+            (0..dice.count).map(|_| rng.roll(dice.sides)).collect()
+        };
+        let total = rolls.iter().map(|roll| i64::from(*roll)).sum::<i64>() + dice.modifier;
+
+        Ok(BotResponse::Privmsg(
+            response_target,
+            format!("{}{} = {}",
+                rolls.iter().map(u32::to_string).collect::<Vec<String>>().join(" + "),
+                match dice.modifier {
+                    0 => "".to_string(),
+                    modifier => format!(" ({:+})", modifier),
+                },
+                total)))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for ChooseCommand {
+    fn trigger(&self) -> &'static str {
+        "choose"
+    }
+
+    fn description(&self) -> &'static str {
+        "Pick one of several whitespace- or comma-separated options, or flip a coin"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let options = if params.args.is_empty() {
+            vec!["heads".to_string(), "tails".to_string()]
+        } else {
+            parse_options(&params.args)
+        };
+        if options.is_empty() {
+            return Ok(BotResponse::Privmsg(
+                response_target,
+                format!("Usage: {}{} [option ...], e.g. pizza, fried rice",
+                    params.leaders.first().map_or("".to_string(), char::to_string),
+                    self.trigger())));
+        }
+
+        let choice = self.rng.lock().unwrap_or_else(|err| err.into_inner()).roll(options.len() as u32) as usize - 1;
+        Ok(BotResponse::Privmsg(response_target, options[choice].clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    #[test]
+    fn dice_notation_is_parsed() {
+        assert_eq!(parse_dice("2d6+3"), Some(Dice { count: 2, sides: 6, modifier: 3 }));
+        assert_eq!(parse_dice("D20"), Some(Dice { count: 1, sides: 20, modifier: 0 }));
+        assert_eq!(parse_dice("4d8-1"), Some(Dice { count: 4, sides: 8, modifier: -1 }));
+        assert_eq!(parse_dice("1d6+1000000"), Some(Dice { count: 1, sides: 6, modifier: 1_000_000 }));
+    }
+
+    #[test]
+    fn malformed_dice_are_rejected() {
+        for expression in &["", "d", "2d", "0d6", "2d0", "2x6", "2d6+", "2d6+-3", "2d6+3+4", "-2d6", "2d6 + 3"] {
+            assert_eq!(parse_dice(expression), None, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn huge_modifiers_are_rejected() {
+        assert_eq!(parse_dice("1d6+1000001"), None);
+        assert_eq!(parse_dice("1d6-1000001"), None);
+        assert_eq!(parse_dice("100d1000+9223372036854775807"), None);
+        assert_eq!(parse_dice("1d6-9223372036854775808"), None);
+    }
+
+    #[test]
+    fn roll_with_huge_modifier_shows_usage() {
+        let fixture = Fixture::default();
+        let command = RollCommand { rng: Mutex::new(XorShift::new()) };
+        let params = fixture.params(":alice!a@example.org PRIVMSG #games :&roll 100d1000+9223372036854775807", &["100d1000+9223372036854775807"]);
+        assert!(matches!(futures::executor::block_on(command.handle(params)),
+            Ok(BotResponse::Privmsg(_, ref text)) if text.starts_with("Usage: &roll")));
+    }
+
+    fn handle(command: &dyn BotCommand, args: &[&str]) -> String {
+        let fixture = Fixture::default();
+        let params = fixture.params(":alice!a@example.org PRIVMSG #games :&x", args);
+        match futures::executor::block_on(command.handle(params)) {
+            Ok(BotResponse::Privmsg(ref target, text)) if target == "#games" => text,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn seeded_generators_repeat_their_sequence() {
+        let mut first = XorShift::with_seed(42);
+        let mut second = XorShift::with_seed(42);
+        let rolls: Vec<u32> = (0..20).map(|_| first.roll(6)).collect();
+        assert_eq!(rolls[..8], [2, 4, 2, 5, 1, 5, 6, 1]);
+        assert_eq!(rolls, (0..20).map(|_| second.roll(6)).collect::<Vec<u32>>());
+        assert_ne!(rolls, (0..20).map(|_| XorShift::with_seed(43).roll(6)).collect::<Vec<u32>>());
+
+        let mut zero = XorShift::with_seed(0);
+        assert!((0..10).any(|_| zero.next() != 0));
+    }
+
+    #[test]
+    fn rolls_cover_all_sides_and_nothing_else() {
+        let mut rng = XorShift::with_seed(7);
+        let rolls: Vec<u32> = (0..600).map(|_| rng.roll(6)).collect();
+        assert!(rolls.iter().all(|roll| (1..=6).contains(roll)));
+        assert!((1..=6).all(|side| rolls.contains(&side)));
+        assert!((0..100).all(|_| rng.roll(1) == 1));
+    }
+
+    #[test]
+    fn seeded_rolls_are_reported_with_their_total() {
+        let mut expected = XorShift::with_seed(2020);
+        let (a, b) = (expected.roll(6), expected.roll(6));
+
+        let command = RollCommand { rng: Mutex::new(XorShift::with_seed(2020)) };
+        assert_eq!(handle(&command, &["2d6+3"]), format!("{} + {} (+3) = {}", a, b, a + b + 3));
+        let c = expected.roll(20);
+        assert_eq!(handle(&command, &["d20-2"]), format!("{} (-2) = {}", c, i64::from(c) - 2));
+        let d = expected.roll(8);
+        assert_eq!(handle(&command, &["1d8"]), format!("{} = {}", d, d));
+    }
+
+    #[test]
+    fn seeded_choices_pick_from_the_options() {
+        let mut expected = XorShift::with_seed(99);
+        let options = ["pizza", "fried rice", "salad"];
+        let coin = ["heads", "tails"];
+
+        let command = ChooseCommand { rng: Mutex::new(XorShift::with_seed(99)) };
+        assert_eq!(handle(&command, &["pizza,", "fried", "rice,", "salad"]), options[expected.roll(3) as usize - 1]);
+        assert_eq!(handle(&command, &[]), coin[expected.roll(2) as usize - 1]);
+        assert_eq!(handle(&command, &["only"]), "only");
+        assert!(handle(&command, &[",", ","]).starts_with("Usage: &choose"));
+    }
+}
@@ -0,0 +1,144 @@
+//! Module that provides help and modules commands listing the commands of the bot
+//!
+//! Commands cannot see each other, so the dispatcher passes the triggers and descriptions of all
+//! registered commands to every invocation in BotParameters::commands, which the help command
+//! reads, and the loaded modules in BotParameters::modules. This avoids a reference from the help
+//! command back into the registry it is part of.
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+};
+
+struct HelpCommand {}
+struct ModulesCommand {}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(HelpCommand{}),
+        Box::new(ModulesCommand{}),
+    ]
+}
+
+#[async_trait::async_trait]
+impl BotCommand for HelpCommand {
+    fn trigger(&self) -> &'static str {
+        "help"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the available commands, or describe the given command"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let leader = params.leaders.first().map_or("".to_string(), char::to_string);
+        match params.args.first() {
+            Some(trigger) => {
+                let trigger = trigger.trim_start_matches(|c| params.leaders.contains(&c)).to_lowercase();
+                Ok(BotResponse::Privmsg(
+                    response_target,
+                    match params.commands.iter().find(|(candidate, _)| *candidate == trigger) {
+                        Some((_, "")) =>
+                            format!("{}{}: no description available", leader, trigger),
+                        Some((_, description)) =>
+                            format!("{}{}: {}", leader, trigger, description),
+                        None =>
+                            format!("Unknown command {}", trigger),
+                    }))
+            },
+            None =>
+                Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Available commands: {}; use {}{} <command> for details",
+                        params.commands.iter()
+                            .map(|(trigger, _)| format!("{}{}", leader, trigger))
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                        leader,
+                        self.trigger()))),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for ModulesCommand {
+    fn trigger(&self) -> &'static str {
+        "modules"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the loaded modules and the commands each of them provides"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        Ok(BotResponse::Privmsg(
+            response_target,
+            params.modules.iter()
+                .map(|(module, triggers)| format!("{}: {}", module, triggers.join(", ")))
+                .collect::<Vec<String>>()
+                .join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::testing::Fixture;
+
+    /**
+     * The commands the dispatcher would pass to the help command.
+     */
+    const COMMANDS: &[(&str, &str)] = &[
+        ("help", "List the available commands, or describe the given command"),
+        ("metar", "Show the current weather at an airport"),
+        ("undocumented", ""),
+    ];
+
+    fn help(args: &[&str]) -> String {
+        let fixture = Fixture::default();
+        let mut params = fixture.params(":alice!a@example.org PRIVMSG #weather :&help", args);
+        params.commands = COMMANDS;
+        match block_on(HelpCommand{}.handle(params)) {
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "#weather" => text.clone(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn help_lists_all_commands() {
+        assert_eq!(help(&[]),
+            "Available commands: &help, &metar, &undocumented; use &help <command> for details");
+    }
+
+    #[test]
+    fn help_describes_the_given_command() {
+        assert_eq!(help(&["metar"]), "&metar: Show the current weather at an airport");
+        assert_eq!(help(&["&METAR"]), "&metar: Show the current weather at an airport");
+        assert_eq!(help(&["undocumented"]), "&undocumented: no description available");
+        assert_eq!(help(&["&taf"]), "Unknown command taf");
+    }
+}
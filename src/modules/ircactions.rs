@@ -5,6 +5,11 @@
 
 extern crate async_trait;
 extern crate irc;
+extern crate toml;
+
+use std::collections::BTreeMap;
+
+use irc::client::prelude::Prefix;
 
 use crate::{
     BotCommand,
@@ -12,15 +17,26 @@ use crate::{
     BotError,
     BotParameters,
     BotResponse,
-    util::is_owner,
+    util::ensure_query,
+    util::load_owners,
     util::is_public,
+    util::is_valid_nickname,
 };
 
-use irc::client;
-
 struct IrcJoinCommand {}
 struct IrcPartCommand {}
 struct IrcQuitCommand {}
+struct IrcKickCommand {}
+struct IrcTopicCommand {}
+struct IrcNickCommand {}
+struct IrcInviteCommand {}
+struct IrcSayCommand {}
+struct AclCommand {}
+struct ErrorsCommand {}
+struct VerboseCommand {}
+struct ReloadCommand {}
+struct IgnoreCommand {}
+struct UnignoreCommand {}
 
 /**
  * Factory function that will create instances of all implemented commands in this module.
@@ -30,101 +46,771 @@ pub fn mk() -> Vec<Box<dyn BotCommand>> {
         Box::new(IrcJoinCommand{}),
         Box::new(IrcPartCommand{}),
         Box::new(IrcQuitCommand{}),
+        Box::new(IrcKickCommand{}),
+        Box::new(IrcTopicCommand{}),
+        Box::new(IrcNickCommand{}),
+        Box::new(IrcInviteCommand{}),
+        Box::new(IrcSayCommand{}),
+        Box::new(AclCommand{}),
+        Box::new(ErrorsCommand{}),
+        Box::new(VerboseCommand{}),
+        Box::new(ReloadCommand{}),
+        Box::new(IgnoreCommand{}),
+        Box::new(UnignoreCommand{}),
     ]
 }
 
+#[async_trait::async_trait]
+impl BotCommand for IrcJoinCommand {
+    fn trigger(&self) -> &'static str {
+        "join"
+    }
+
+    fn description(&self) -> &'static str {
+        "Join one or more comma-separated channels, optionally with their keys (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    fn redact_args(&self) -> bool {
+        // Channel keys are passwords
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        // Like JOIN itself, keys are given as a comma-separated list in the order of the channels
+        let (channels, keys) = match params.args.as_slice() {
+            [channels] if channels.split(',').all(is_public) =>
+                (channels, None),
+            [channels, keys] if channels.split(',').all(is_public) =>
+                (channels, Some(keys.to_string())),
+            [] =>
+                return Ok(BotResponse::Ignore),
+            _ =>
+                return Ok(match params.message.source_nickname() {
+                    Some(source_nickname) =>
+                        BotResponse::Notice(
+                            source_nickname.to_string(),
+                            format!("Usage: {} <channel>[,<channel>...] [<key>[,<key>...]]", self.trigger())),
+                    None =>
+                        BotResponse::Ignore,
+                }),
+        };
+
+        Ok(BotResponse::JoinKeyed(channels.to_string(), keys))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for IrcPartCommand {
+    fn trigger(&self) -> &'static str {
+        "part"
+    }
+
+    fn description(&self) -> &'static str {
+        "Leave one or more channels (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        // The first argument is always a channel; further arguments are parted as well as long as
+        // they look like channels, and everything after that is the part message. Channels are
+        // joined into the comma-separated list that PART accepts, so parting several channels
+        // still requires just a single response.
+        let channel_args = match params.args.split_first() {
+            Some((_, rest)) =>
+                1 + rest.iter()
+                    .take_while(|arg| arg.split(',').all(is_public))
+                    .count(),
+            None => 0,
+        };
+
+        let channels: Vec<&str> = params.args[..channel_args].iter()
+            .flat_map(|arg| arg.split(','))
+            .filter(|channel| !channel.is_empty())
+            .collect();
+
+        // "*" parts all channels the bot is in
+        let channels: Vec<&str> = if channels.contains(&"*") {
+            if params.channels.is_empty() {
+                return Err(BotError::NoChannelList);
+            }
+            params.channels.iter().map(String::as_str).collect()
+        } else {
+            channels
+        };
+
+        let channel = if channels.is_empty() {
+            match params.message.response_target() {
+                Some(response_target) =>
+                    if is_public(response_target) {
+                        Ok(response_target.to_string())
+                    } else {
+                        Err(BotError::NoChannelToPart)
+                    },
+                None =>
+                    Err(BotError::NoChannelToPart),
+            }?
+        } else {
+            channels.join(",")
+        };
+
+        let comment = if params.args.len() > channel_args {
+            Some(params.args[channel_args..].join(" "))
+        } else {
+            None
+        };
+
+        Ok(BotResponse::Part(channel, comment))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for IrcQuitCommand {
+    fn trigger(&self) -> &'static str {
+        "quit"
+    }
+
+    fn description(&self) -> &'static str {
+        "Disconnect from the server (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        Ok(BotResponse::Quit(
+            if !params.args.is_empty() {
+                Some(params.args.join(" "))
+            } else {
+                None
+            }))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for IrcKickCommand {
+    fn trigger(&self) -> &'static str {
+        "kick"
+    }
+
+    fn description(&self) -> &'static str {
+        "Kick a user from a channel (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        // An explicit channel may precede the nickname; otherwise, kick from the current channel
+        let (channel, args) = match params.args.split_first() {
+            Some((channel, rest)) if is_public(channel) =>
+                (channel.to_string(), rest),
+            _ =>
+                match params.message.response_target() {
+                    Some(response_target) if is_public(response_target) =>
+                        (response_target.to_string(), params.args.as_slice()),
+                    _ =>
+                        return Err(BotError::NoChannelToKick),
+                },
+        };
+
+        match args.split_first() {
+            Some((nickname, reason)) =>
+                Ok(BotResponse::Kick(
+                    channel,
+                    nickname.to_string(),
+                    if !reason.is_empty() {
+                        Some(reason.join(" "))
+                    } else {
+                        None
+                    })),
+            None =>
+                Ok(BotResponse::Ignore),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for IrcTopicCommand {
+    fn trigger(&self) -> &'static str {
+        "topic"
+    }
+
+    fn description(&self) -> &'static str {
+        "Set the topic of a channel (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        // Like kick, an explicit channel may precede the topic
+        let (channel, args) = match params.args.split_first() {
+            Some((channel, rest)) if is_public(channel) =>
+                (channel.to_string(), rest),
+            _ =>
+                match params.message.response_target() {
+                    Some(response_target) if is_public(response_target) =>
+                        (response_target.to_string(), params.args.as_slice()),
+                    _ =>
+                        return Err(BotError::NoChannelForTopic),
+                },
+        };
+
+        Ok(BotResponse::Topic(
+            channel,
+            if !args.is_empty() {
+                Some(args.join(" "))
+            } else {
+                None
+            }))
+    }
+}
+
 /**
- * Function to ensure that the person sending the message is the owner of the bot. If that is the
- * case, None will be returned, and execution of the command should continue. Otherwise, a suitable
- * error message is returned as a BotCommandResult, which should be bubbled up to the caller.
+ * Changes the nickname of the bot. If the nickname is already in use, the server rejects the change
+ * and the bot keeps its current nickname; there is no automatic fallback to another nickname.
  */
-fn ensure_owner(command: &str, params: &BotParameters<'_>) -> Option<BotCommandResult> {
-    if !is_owner(&params.message.prefix.as_ref().unwrap_or(&client::prelude::Prefix::new_from_str("")), &params.owners) {
-        if let Some(source_nickname) = params.message.source_nickname() {
-            Some(Ok(BotResponse::Notice(
-                source_nickname.to_string(),
-                format!("You are not authorized to use the {} command", command))))
-        } else {
-            Some(Ok(BotResponse::Ignore))
+#[async_trait::async_trait]
+impl BotCommand for IrcNickCommand {
+    fn trigger(&self) -> &'static str {
+        "nick"
+    }
+
+    fn description(&self) -> &'static str {
+        "Change the nickname of the bot (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        match (params.args.as_slice(), params.message.source_nickname()) {
+            ([nickname], _) if is_valid_nickname(nickname) =>
+                Ok(BotResponse::Nick(nickname.to_string())),
+            (_, Some(source_nickname)) =>
+                Ok(BotResponse::Notice(
+                    source_nickname.to_string(),
+                    format!("Usage: {} <nickname>, where the nickname starts with a letter and has no spaces", self.trigger()))),
+            (_, None) =>
+                Ok(BotResponse::Ignore),
         }
-    } else {
-        None
     }
 }
 
+#[async_trait::async_trait]
+impl BotCommand for IrcInviteCommand {
+    fn trigger(&self) -> &'static str {
+        "invite"
+    }
+
+    fn description(&self) -> &'static str {
+        "Invite a user to a channel (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let (nickname, channel) = match params.args.as_slice() {
+            [nickname] =>
+                match params.message.response_target() {
+                    Some(response_target) if is_public(response_target) =>
+                        (nickname, response_target.to_string()),
+                    _ =>
+                        return Err(BotError::NoChannelToInvite),
+                },
+            [nickname, channel] =>
+                (nickname, channel.to_string()),
+            _ =>
+                return Ok(BotResponse::Ignore),
+        };
+
+        if !is_valid_nickname(nickname) || !is_public(&channel) {
+            return Ok(match params.message.source_nickname() {
+                Some(source_nickname) =>
+                    BotResponse::Notice(
+                        source_nickname.to_string(),
+                        format!("Usage: {} <nickname> [channel]", self.trigger())),
+                None =>
+                    BotResponse::Ignore,
+            });
+        }
+
+        Ok(BotResponse::Invite(nickname.to_string(), channel))
+    }
+}
 
 #[async_trait::async_trait]
-impl BotCommand for IrcJoinCommand {
+impl BotCommand for IrcSayCommand {
     fn trigger(&self) -> &'static str {
-        "join"
+        "say"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send a message to a channel or user (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    fn cross_target(&self) -> bool {
+        true
     }
 
     async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
-        if let Some(botcommand) = ensure_owner(self.trigger(), &params) {
+        let (target, text) = match params.args.as_slice() {
+            [target, text @ ..] if !text.is_empty() =>
+                (target, text.join(" ")),
+            _ =>
+                return Ok(match params.message.source_nickname() {
+                    Some(source_nickname) =>
+                        BotResponse::Notice(
+                            source_nickname.to_string(),
+                            format!("Usage: {} <channel or nickname> <text>", self.trigger())),
+                    None =>
+                        BotResponse::Ignore,
+                }),
+        };
+
+        // Messages to channels the bot is not in would either fail or be sent from outside
+        let in_channel = params.channels.is_empty() || params.channels.iter().any(|channel| channel.eq_ignore_ascii_case(target));
+        if is_public(target) && !in_channel {
+            return Ok(match params.message.source_nickname() {
+                Some(source_nickname) =>
+                    BotResponse::Notice(
+                        source_nickname.to_string(),
+                        format!("I am not in {}", target)),
+                None =>
+                    BotResponse::Ignore,
+            });
+        }
+
+        Ok(BotResponse::Privmsg(target.to_string(), text))
+    }
+}
+
+/**
+ * Serialize the effective owner configuration into TOML `key = value` lines that can be pasted into
+ * the [options] section of the configuration file, replacing the corresponding keys. Parsing the
+ * owners and owner_accounts options of the snippet again yields the same lists of owners.
+ */
+fn export_acl(params: &BotParameters<'_>) -> Result<String, BotError> {
+    let owners = params.owners.read().unwrap_or_else(|err| err.into_inner()).iter()
+        .map(|owner| owner.to_string())
+        .filter(|owner| !owner.is_empty())
+        .collect::<Vec<String>>()
+        .join(";");
+
+    let mut entries = BTreeMap::new();
+    entries.insert("owners", owners);
+    if !params.owner_accounts.is_empty() {
+        entries.insert("owner_accounts", params.owner_accounts.join(","));
+    }
+    if let Some(strict) = params.options.get("strict_owner_matching") {
+        entries.insert("strict_owner_matching", strict.clone());
+    }
+    toml::to_string(&entries)
+        .map_err(|err| BotError::UserMessage(format!("Cannot export the owner configuration: {}", err)))
+}
+
+#[async_trait::async_trait]
+impl BotCommand for AclCommand {
+    fn trigger(&self) -> &'static str {
+        "acl"
+    }
+
+    fn description(&self) -> &'static str {
+        "Export the owner configuration (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        // The owner list should not be shown to everybody in a channel
+        if let Some(botcommand) = ensure_query(self.trigger(), &params) {
             return botcommand;
         }
 
-        match params.args.get(0) {
-            Some(channel) =>
-                Ok(BotResponse::Join(channel.to_string())),
-            None =>
-                Ok(BotResponse::Ignore)
+        match params.args.first().map(String::as_str) {
+            Some("export") =>
+                Ok(BotResponse::Multi(export_acl(&params)?
+                    .lines()
+                    .map(|line| BotResponse::Privmsg(response_target.clone(), line.to_string()))
+                    .collect())),
+            _ =>
+                Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {} export", self.trigger()))),
         }
     }
 }
 
 #[async_trait::async_trait]
-impl BotCommand for IrcPartCommand {
+impl BotCommand for ErrorsCommand {
     fn trigger(&self) -> &'static str {
-        "part"
+        "errors"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the errors most recently returned by commands (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
     }
 
     async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
-        if let Some(botcommand) = ensure_owner(self.trigger(), &params) {
+        if let Some(botcommand) = ensure_query(self.trigger(), &params) {
             return botcommand;
         }
 
-        let channel = match params.args.get(0) {
-            Some(channel) => Ok(channel.as_str()),
-            None =>
-                match params.message.response_target() {
-                    Some(response_target) =>
-                        if is_public(response_target) {
-                            Ok(response_target)
-                        } else {
-                            Err(BotError::NoChannelToPart)
-                        },
-                    None =>
-                        Err(BotError::NoChannelToPart),
-                },
-        }?.to_string();
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
 
-        let comment = if params.args.len() > 1 {
-            Some(params.args[1..].join(" "))
-        } else {
-            None
+        let report = match params.args.first() {
+            Some(trigger) => {
+                let errors = params.errors.get(&trigger.to_lowercase());
+                if errors.is_empty() {
+                    format!("No errors recorded for {}", trigger)
+                } else {
+                    errors.iter()
+                        .rev()
+                        .map(|error| format!("{}s ago: {}", error.time.elapsed().as_secs(), error.message))
+                        .collect::<Vec<String>>()
+                        .join(" | ")
+                }
+            },
+            None => {
+                let counts = params.errors.counts();
+                if counts.is_empty() {
+                    "No errors recorded".to_string()
+                } else {
+                    counts.iter()
+                        .map(|(trigger, count)| format!("{}: {}", trigger, count))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                }
+            },
         };
 
-        Ok(BotResponse::Part(channel, comment))
+        Ok(BotResponse::Privmsg(response_target, report))
     }
 }
 
 #[async_trait::async_trait]
-impl BotCommand for IrcQuitCommand {
+impl BotCommand for VerboseCommand {
     fn trigger(&self) -> &'static str {
-        "quit"
+        "verbose"
+    }
+
+    fn description(&self) -> &'static str {
+        "Report command errors in a channel (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
     }
 
     async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
-        if let Some(botcommand) = ensure_owner(self.trigger(), &params) {
-            return botcommand;
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        // Applies to the given channel, or the current channel if none is given
+        let (setting, channel) = match params.args.as_slice() {
+            [setting] => (setting, response_target.as_str()),
+            [setting, channel] => (setting, channel.as_str()),
+            _ => return Ok(BotResponse::Privmsg(
+                response_target.clone(),
+                format!("Usage: {} on|off [channel]", self.trigger()))),
+        };
+
+        if !is_public(channel) {
+            return Ok(BotResponse::Privmsg(
+                response_target.clone(),
+                format!("{} is not a channel", channel)));
         }
 
-        Ok(BotResponse::Quit(
-            if params.args.len() > 0 {
-                Some(params.args.join(" "))
+        let verbose = match setting.as_str() {
+            "on" => true,
+            "off" => false,
+            _ => return Ok(BotResponse::Privmsg(
+                response_target.clone(),
+                format!("Usage: {} on|off [channel]", self.trigger()))),
+        };
+
+        params.verbose.set(channel, verbose);
+        Ok(BotResponse::Privmsg(
+            response_target.clone(),
+            format!("Verbose errors are now {} in {}", setting, channel)))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for ReloadCommand {
+    fn trigger(&self) -> &'static str {
+        "reload"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reload the list of owners from the owners_file (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        // Keep the current owners if the file cannot be read, so a typo does not lock everybody out
+        Ok(BotResponse::Privmsg(
+            response_target,
+            match load_owners(params.options) {
+                Ok(owners) => {
+                    let count = owners.iter().filter(|owner| !owner.to_string().is_empty()).count();
+                    *params.owners.write().unwrap_or_else(|err| err.into_inner()) = owners;
+                    format!("Reloaded {} owners", count)
+                },
+                Err(err) =>
+                    format!("Failed to reload owners: {}", err),
+            }))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for IgnoreCommand {
+    fn trigger(&self) -> &'static str {
+        "ignore"
+    }
+
+    fn description(&self) -> &'static str {
+        "Ignore all messages of users matching a nick!user@host pattern, or list the ignored patterns (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let mut ignored = params.ignored.write().unwrap_or_else(|err| err.into_inner());
+        Ok(BotResponse::Privmsg(
+            response_target,
+            match params.args.first() {
+                None if ignored.is_empty() =>
+                    "Nobody is ignored".to_string(),
+                None =>
+                    format!("Ignoring {}", ignored.iter().map(Prefix::to_string).collect::<Vec<String>>().join(", ")),
+                Some(pattern) => {
+                    let pattern = Prefix::new_from_str(pattern);
+                    if !ignored.contains(&pattern) {
+                        ignored.push(pattern.clone());
+                    }
+                    format!("Ignoring {}", pattern)
+                },
+            }))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for UnignoreCommand {
+    fn trigger(&self) -> &'static str {
+        "unignore"
+    }
+
+    fn description(&self) -> &'static str {
+        "Stop ignoring users matching a pattern added with ignore (owners only)"
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let pattern = match params.args.first() {
+            Some(pattern) => Prefix::new_from_str(pattern),
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {} <nick!user@host pattern>", self.trigger()))),
+        };
+
+        let mut ignored = params.ignored.write().unwrap_or_else(|err| err.into_inner());
+        let count = ignored.len();
+        ignored.retain(|candidate| *candidate != pattern);
+        Ok(BotResponse::Privmsg(
+            response_target,
+            if ignored.len() < count {
+                format!("No longer ignoring {}", pattern)
             } else {
-                None
+                format!("{} was not ignored", pattern)
             }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+    use crate::testing::Fixture;
+    use crate::util::load_owner_accounts;
+    use crate::ERRORS_PER_COMMAND;
+
+    static IN_CHANNEL: &str = ":owner!o@example.org PRIVMSG #weather :&part";
+    static IN_QUERY: &str = ":owner!o@example.org PRIVMSG metarbot :part";
+
+    fn part(fixture: &Fixture, raw: &str, args: &[&str]) -> BotCommandResult {
+        futures::executor::block_on(IrcPartCommand{}.handle(fixture.params(raw, args)))
+    }
+
+    #[test]
+    fn part_defaults_to_current_channel() {
+        let fixture = Fixture::default();
+        assert!(matches!(part(&fixture, IN_CHANNEL, &[]),
+            Ok(BotResponse::Part(ref channel, None)) if channel == "#weather"));
+        assert!(matches!(part(&fixture, IN_QUERY, &[]), Err(BotError::NoChannelToPart)));
+    }
+
+    #[test]
+    fn part_several_channels_with_message() {
+        let fixture = Fixture::default();
+        assert!(matches!(part(&fixture, IN_QUERY, &["#weather", "#aviation,#metar", "bye", "all"]),
+            Ok(BotResponse::Part(ref channels, Some(ref message)))
+                if channels == "#weather,#aviation,#metar" && message == "bye all"));
+    }
+
+    #[test]
+    fn part_all_channels() {
+        let mut fixture = Fixture::default();
+        assert!(matches!(part(&fixture, IN_QUERY, &["*"]), Err(BotError::NoChannelList)));
+
+        fixture.channels = vec!["#weather".to_string(), "#aviation".to_string()];
+        assert!(matches!(part(&fixture, IN_QUERY, &["*", "maintenance"]),
+            Ok(BotResponse::Part(ref channels, Some(ref message)))
+                if channels == "#weather,#aviation" && message == "maintenance"));
+    }
+
+    /**
+     * Parse the options of an exported ACL snippet, as the [options] section of the configuration file
+     * would be parsed.
+     */
+    fn parse_export(export: &str) -> HashMap<String, String> {
+        toml::from_str(export).unwrap()
+    }
+
+    #[test]
+    fn acl_export_round_trips() {
+        let mut fixture = Fixture::with_options(&[
+            ("owners", "alice!*@*.example.org;;bob;*!*@\"quoted\".example.net"),
+            ("owner_accounts", "alice, carol"),
+            ("strict_owner_matching", "true"),
+        ]);
+        fixture.owners = RwLock::new(load_owners(&fixture.options).unwrap());
+        fixture.owner_accounts = load_owner_accounts(&fixture.options);
+
+        let export = export_acl(&fixture.params(":owner!o@example.org PRIVMSG metarbot :acl export", &["export"])).unwrap();
+        assert!(export.lines().all(|line| regex::Regex::new(r"^\w+ = ").unwrap().is_match(line)), "{}", export);
+        assert!(export.lines().any(|line| line.starts_with("owners = ")), "{}", export);
+        let options = parse_export(&export);
+
+        let effective = |owners: Vec<Prefix>| owners.into_iter().filter(|owner| !owner.to_string().is_empty()).collect::<Vec<Prefix>>();
+        assert_eq!(effective(load_owners(&options).unwrap()), effective(fixture.owners.into_inner().unwrap()));
+        assert_eq!(load_owner_accounts(&options), fixture.owner_accounts);
+        assert_eq!(options.get("strict_owner_matching").map(String::as_str), Some("true"));
+    }
+
+    fn errors(fixture: &Fixture, args: &[&str]) -> String {
+        match futures::executor::block_on(ErrorsCommand{}.handle(fixture.params(":owner!o@example.org PRIVMSG metarbot :errors", args))) {
+            Ok(BotResponse::Privmsg(ref target, ref report)) if target == "owner" => report.clone(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn recorded_errors_are_reported_redacted() {
+        let fixture = Fixture::with_options(&[("avwx_apikey", "s3cr3t")]);
+        assert_eq!(errors(&fixture, &[]), "No errors recorded");
+        assert_eq!(errors(&fixture, &["metar"]), "No errors recorded for metar");
+
+        fixture.errors.record("metar", &BotError::UserMessage("request with key s3cr3t failed".to_string()), &fixture.options);
+        fixture.errors.record("metar", &BotError::Unconfigured("avwx_apikey"), &fixture.options);
+        fixture.errors.record("taf", &BotError::Panicked("taf"), &fixture.options);
+
+        assert_eq!(errors(&fixture, &[]), "metar: 2, taf: 1");
+        assert_eq!(errors(&fixture, &["METAR"]),
+            "0s ago: A required configuration option is missing: avwx_apikey | 0s ago: request with key <redacted> failed");
+    }
+
+    #[test]
+    fn only_the_last_errors_are_kept() {
+        let fixture = Fixture::default();
+        for i in 0..ERRORS_PER_COMMAND + 2 {
+            fixture.errors.record("metar", &BotError::UserMessage(format!("error {}", i)), &fixture.options);
+        }
+
+        let recorded = fixture.errors.get("metar");
+        assert_eq!(recorded.len(), ERRORS_PER_COMMAND);
+        assert_eq!(recorded[0].message, "error 2");
+        assert_eq!(recorded[ERRORS_PER_COMMAND - 1].message, format!("error {}", ERRORS_PER_COMMAND + 1));
+    }
+
+    fn topic(fixture: &Fixture, raw: &str, args: &[&str]) -> BotCommandResult {
+        futures::executor::block_on(IrcTopicCommand{}.handle(fixture.params(raw, args)))
+    }
+
+    #[test]
+    fn topic_of_current_channel() {
+        let fixture = Fixture::default();
+        assert!(matches!(topic(&fixture, IN_CHANNEL, &["Clear", "skies"]),
+            Ok(BotResponse::Topic(ref channel, Some(ref topic))) if channel == "#weather" && topic == "Clear skies"));
+        assert!(matches!(topic(&fixture, IN_CHANNEL, &[]),
+            Ok(BotResponse::Topic(ref channel, None)) if channel == "#weather"));
+    }
+
+    #[test]
+    fn topic_of_explicit_channel() {
+        let fixture = Fixture::default();
+        assert!(matches!(topic(&fixture, IN_CHANNEL, &["#aviation", "Fly", "safe"]),
+            Ok(BotResponse::Topic(ref channel, Some(ref topic))) if channel == "#aviation" && topic == "Fly safe"));
+        assert!(matches!(topic(&fixture, IN_QUERY, &["#aviation"]),
+            Ok(BotResponse::Topic(ref channel, None)) if channel == "#aviation"));
+        assert!(matches!(topic(&fixture, IN_QUERY, &["Fly", "safe"]), Err(BotError::NoChannelForTopic)));
+    }
+}
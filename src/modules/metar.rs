@@ -6,29 +6,46 @@
 extern crate async_trait;
 extern crate irc;
 extern crate regex;
+extern crate futures;
+extern crate serde_json;
 
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Mutex;
 use std::time;
 
+use futures::future;
+
 use crate::{
     BotCommand,
     BotCommandResult,
     BotError,
     BotParameters,
     BotResponse,
+    util::ensure_query,
+    util::max_message_length,
+    util::split_message,
 };
 
-static STATION_API_URL: &str = "https://avwx.rest/api/station/";
-static METAR_API_URL: &str = "https://avwx.rest/api/metar/";
-static TAF_API_URL: &str = "https://avwx.rest/api/taf/";
+static AVWX_BASE_URL: &str = "https://avwx.rest";
+static CHECK_KEY_AIRPORT: &str = "KJFK";
 
 lazy_static! {
     static ref AIRPORT_RE: regex::Regex = regex::Regex::new(r"^(?i)[a-z0-9]{4}$").unwrap();
+    static ref IATA_RE: regex::Regex = regex::Regex::new(r"^(?i)[a-z0-9]{3}$").unwrap();
     static ref REQWEST: reqwest::Client = reqwest::Client::new();
+    static ref REPORT_CACHE: Mutex<HashMap<(WeatherType, String), (time::Instant, String)>> = Mutex::new(HashMap::new());
 }
 
 struct MetarCommand {}
 struct TafCommand {}
+struct WxDiffCommand {}
+struct MinimumsCommand {}
+struct DistCommand {}
+struct CodeCommand {}
+struct StationCommand {}
+struct SigmetCommand {}
+struct CheckKeyCommand {}
 
 /**
  * Factory function that will create instances of all implemented commands in this module.
@@ -37,20 +54,27 @@ pub fn mk() -> Vec<Box<dyn BotCommand>> {
     vec![
         Box::new(MetarCommand{}),
         Box::new(TafCommand{}),
+        Box::new(WxDiffCommand{}),
+        Box::new(MinimumsCommand{}),
+        Box::new(DistCommand{}),
+        Box::new(CodeCommand{}),
+        Box::new(StationCommand{}),
+        Box::new(SigmetCommand{}),
+        Box::new(CheckKeyCommand{}),
     ]
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum WeatherType {
-    METAR,
-    TAF
+    Metar,
+    Taf
 }
 
 impl fmt::Display for WeatherType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            WeatherType::METAR => write!(f, "METAR"),
-            WeatherType::TAF   => write!(f, "TAF"),
+            WeatherType::Metar => write!(f, "METAR"),
+            WeatherType::Taf   => write!(f, "TAF"),
         }
     }
 }
@@ -58,8 +82,13 @@ impl fmt::Display for WeatherType {
 #[derive(Debug)]
 enum MetarError {
     NonSuccessResponse(reqwest::StatusCode),
+    RateLimited,
+    Misconfigured(reqwest::StatusCode),
+    NotFound,
     NoData(String, String),
+    NoCoordinates(String, String),
     ReqwestError(reqwest::Error),
+    SchemaError(String),
 }
 
 impl fmt::Display for MetarError {
@@ -67,11 +96,70 @@ impl fmt::Display for MetarError {
         match self {
             MetarError::NonSuccessResponse(statuscode) =>
                 write!(f, "{}", statuscode),
+            MetarError::RateLimited =>
+                write!(f, "Too many weather requests, please try again later"),
+            MetarError::Misconfigured(_) =>
+                write!(f, "The weather service is misconfigured"),
+            MetarError::NotFound =>
+                write!(f, "Airport not found"),
             MetarError::NoData(icao, name) =>
                 write!(f, "{} ({}) is not reporting weather", icao, name),
+            MetarError::NoCoordinates(icao, name) =>
+                write!(f, "The location of {} ({}) is unknown", icao, name),
             MetarError::ReqwestError(err) =>
                 write!(f, "ReqwestError: {}", err),
+            MetarError::SchemaError(field) =>
+                write!(f, "Unexpected response from avwx: missing or invalid {}", field),
+        }
+    }
+}
+
+/**
+ * Errors of the weather service are shown to the user that asked for the weather.
+ */
+impl From<MetarError> for BotError {
+    fn from(err: MetarError) -> BotError {
+        BotError::UserMessage(err.to_string())
+    }
+}
+
+impl MetarError {
+    /**
+     * Map an unsuccessful HTTP status of the avwx API to an error. Rejected API keys are logged,
+     * since only the operator of the bot can fix them, and users just see that the service is
+     * misconfigured.
+     */
+    fn from_status(statuscode: reqwest::StatusCode) -> MetarError {
+        match statuscode {
+            reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                MetarError::RateLimited,
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                warn!("avwx rejected the API key: {}", statuscode);
+                MetarError::Misconfigured(statuscode)
+            },
+            reqwest::StatusCode::NOT_FOUND =>
+                MetarError::NotFound,
+            _ =>
+                MetarError::NonSuccessResponse(statuscode),
+        }
+    }
+}
+
+/**
+ * A response of the avwx API. Responses are first parsed into a generic JSON value and checked for
+ * the fields this module needs, so a change of the upstream response format is reported as a
+ * SchemaError naming the field rather than as a confusing network error. Unknown fields are
+ * ignored.
+ */
+trait AvwxJson: serde::de::DeserializeOwned {
+    /** The fields that must be present and non-null in the response */
+    const REQUIRED_FIELDS: &'static [&'static str];
+
+    fn from_json(value: serde_json::Value) -> Result<Self, MetarError> {
+        if let Some(field) = Self::REQUIRED_FIELDS.iter().find(|field| value.get(field).map_or(true, serde_json::Value::is_null)) {
+            return Err(MetarError::SchemaError(format!("field `{}`", field)));
         }
+        serde_json::from_value(value).map_err(|err| MetarError::SchemaError(err.to_string()))
     }
 }
 
@@ -80,18 +168,388 @@ struct TafMetarJson {
     raw: String,
 }
 
+impl AvwxJson for TafMetarJson {
+    const REQUIRED_FIELDS: &'static [&'static str] = &["raw"];
+}
+
+#[derive(Deserialize)]
+struct ReportedValue {
+    repr: String,
+    value: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct CloudLayer {
+    #[serde(rename = "type")]
+    type_: String,
+    altitude: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Units {
+    altimeter: String,
+    temperature: String,
+    visibility: String,
+    wind_speed: String,
+}
+
+/**
+ * The structured fields of a METAR as decoded by avwx. Every field may be missing when the station
+ * does not report it.
+ */
+#[derive(Deserialize)]
+struct MetarJson {
+    station: String,
+    raw: Option<String>,
+    wind_direction: Option<ReportedValue>,
+    wind_speed: Option<ReportedValue>,
+    wind_gust: Option<ReportedValue>,
+    visibility: Option<ReportedValue>,
+    temperature: Option<ReportedValue>,
+    altimeter: Option<ReportedValue>,
+    #[serde(default)]
+    clouds: Vec<CloudLayer>,
+    #[serde(default)]
+    units: Units,
+}
+
+impl AvwxJson for MetarJson {
+    const REQUIRED_FIELDS: &'static [&'static str] = &["station"];
+}
+
+impl MetarJson {
+    fn wind(&self) -> Option<String> {
+        let speed = self.wind_speed.as_ref()?.value?;
+        let direction = self.wind_direction.as_ref().map_or("???", |dir| dir.repr.as_str());
+        let gust = match self.wind_gust.as_ref().and_then(|gust| gust.value) {
+            Some(gust) => format!("G{}", gust),
+            None => "".to_string(),
+        };
+        Some(format!("{}@{}{}{}", direction, speed, gust, self.units.wind_speed))
+    }
+
+    fn visibility(&self) -> Option<String> {
+        let visibility = self.visibility.as_ref()?;
+        Some(format!("{}{}", visibility.repr, self.units.visibility))
+    }
+
+    /**
+     * The height of the lowest broken or overcast layer or vertical visibility in feet, or None if
+     * there is no ceiling (e.g. CAVOK or only few or scattered clouds).
+     */
+    fn ceiling_ft(&self) -> Option<u32> {
+        self.clouds.iter()
+            .filter(|layer| ["BKN", "OVC", "VV"].contains(&layer.type_.as_str()))
+            .filter_map(|layer| layer.altitude)
+            .min()
+            .map(|altitude| altitude * 100)
+    }
+
+    fn ceiling(&self) -> Option<String> {
+        Some(self.ceiling_ft().map_or("none".to_string(), |ceiling| format!("{}ft", ceiling)))
+    }
+
+    /**
+     * The prevailing visibility converted to meters, or None if it is not reported.
+     */
+    fn visibility_m(&self) -> Option<f64> {
+        let visibility = self.visibility.as_ref()?.value?;
+        Some(match self.units.visibility.as_str() {
+            "sm" => visibility * METERS_PER_SM,
+            _ => visibility,
+        })
+    }
+
+    fn temperature(&self) -> Option<String> {
+        let temperature = self.temperature.as_ref()?.value?;
+        Some(format!("{}{}", temperature, self.units.temperature))
+    }
+
+    fn altimeter(&self) -> Option<String> {
+        let altimeter = self.altimeter.as_ref()?.value?;
+        Some(format!("{} {}", altimeter, self.units.altimeter))
+    }
+
+    /**
+     * A plain-English summary of the decoded fields, for readers who do not know the METAR format.
+     * Temperatures and wind speeds are converted to the given unit system ("metric" or "imperial"),
+     * or left as reported if None. Returns None if avwx did not decode any of the fields.
+     */
+    fn summary(&self, system: Option<&str>) -> Option<String> {
+        let speed = |value: f64| {
+            let (value, unit) = convert_speed(value, &self.units.wind_speed, system);
+            format!("{}{}", format_number(value), unit)
+        };
+        let wind = self.wind_speed.as_ref().and_then(|speed| speed.value).map(|value| {
+            let direction = match self.wind_direction.as_ref().map(|dir| dir.repr.as_str()) {
+                Some("VRB") | None => "variable".to_string(),
+                Some(direction) => format!("from {} degrees", direction),
+            };
+            match self.wind_gust.as_ref().and_then(|gust| gust.value) {
+                Some(gust) => format!("wind {} at {} gusting {}", direction, speed(value), speed(gust)),
+                None => format!("wind {} at {}", direction, speed(value)),
+            }
+        });
+
+        let temperature = self.temperature.as_ref().and_then(|temperature| temperature.value).map(|value| {
+            let (value, unit) = convert_temperature(value, &self.units.temperature, system);
+            format!("temperature {}{}", format_number(value), unit)
+        });
+
+        let fields: Vec<String> = vec![
+            wind,
+            self.visibility().map(|visibility| format!("visibility {}", visibility)),
+            self.ceiling_ft().map(|ceiling| format!("ceiling {}ft", ceiling)),
+            temperature,
+            self.altimeter().map(|altimeter| format!("altimeter {}", altimeter)),
+        ].into_iter().flatten().collect();
+
+        if fields.is_empty() {
+            None
+        } else {
+            Some(format!("{}: {}", self.station, fields.join(", ")))
+        }
+    }
+}
+
+static KMH_PER_KT: f64 = 1.852;
+static MPH_PER_KT: f64 = 1.150_779;
+
+fn celsius_to_fahrenheit(celsius: f64) -> f64 {
+    celsius * 9.0 / 5.0 + 32.0
+}
+
+fn fahrenheit_to_celsius(fahrenheit: f64) -> f64 {
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+fn knots_to_kmh(knots: f64) -> f64 {
+    knots * KMH_PER_KT
+}
+
+fn knots_to_mph(knots: f64) -> f64 {
+    knots * MPH_PER_KT
+}
+
+/**
+ * Convert a temperature in the given unit ("C" or "F") to the given unit system, returning the
+ * converted value along with its unit. Unknown units and systems are left unchanged.
+ */
+fn convert_temperature<'a>(value: f64, unit: &'a str, system: Option<&str>) -> (f64, &'a str) {
+    match (unit, system) {
+        ("C", Some("imperial")) => (celsius_to_fahrenheit(value), "F"),
+        ("F", Some("metric")) => (fahrenheit_to_celsius(value), "C"),
+        _ => (value, unit),
+    }
+}
+
+/**
+ * Convert a wind speed in knots to km/h for the metric and mph for the imperial unit system,
+ * returning the converted value along with its unit. Other units and systems are left unchanged.
+ */
+fn convert_speed<'a>(value: f64, unit: &'a str, system: Option<&str>) -> (f64, &'a str) {
+    match (unit, system) {
+        ("kt", Some("metric")) => (knots_to_kmh(value), "km/h"),
+        ("kt", Some("imperial")) => (knots_to_mph(value), "mph"),
+        _ => (value, unit),
+    }
+}
+
+/**
+ * Format a converted value with at most one decimal, dropping it for whole numbers.
+ */
+fn format_number(value: f64) -> String {
+    let rounded = (value * 10.0).round() / 10.0;
+    if rounded.fract() == 0.0 {
+        format!("{:.0}", rounded)
+    } else {
+        format!("{:.1}", rounded)
+    }
+}
+
+/**
+ * Format a side-by-side comparison of the conditions at two airports. Fields one of the stations
+ * does not report are marked as such for that side only.
+ */
+fn format_wxdiff(a: &MetarJson, b: &MetarJson) -> String {
+    let field = |name: &str, extract: fn(&MetarJson) -> Option<String>| {
+        let not_reported = || "not reported".to_string();
+        format!("{} {} / {}",
+            name,
+            extract(a).unwrap_or_else(not_reported),
+            extract(b).unwrap_or_else(not_reported))
+    };
+
+    format!("{} / {}: {}, {}, {}, {}",
+        a.station,
+        b.station,
+        field("wind", MetarJson::wind),
+        field("vis", MetarJson::visibility),
+        field("ceiling", MetarJson::ceiling),
+        field("temp", MetarJson::temperature))
+}
+
+static METERS_PER_SM: f64 = 1609.344;
+
+/**
+ * Parse a visibility such as "3sm" or "5000m" into meters. Values without a unit are taken to be
+ * statute miles.
+ */
+fn parse_visibility_m(visibility: &str) -> Option<f64> {
+    let visibility = visibility.to_lowercase();
+    if let Some(sm) = visibility.strip_suffix("sm") {
+        sm.parse::<f64>().ok().map(|sm| sm * METERS_PER_SM)
+    } else if let Some(m) = visibility.strip_suffix('m') {
+        m.parse::<f64>().ok()
+    } else {
+        visibility.parse::<f64>().ok().map(|sm| sm * METERS_PER_SM)
+    }
+}
+
+/**
+ * Decide whether the conditions in the given METAR are at or above the given ceiling and visibility
+ * minimums. Returns Ok(()) for GO, or the limiting factor as Err for NO-GO. A missing ceiling means
+ * the sky is clear enough to satisfy any ceiling minimum; a missing visibility is a NO-GO, because
+ * the minimum can't be confirmed.
+ */
+fn go_no_go(metar: &MetarJson, min_ceiling_ft: u32, min_visibility_m: f64) -> Result<(), String> {
+    if let Some(ceiling) = metar.ceiling_ft() {
+        if ceiling < min_ceiling_ft {
+            return Err(format!("ceiling {}ft below {}ft", ceiling, min_ceiling_ft));
+        }
+    }
+
+    match metar.visibility_m() {
+        None =>
+            Err("visibility not reported".to_string()),
+        Some(visibility) if visibility < min_visibility_m =>
+            Err(format!("visibility {} below minimum",
+                metar.visibility().unwrap_or_else(|| format!("{:.0}m", visibility)))),
+        Some(_) =>
+            Ok(()),
+    }
+}
+
 #[derive(Deserialize)]
 struct Station {
     name: String,
     icao: String,
     reporting: bool,
+    iata: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    city: Option<String>,
+    country: Option<String>,
+    elevation_ft: Option<f64>,
+}
+
+impl AvwxJson for Station {
+    const REQUIRED_FIELDS: &'static [&'static str] = &["name", "icao", "reporting"];
+}
+
+impl Station {
+    fn coordinates(&self) -> Result<(f64, f64), MetarError> {
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => Ok((latitude, longitude)),
+            _ => Err(MetarError::NoCoordinates(self.icao.clone(), self.name.clone())),
+        }
+    }
+
+    /**
+     * A one-line description of the station, e.g. "EDDF: Frankfurt am Main Airport, Frankfurt am
+     * Main, DE, elevation 364ft". Missing details are left out.
+     */
+    fn describe(&self) -> String {
+        let mut parts = vec![self.name.clone()];
+        parts.extend(self.city.iter().chain(self.country.iter())
+            .filter(|part| !part.is_empty())
+            .cloned());
+        if let Some(elevation_ft) = self.elevation_ft {
+            parts.push(format!("elevation {:.0}ft", elevation_ft));
+        }
+        if !self.reporting {
+            parts.push("no weather reports".to_string());
+        }
+        format!("{}: {}", self.icao, parts.join(", "))
+    }
+}
+
+#[derive(Deserialize)]
+struct AirSigmet {
+    raw: String,
+}
+
+#[derive(Deserialize)]
+struct AirSigmetJson {
+    reports: Vec<AirSigmet>,
+}
+
+impl AvwxJson for AirSigmetJson {
+    const REQUIRED_FIELDS: &'static [&'static str] = &["reports"];
+}
+
+static EARTH_RADIUS_NM: f64 = 3440.065;
+
+/**
+ * Compute the great-circle distance in nautical miles between two points given as (latitude,
+ * longitude) in degrees, using the haversine formula.
+ */
+fn distance_nm(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+}
+
+/**
+ * Compute the initial true bearing in degrees (0 to 360) of the great-circle route from one point to
+ * another, both given as (latitude, longitude) in degrees.
+ */
+fn initial_bearing(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+
+    let y = (lon2 - lon1).sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * (lon2 - lon1).cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+/**
+ * The avwx API endpoint and credentials to use, from the avwx_base_url, avwx_apikey and
+ * avwx_timeout_secs options.
+ */
+struct Avwx<'a> {
+    base_url: &'a str,
+    apikey: &'a str,
+    timeout: time::Duration,
+}
+
+impl<'a> Avwx<'a> {
+    fn from_options(options: &'a HashMap<String, String>) -> Result<Avwx<'a>, BotError> {
+        Ok(Avwx {
+            base_url: options.get("avwx_base_url").map_or(AVWX_BASE_URL, String::as_str),
+            apikey: options.get("avwx_apikey").ok_or(BotError::Unconfigured("avwx_apikey not set"))?,
+            timeout: time::Duration::from_secs(
+                options.get("avwx_timeout_secs").and_then(|secs| secs.parse().ok()).unwrap_or(5)),
+        })
+    }
+
+    /**
+     * The URL of the given avwx API for the given airport, e.g. https://avwx.rest/api/metar/EDDF
+     */
+    fn url(&self, api: &str, airport: &str) -> String {
+        format!("{}/api/{}/{}", self.base_url.trim_end_matches('/'), api, airport)
+    }
 }
 
-async fn info(airport: &str, apikey: &str) -> Result<Station, MetarError> {
-    let result = REQWEST.get(&[STATION_API_URL, airport].concat())
+async fn info(avwx: &Avwx<'_>, airport: &str) -> Result<Station, MetarError> {
+    let result = REQWEST.get(&avwx.url("station", airport))
         .header("Accept", "application/json")
-        .header("Authorization", ["Bearer", apikey].join(" "))
-        .timeout(time::Duration::from_secs(5))
+        .header("Authorization", ["Bearer", avwx.apikey].join(" "))
+        .timeout(avwx.timeout)
         .send()
         .await;
 
@@ -100,33 +558,95 @@ async fn info(airport: &str, apikey: &str) -> Result<Station, MetarError> {
             Err(MetarError::ReqwestError(err)),
         Ok(response) =>
             if !response.status().is_success() {
-                Err(MetarError::NonSuccessResponse(response.status()))
+                Err(MetarError::from_status(response.status()))
             } else {
-                match response.json::<Station>().await {
+                match response.json::<serde_json::Value>().await {
                     Err(err) =>
                         Err(MetarError::ReqwestError(err)),
                     Ok(station) =>
-                        Ok(station),
+                        Station::from_json(station),
+                }
+            },
+    }
+}
+
+/**
+ * Search the avwx station API for up to count reporting stations whose name, city or code matches
+ * the given text, best match first.
+ */
+async fn search(avwx: &Avwx<'_>, text: &str, count: usize) -> Result<Vec<Station>, MetarError> {
+    let result = REQWEST.get(&avwx.url("search", "station"))
+        .query(&[("text", text), ("n", &count.to_string()), ("reporting", "true")])
+        .header("Accept", "application/json")
+        .header("Authorization", ["Bearer", avwx.apikey].join(" "))
+        .timeout(avwx.timeout)
+        .send()
+        .await;
+
+    match result {
+        Err(err) =>
+            Err(MetarError::ReqwestError(err)),
+        Ok(response) =>
+            if !response.status().is_success() {
+                Err(MetarError::from_status(response.status()))
+            } else {
+                match response.json::<Vec<serde_json::Value>>().await {
+                    Err(err) =>
+                        Err(MetarError::ReqwestError(err)),
+                    Ok(stations) =>
+                        stations.into_iter().map(Station::from_json).collect(),
+                }
+            },
+    }
+}
+
+/**
+ * Fetch the active AIRMETs and SIGMETs that affect the given airport.
+ */
+async fn advisories(avwx: &Avwx<'_>, airport: &str) -> Result<Vec<AirSigmet>, MetarError> {
+    let result = REQWEST.get(&avwx.url("airsigmet", airport))
+        .header("Accept", "application/json")
+        .header("Authorization", ["Bearer", avwx.apikey].join(" "))
+        .timeout(avwx.timeout)
+        .send()
+        .await;
+
+    match result {
+        Err(err) =>
+            Err(MetarError::ReqwestError(err)),
+        Ok(response) =>
+            if !response.status().is_success() {
+                Err(MetarError::from_status(response.status()))
+            } else if response.status() == reqwest::StatusCode::NO_CONTENT {
+                Ok(Vec::new())
+            } else {
+                match response.json::<serde_json::Value>().await {
+                    Err(err) =>
+                        Err(MetarError::ReqwestError(err)),
+                    Ok(data) =>
+                        AirSigmetJson::from_json(data).map(|advisories| advisories.reports),
                 }
             },
     }
 }
 
-async fn weather(type_: WeatherType, airport: &str, apikey: &str) -> Result<String, MetarError> {
-    let info = info(airport, apikey).await?;
+async fn weather<T>(avwx: &Avwx<'_>, type_: WeatherType, airport: &str) -> Result<T, MetarError>
+    where T: AvwxJson
+{
+    let info = info(avwx, airport).await?;
     if !info.reporting {
         return Err(MetarError::NoData(info.icao, info.name));
     }
 
-    let url = match type_ {
-        WeatherType::METAR => METAR_API_URL,
-        WeatherType::TAF => TAF_API_URL,
+    let api = match type_ {
+        WeatherType::Metar => "metar",
+        WeatherType::Taf => "taf",
     };
 
-    let result = REQWEST.get(&[url, airport].concat())
+    let result = REQWEST.get(&avwx.url(api, airport))
         .header("Accept", "application/json")
-        .header("Authorization", ["Bearer", apikey].join(" "))
-        .timeout(time::Duration::from_secs(5))
+        .header("Authorization", ["Bearer", avwx.apikey].join(" "))
+        .timeout(avwx.timeout)
         .send()
         .await;
 
@@ -135,72 +655,1254 @@ async fn weather(type_: WeatherType, airport: &str, apikey: &str) -> Result<Stri
             Err(MetarError::ReqwestError(err)),
         Ok(response) =>
             if !response.status().is_success() {
-                Err(MetarError::NonSuccessResponse(response.status()))
+                Err(MetarError::from_status(response.status()))
             } else if response.status() == reqwest::StatusCode::NO_CONTENT {
                 Err(MetarError::NoData(info.icao, info.name))
             } else {
-                match response.json::<TafMetarJson>().await {
+                match response.json::<serde_json::Value>().await {
                     Err(err) =>
                         Err(MetarError::ReqwestError(err)),
                     Ok(data) =>
-                        Ok(data.raw),
+                        T::from_json(data),
                 }
             },
     }
 }
 
+/**
+ * Fetch the raw report of the given type for the given airport, unless it has been fetched less
+ * than ttl ago, in which case the earlier report is returned without any request to avwx. Errors
+ * are not cached.
+ */
+async fn cached_report(avwx: &Avwx<'_>, type_: WeatherType, airport: &str, ttl: time::Duration) -> Result<String, MetarError> {
+    let key = (type_, airport.to_uppercase());
+    {
+        let mut cache = REPORT_CACHE.lock().unwrap_or_else(|err| err.into_inner());
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < ttl);
+        if let Some((_, raw)) = cache.get(&key) {
+            return Ok(raw.clone());
+        }
+    }
+
+    // avwx resolves IATA codes in its station API, but not in the weather APIs
+    let icao = if IATA_RE.is_match(airport) {
+        info(avwx, airport).await?.icao
+    } else {
+        airport.to_string()
+    };
+    let raw = weather::<TafMetarJson>(avwx, type_, &icao).await?.raw;
+
+    REPORT_CACHE.lock().unwrap_or_else(|err| err.into_inner()).insert(key, (time::Instant::now(), raw.clone()));
+    Ok(raw)
+}
+
 async fn handle(type_: WeatherType, params: BotParameters<'_>) -> BotCommandResult {
     let response_target = params.message
         .response_target()
         .ok_or(BotError::NoResponseTarget)?
         .to_string();
 
-    let apikey = params.options.get("avwx_apikey")
-        .ok_or(BotError::Unconfigured("avwx_apikey not set"))?;
+    let avwx = Avwx::from_options(params.options)?;
+
+    if params.args.is_empty() {
+        return Ok(BotResponse::Privmsg(
+            response_target,
+            format!("Usage: {}{} <4-letter ICAO airport code> [...] | <city or airport name>",
+                params.leaders.first().map_or("".to_string(), char::to_string),
+                type_.to_string().to_lowercase()),
+        ));
+    }
+
+    // Arguments that are not all airport codes are the name of a city or an airport, e.g. "frankfurt"
+    let accept_iata = params.options.get("metar_accept_iata").map(String::as_str) == Some("true");
+    let airports = if params.args.iter().all(|airport| AIRPORT_RE.is_match(airport) || (accept_iata && IATA_RE.is_match(airport))) {
+        params.args.clone()
+    } else {
+        let query = params.args.join(" ");
+        let count = params.options.get("metar_search_results").and_then(|count| count.parse().ok()).unwrap_or(5);
+        match search(&avwx, &query, count).await {
+            Ok(stations) =>
+                match stations.as_slice() {
+                    [] =>
+                        return Ok(BotResponse::Privmsg(
+                            response_target,
+                            format!("No reporting airport matches {}", query))),
+                    [station] =>
+                        vec![station.icao.clone()],
+                    _ =>
+                        return Ok(BotResponse::Privmsg(
+                            response_target,
+                            format!("Several airports match {}: {}", query, stations.iter()
+                                .map(|station| format!("{} ({})", station.icao, station.name))
+                                .collect::<Vec<String>>()
+                                .join(", ")))),
+                },
+            Err(err) =>
+                return Err(err.into()),
+        }
+    };
+
+    let max_airports = params.options.get("metar_max_airports").and_then(|max| max.parse().ok()).unwrap_or(5);
+    if airports.len() > max_airports {
+        return Ok(BotResponse::Privmsg(
+            response_target,
+            format!("Please ask for at most {} airports at a time", max_airports)));
+    }
+
+    let ttl = time::Duration::from_secs(
+        params.options.get("metar_cache_secs").and_then(|secs| secs.parse().ok()).unwrap_or(300));
+    let reports = future::join_all(airports.iter()
+        .map(|airport| cached_report(&avwx, type_, airport, ttl))).await;
+
+    // Long reports, TAFs in particular, are split so the server does not truncate them
+    let mut texts = Vec::new();
+    for (airport, report) in airports.iter().zip(reports) {
+        match report {
+            Ok(raw) =>
+                texts.push(raw),
+            // With several airports, the reports of the others are still useful
+            Err(err) if airports.len() > 1 =>
+                texts.push(format!("{}: Error: {}", airport.to_uppercase(), err)),
+            Err(err) =>
+                return Err(err.into()),
+        }
+    }
+
+    let max_length = max_message_length(&response_target, params.options);
+    let mut responses: Vec<BotResponse> = texts.iter()
+        .flat_map(|text| split_message(text, max_length))
+        .map(|line| BotResponse::Privmsg(response_target.clone(), line))
+        .collect();
+
+    if responses.len() == 1 {
+        Ok(responses.remove(0))
+    } else {
+        Ok(BotResponse::Multi(responses))
+    }
+}
+
+/**
+ * Report the reporting status of a station as returned by the avwx station API, without fetching
+ * any weather.
+ */
+async fn status(params: BotParameters<'_>) -> BotCommandResult {
+    let response_target = params.message
+        .response_target()
+        .ok_or(BotError::NoResponseTarget)?
+        .to_string();
+
+    let avwx = Avwx::from_options(params.options)?;
 
-    if let Some(airport) = params.args.get(0) {
-        if AIRPORT_RE.is_match(airport) {
+    match params.args.get(1) {
+        Some(airport) if AIRPORT_RE.is_match(airport) => {
+            let station = info(&avwx, airport).await?;
             Ok(BotResponse::Privmsg(
                 response_target,
-                match weather(type_, airport, apikey).await {
-                    Ok(metar) =>
-                        metar,
-                    Err(err) =>
-                        format!("Error: {}", err),
-                }))
-        } else {
+                format!("{} ({}) is {}",
+                    station.icao,
+                    station.name,
+                    if station.reporting { "reporting" } else { "not reporting" })))
+        },
+        Some(airport) =>
             Ok(BotResponse::Privmsg(
                 response_target,
-                format!("{} does not seem to be a valid ICAO airport code", airport)))
-        }
-    } else {
-        Ok(BotResponse::Privmsg(
-            response_target,
-            format!("Usage: {}{} <4-letter ICAO airport code>",
-                params.leaders.get(0).map_or("".to_string(), char::to_string),
-                type_.to_string().to_lowercase()),
-        ))
+                format!("{} does not seem to be a valid ICAO airport code", airport))),
+        None =>
+            Ok(BotResponse::Privmsg(
+                response_target,
+                format!("Usage: {}metar status <4-letter ICAO airport code>",
+                    params.leaders.first().map_or("".to_string(), char::to_string)))),
     }
 }
 
-#[async_trait::async_trait]
-impl BotCommand for MetarCommand {
-    fn trigger(&self) -> &'static str {
-        "metar"
+/**
+ * Validate the configured avwx API key by looking up a station that is known to exist, reporting
+ * whether the key was accepted, rejected or avwx could not be reached. Only available in a query,
+ * since the answer reveals details about the configuration.
+ */
+async fn check_key(params: BotParameters<'_>) -> BotCommandResult {
+    if let Some(botcommand) = ensure_query("metar check-key", &params) {
+        return botcommand;
     }
 
-    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
-        handle(WeatherType::METAR, params).await
-    }
+    let response_target = params.message
+        .response_target()
+        .ok_or(BotError::NoResponseTarget)?
+        .to_string();
+
+    let avwx = Avwx::from_options(params.options)?;
+
+    Ok(BotResponse::Privmsg(
+        response_target,
+        match info(&avwx, CHECK_KEY_AIRPORT).await {
+            Ok(_) =>
+                "The avwx API key is valid".to_string(),
+            Err(MetarError::Misconfigured(statuscode)) =>
+                format!("The avwx API key was rejected: {}", statuscode),
+            Err(MetarError::ReqwestError(err)) =>
+                format!("Could not reach avwx: {}", err),
+            Err(err) =>
+                format!("Unexpected response from avwx: {}", err),
+        }))
 }
 
-#[async_trait::async_trait]
-impl BotCommand for TafCommand {
-    fn trigger(&self) -> &'static str {
-        "taf"
-    }
+/**
+ * Show a plain-English summary of the current METAR of an airport instead of the raw report. Falls
+ * back to the raw report if avwx did not decode it.
+ */
+async fn decode(params: BotParameters<'_>) -> BotCommandResult {
+    let response_target = params.message
+        .response_target()
+        .ok_or(BotError::NoResponseTarget)?
+        .to_string();
 
-    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
-        handle(WeatherType::TAF, params).await
+    let avwx = Avwx::from_options(params.options)?;
+
+    match params.args.first() {
+        Some(airport) if AIRPORT_RE.is_match(airport) => {
+            let text = match weather::<MetarJson>(&avwx, WeatherType::Metar, airport).await {
+                Ok(metar) =>
+                    match (metar.summary(params.options.get("avwx_units").map(String::as_str)), metar.raw) {
+                        (Some(summary), _) => summary,
+                        (None, Some(raw)) => raw,
+                        (None, None) => format!("No decoded METAR available for {}", metar.station),
+                    },
+                Err(MetarError::SchemaError(_)) =>
+                    weather::<TafMetarJson>(&avwx, WeatherType::Metar, airport).await?.raw,
+                Err(err) =>
+                    return Err(err.into()),
+            };
+            Ok(BotResponse::Privmsg(response_target, text))
+        },
+        Some(airport) =>
+            Ok(BotResponse::Privmsg(
+                response_target,
+                format!("{} does not seem to be a valid ICAO airport code", airport))),
+        None =>
+            Ok(BotResponse::Privmsg(
+                response_target,
+                format!("Usage: {}metar <4-letter ICAO airport code> decode",
+                    params.leaders.first().map_or("".to_string(), char::to_string)))),
+    }
+}
+
+/**
+ * How long the dispatcher caches the responses of the metar and taf commands. This is shorter than
+ * metar_cache_secs, since it only spares repeated lookups of the same airports in a busy channel.
+ */
+static RESPONSE_CACHE_TTL: time::Duration = time::Duration::from_secs(60);
+
+/**
+ * How many report lookups may run at the same time, so a burst of requests does not open a
+ * connection to the weather service for each of them.
+ */
+const MAX_CONCURRENT_LOOKUPS: usize = 4;
+
+/**
+ * The cache key of a request for the reports of the given airports: the response target and the
+ * airport codes. Requests that are not plain lists of ICAO codes, e.g. the subcommands or searches
+ * by name, are not cached.
+ */
+fn report_cache_key(params: &BotParameters<'_>) -> Option<String> {
+    if params.args.is_empty() || !params.args.iter().all(|airport| AIRPORT_RE.is_match(airport)) {
+        return None;
+    }
+    let response_target = params.message.response_target()?;
+    Some(format!("{} {}", response_target.to_lowercase(), params.args.join(" ").to_uppercase()))
+}
+
+#[async_trait::async_trait]
+impl BotCommand for MetarCommand {
+    fn trigger(&self) -> &'static str {
+        "metar"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the current METAR of an airport"
+    }
+
+    fn cache_key(&self, params: &BotParameters<'_>) -> Option<String> {
+        report_cache_key(params)
+    }
+
+    fn cache_ttl(&self) -> Option<time::Duration> {
+        Some(RESPONSE_CACHE_TTL)
+    }
+
+    fn max_concurrency(&self) -> Option<usize> {
+        Some(MAX_CONCURRENT_LOOKUPS)
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        match params.args.first().map(String::as_str) {
+            Some("status") => status(params).await,
+            _ if params.args.len() == 2 && params.args[1].eq_ignore_ascii_case("decode") => decode(params).await,
+            _ => handle(WeatherType::Metar, params).await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for CheckKeyCommand {
+    fn trigger(&self) -> &'static str {
+        "check-key"
+    }
+
+    fn description(&self) -> &'static str {
+        "Check whether avwx accepts the configured API key (owners only)"
+    }
+
+    fn triggers(&self) -> Vec<&'static str> {
+        vec!["metar check-key"]
+    }
+
+    fn owner_only(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        check_key(params).await
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for TafCommand {
+    fn trigger(&self) -> &'static str {
+        "taf"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the current TAF of an airport"
+    }
+
+    fn cache_key(&self, params: &BotParameters<'_>) -> Option<String> {
+        report_cache_key(params)
+    }
+
+    fn cache_ttl(&self) -> Option<time::Duration> {
+        Some(RESPONSE_CACHE_TTL)
+    }
+
+    fn max_concurrency(&self) -> Option<usize> {
+        Some(MAX_CONCURRENT_LOOKUPS)
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        handle(WeatherType::Taf, params).await
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for WxDiffCommand {
+    fn trigger(&self) -> &'static str {
+        "wxdiff"
+    }
+
+    fn description(&self) -> &'static str {
+        "Compare the current weather of two airports"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let avwx = Avwx::from_options(params.options)?;
+
+        let (a, b) = match params.args.as_slice() {
+            [a, b] => (a, b),
+            _ =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <ICAO airport code> <ICAO airport code>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        for airport in &[a, b] {
+            if !AIRPORT_RE.is_match(airport) {
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{} does not seem to be a valid ICAO airport code", airport)));
+            }
+        }
+
+        let (metar_a, metar_b) = futures::join!(
+            weather::<MetarJson>(&avwx, WeatherType::Metar, a),
+            weather::<MetarJson>(&avwx, WeatherType::Metar, b));
+
+        Ok(BotResponse::Privmsg(response_target, format_wxdiff(&metar_a?, &metar_b?)))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for MinimumsCommand {
+    fn trigger(&self) -> &'static str {
+        "minimums"
+    }
+
+    fn description(&self) -> &'static str {
+        "Check the current weather of an airport against ceiling and visibility minimums"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let avwx = Avwx::from_options(params.options)?;
+
+        let default_ceiling = params.options.get("minimums_ceiling_ft").map_or("1000", String::as_str);
+        let default_visibility = params.options.get("minimums_visibility").map_or("3sm", String::as_str);
+
+        let (airport, ceiling, visibility) = match params.args.as_slice() {
+            [airport] => (airport, default_ceiling, default_visibility),
+            [airport, ceiling, visibility] => (airport, ceiling.as_str(), visibility.as_str()),
+            _ =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <ICAO airport code> [<ceiling in ft> <visibility, e.g. 3sm or 5000m>]",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        if !AIRPORT_RE.is_match(airport) {
+            return Ok(BotResponse::Privmsg(
+                response_target,
+                format!("{} does not seem to be a valid ICAO airport code", airport)));
+        }
+
+        let (min_ceiling_ft, min_visibility_m) = match (ceiling.parse::<u32>(), parse_visibility_m(visibility)) {
+            (Ok(ceiling), Some(visibility)) => (ceiling, visibility),
+            _ =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Invalid minimums: ceiling {}, visibility {}", ceiling, visibility))),
+        };
+
+        let metar = weather::<MetarJson>(&avwx, WeatherType::Metar, airport).await?;
+        Ok(BotResponse::Privmsg(
+            response_target,
+            match go_no_go(&metar, min_ceiling_ft, min_visibility_m) {
+                Ok(()) => format!("{}: GO", metar.station),
+                Err(limit) => format!("{}: NO-GO, {}", metar.station, limit),
+            }))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for DistCommand {
+    fn trigger(&self) -> &'static str {
+        "dist"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the great circle distance and initial bearing between two airports"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let avwx = Avwx::from_options(params.options)?;
+
+        let (a, b) = match params.args.as_slice() {
+            [a, b] => (a, b),
+            _ =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <ICAO airport code> <ICAO airport code>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        for airport in &[a, b] {
+            if !AIRPORT_RE.is_match(airport) {
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{} does not seem to be a valid ICAO airport code", airport)));
+            }
+        }
+
+        let route = async {
+            let (from, to) = futures::join!(info(&avwx, a), info(&avwx, b));
+            let (from, to) = (from?, to?);
+            let (from_coordinates, to_coordinates) = (from.coordinates()?, to.coordinates()?);
+            Ok::<String, MetarError>(format!("{} to {}: {:.0}NM, initial true course {:03.0}°",
+                from.icao,
+                to.icao,
+                distance_nm(from_coordinates, to_coordinates),
+                initial_bearing(from_coordinates, to_coordinates)))
+        };
+
+        Ok(BotResponse::Privmsg(response_target, route.await?))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for CodeCommand {
+    fn trigger(&self) -> &'static str {
+        "code"
+    }
+
+    fn description(&self) -> &'static str {
+        "Convert between IATA and ICAO airport codes"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let avwx = Avwx::from_options(params.options)?;
+
+        let code = match params.args.first() {
+            Some(code) if AIRPORT_RE.is_match(code) || IATA_RE.is_match(code) =>
+                code.to_uppercase(),
+            Some(code) =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{} is neither a 3-letter IATA nor a 4-letter ICAO airport code", code))),
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <IATA or ICAO airport code>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        let text = match info(&avwx, &code).await {
+            Ok(station) =>
+                match station.iata.as_deref().filter(|iata| !iata.is_empty()) {
+                    Some(iata) =>
+                        format!("{}: IATA {}, ICAO {}", station.name, iata, station.icao),
+                    None =>
+                        format!("{}: ICAO {}, no IATA code", station.name, station.icao),
+                },
+            Err(MetarError::NotFound) | Err(MetarError::NonSuccessResponse(_)) =>
+                format!("{} is not a known airport code", code),
+            Err(err) =>
+                return Err(err.into()),
+        };
+        Ok(BotResponse::Privmsg(response_target, text))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for StationCommand {
+    fn trigger(&self) -> &'static str {
+        "station"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the name, city, country and elevation of an airport"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let avwx = Avwx::from_options(params.options)?;
+
+        let airport = match params.args.first() {
+            Some(airport) if AIRPORT_RE.is_match(airport) =>
+                airport.to_uppercase(),
+            Some(airport) =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{} does not seem to be a valid ICAO airport code", airport))),
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <ICAO airport code>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        let text = match info(&avwx, &airport).await {
+            Ok(station) =>
+                station.describe(),
+            Err(MetarError::NotFound) | Err(MetarError::NonSuccessResponse(_)) =>
+                format!("{} is not a known airport code", airport),
+            Err(err) =>
+                return Err(err.into()),
+        };
+        Ok(BotResponse::Privmsg(response_target, text))
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for SigmetCommand {
+    fn trigger(&self) -> &'static str {
+        "sigmet"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the active AIRMETs and SIGMETs that affect an airport"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let avwx = Avwx::from_options(params.options)?;
+
+        let airport = match params.args.first() {
+            Some(airport) if AIRPORT_RE.is_match(airport) =>
+                airport.to_uppercase(),
+            Some(airport) =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{} does not seem to be a valid ICAO airport code", airport))),
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <ICAO airport code>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        let reports = advisories(&avwx, &airport).await?;
+        if reports.is_empty() {
+            return Ok(BotResponse::Privmsg(
+                response_target,
+                format!("No active advisories for {}", airport)));
+        }
+
+        // Advisories are long, so only the first few are shown, each on as many lines as it needs
+        let max_reports = params.options.get("sigmet_max_reports").and_then(|max| max.parse().ok()).unwrap_or(3);
+        let max_length = max_message_length(&response_target, params.options);
+        let mut lines = vec![format!("{} active advisories for {}{}",
+            reports.len(),
+            airport,
+            if reports.len() > max_reports { format!(", showing the first {}", max_reports) } else { "".to_string() })];
+        lines.extend(reports.iter()
+            .take(max_reports)
+            .flat_map(|report| split_message(&report.raw.split_whitespace().collect::<Vec<&str>>().join(" "), max_length)));
+
+        Ok(BotResponse::Multi(lines.into_iter()
+            .map(|line| BotResponse::Privmsg(response_target.clone(), line))
+            .collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, http_response, http_server};
+
+    fn metar(json: serde_json::Value) -> MetarJson {
+        MetarJson::from_json(json).unwrap()
+    }
+
+    fn kjfk() -> MetarJson {
+        metar(serde_json::json!({
+            "station": "KJFK",
+            "raw": "KJFK 121851Z 31012G20KT 10SM BKN050 OVC250 22/08 A3002",
+            "wind_direction": {"repr": "310", "value": 310},
+            "wind_speed": {"repr": "12", "value": 12},
+            "wind_gust": {"repr": "20", "value": 20},
+            "visibility": {"repr": "10", "value": 10},
+            "temperature": {"repr": "22", "value": 22},
+            "altimeter": {"repr": "3002", "value": 30.02},
+            "clouds": [{"type": "BKN", "altitude": 50}, {"type": "OVC", "altitude": 250}],
+            "units": {"altimeter": "inHg", "temperature": "C", "visibility": "sm", "wind_speed": "kt"},
+        }))
+    }
+
+    fn eddf() -> MetarJson {
+        metar(serde_json::json!({
+            "station": "EDDF",
+            "raw": "EDDF 121850Z VRB03KT 9999 FEW040 18/11 Q1015",
+            "wind_direction": {"repr": "VRB", "value": null},
+            "wind_speed": {"repr": "03", "value": 3},
+            "visibility": {"repr": "9999", "value": 9999},
+            "temperature": {"repr": "18", "value": 18},
+            "altimeter": {"repr": "1015", "value": 1015},
+            "clouds": [{"type": "FEW", "altitude": 40}],
+            "units": {"altimeter": "hPa", "temperature": "C", "visibility": "m", "wind_speed": "kt"},
+        }))
+    }
+
+    #[test]
+    fn wxdiff_compares_both_stations() {
+        assert_eq!(format_wxdiff(&kjfk(), &eddf()),
+            "KJFK / EDDF: wind 310@12G20kt / VRB@3kt, vis 10sm / 9999m, ceiling 5000ft / none, temp 22C / 18C");
+    }
+
+    #[test]
+    fn summary_describes_the_decoded_fields() {
+        assert_eq!(kjfk().summary(None).as_deref(), Some(
+            "KJFK: wind from 310 degrees at 12kt gusting 20kt, visibility 10sm, ceiling 5000ft, temperature 22C, altimeter 30.02 inHg"));
+        assert_eq!(eddf().summary(None).as_deref(), Some(
+            "EDDF: wind variable at 3kt, visibility 9999m, temperature 18C, altimeter 1015 hPa"));
+    }
+
+    #[test]
+    fn summary_converts_units() {
+        assert_eq!(kjfk().summary(Some("metric")).as_deref(), Some(
+            "KJFK: wind from 310 degrees at 22.2km/h gusting 37km/h, visibility 10sm, ceiling 5000ft, temperature 22C, altimeter 30.02 inHg"));
+        assert_eq!(eddf().summary(Some("imperial")).as_deref(), Some(
+            "EDDF: wind variable at 3.5mph, visibility 9999m, temperature 64.4F, altimeter 1015 hPa"));
+    }
+
+    #[test]
+    fn summary_of_undecoded_report_is_none() {
+        let undecoded = metar(serde_json::json!({
+            "station": "KXYZ",
+            "raw": "KXYZ 121851Z AUTO",
+            "units": {"altimeter": "inHg", "temperature": "C", "visibility": "sm", "wind_speed": "kt"},
+        }));
+        assert_eq!(undecoded.summary(None), None);
+    }
+
+    #[test]
+    fn wxdiff_marks_fields_missing_on_one_side() {
+        let partial = metar(serde_json::json!({
+            "station": "KXYZ",
+            "temperature": {"repr": "M02", "value": -2},
+            "units": {"temperature": "C"},
+        }));
+        assert_eq!(format_wxdiff(&kjfk(), &partial),
+            "KJFK / KXYZ: wind 310@12G20kt / not reported, vis 10sm / not reported, ceiling 5000ft / none, temp 22C / -2C");
+    }
+
+    #[test]
+    fn cache_key_covers_airports_and_target() {
+        let fixture = Fixture::default();
+        let key = |raw, args: &[&str]| MetarCommand{}.cache_key(&fixture.params(raw, args));
+
+        let in_weather = key(":alice!a@example.org PRIVMSG #weather :&metar eddf", &["eddf"]);
+        assert_eq!(in_weather, Some("#weather EDDF".to_string()));
+        assert_eq!(key(":bob!b@example.org PRIVMSG #Weather :&metar EDDF", &["EDDF"]), in_weather);
+        assert_ne!(key(":alice!a@example.org PRIVMSG #aviation :&metar EDDF", &["EDDF"]), in_weather);
+        assert_ne!(key(":alice!a@example.org PRIVMSG metarbot :metar EDDF", &["EDDF"]), in_weather);
+        assert_ne!(key(":alice!a@example.org PRIVMSG #weather :&metar EDDF KJFK", &["EDDF", "KJFK"]), in_weather);
+    }
+
+    #[test]
+    fn subcommands_and_searches_are_not_cached() {
+        let fixture = Fixture::default();
+        let key = |args: &[&str]| MetarCommand{}.cache_key(&fixture.params(":alice!a@example.org PRIVMSG #weather :&metar", args));
+
+        assert_eq!(key(&[]), None);
+        assert_eq!(key(&["check-key"]), None);
+        assert_eq!(key(&["status", "EDDF"]), None);
+        assert_eq!(key(&["EDDF", "decode"]), None);
+        assert_eq!(key(&["frankfurt"]), None);
+    }
+
+    #[test]
+    fn go_no_go_against_minimums() {
+        let vfr = parse_visibility_m("3sm").unwrap();
+        assert_eq!(go_no_go(&kjfk(), 1000, vfr), Ok(()));
+        assert_eq!(go_no_go(&eddf(), 1000, vfr), Ok(()));
+        assert_eq!(go_no_go(&kjfk(), 6000, vfr), Err("ceiling 5000ft below 6000ft".to_string()));
+        assert_eq!(go_no_go(&kjfk(), 5000, vfr), Ok(()));
+        assert_eq!(go_no_go(&eddf(), 1000, parse_visibility_m("10000m").unwrap()),
+            Err("visibility 9999m below minimum".to_string()));
+    }
+
+    #[test]
+    fn go_no_go_with_low_clouds_and_missing_visibility() {
+        let fog = metar(serde_json::json!({
+            "station": "KSFO",
+            "visibility": {"repr": "1/4", "value": 0.25},
+            "clouds": [{"type": "VV", "altitude": 2}],
+            "units": {"visibility": "sm"},
+        }));
+        assert_eq!(go_no_go(&fog, 200, 100.0), Ok(()));
+        assert_eq!(go_no_go(&fog, 1000, 100.0), Err("ceiling 200ft below 1000ft".to_string()));
+        assert_eq!(go_no_go(&fog, 200, parse_visibility_m("1sm").unwrap()),
+            Err("visibility 1/4sm below minimum".to_string()));
+
+        let unknown = metar(serde_json::json!({"station": "KXYZ"}));
+        assert_eq!(go_no_go(&unknown, 1000, 5000.0), Err("visibility not reported".to_string()));
+    }
+
+    #[test]
+    fn visibility_minimums_are_parsed() {
+        assert_eq!(parse_visibility_m("5000m"), Some(5000.0));
+        assert_eq!(parse_visibility_m("1SM"), Some(METERS_PER_SM));
+        assert_eq!(parse_visibility_m("2"), Some(2.0 * METERS_PER_SM));
+        assert_eq!(parse_visibility_m("far"), None);
+    }
+
+    #[test]
+    fn report_lookups_are_limited() {
+        assert_eq!(MetarCommand{}.max_concurrency(), Some(MAX_CONCURRENT_LOOKUPS));
+        assert_eq!(TafCommand{}.max_concurrency(), Some(MAX_CONCURRENT_LOOKUPS));
+    }
+
+    static KSFO: (f64, f64) = (37.619, -122.375);
+    static KJFK: (f64, f64) = (40.640, -73.779);
+    static EDDF: (f64, f64) = (50.033, 8.570);
+    static EGLL: (f64, f64) = (51.470, -0.454);
+
+    #[test]
+    fn distance_between_known_airports() {
+        assert!((distance_nm(KSFO, KJFK) - 2242.0).abs() < 1.0);
+        assert!((distance_nm(EDDF, EGLL) - 353.0).abs() < 1.0);
+        assert_eq!(distance_nm(EDDF, EGLL), distance_nm(EGLL, EDDF));
+        assert_eq!(distance_nm(EDDF, EDDF), 0.0);
+    }
+
+    #[test]
+    fn bearing_between_known_airports() {
+        assert!((initial_bearing(KSFO, KJFK) - 69.8).abs() < 0.1);
+        assert!((initial_bearing(EDDF, EGLL) - 287.6).abs() < 0.1);
+        assert!((initial_bearing(EGLL, EDDF) - 100.6).abs() < 0.1);
+        assert!((initial_bearing((0.0, 0.0), (10.0, 0.0)) - 0.0).abs() < 1e-9);
+        assert!((initial_bearing((0.0, 0.0), (-10.0, 0.0)) - 180.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn station_without_coordinates_is_an_error() {
+        let station = Station::from_json(serde_json::json!({
+            "name": "Somewhere Field",
+            "icao": "XXXX",
+            "reporting": false,
+        })).unwrap();
+        assert!(matches!(station.coordinates(), Err(MetarError::NoCoordinates(ref icao, _)) if icao == "XXXX"));
+
+        let station = Station::from_json(serde_json::json!({
+            "name": "Frankfurt am Main Airport",
+            "icao": "EDDF",
+            "reporting": true,
+            "latitude": 50.033,
+            "longitude": 8.570,
+        })).unwrap();
+        assert_eq!(station.coordinates().unwrap(), EDDF);
+    }
+
+    /**
+     * Invoke the given command with the given arguments in #weather against a mocked avwx API that
+     * sends the given responses. Returns the text of the response and the requests the API received.
+     */
+    async fn invoke(command: &dyn BotCommand, args: &[&str], responses: Vec<String>) -> (String, Vec<String>) {
+        let (url, server) = http_server(responses).await;
+        let fixture = Fixture::with_options(&[("avwx_base_url", &url), ("avwx_apikey", "s3cr3t")]);
+        let raw = format!(":alice!a@example.org PRIVMSG #weather :&{} {}", command.trigger(), args.join(" "));
+        let text = match command.handle(fixture.params(&raw, args)).await {
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "#weather" => text.clone(),
+            result => panic!("unexpected result {:?}", result),
+        };
+        (text, server.await.unwrap())
+    }
+
+    async fn status_of(responses: Vec<String>) -> (String, Vec<String>) {
+        invoke(&MetarCommand{}, &["status", "EDDF"], responses).await
+    }
+
+    #[tokio::test]
+    async fn status_of_reporting_station() {
+        let (text, requests) = status_of(vec![http_response(200, "application/json",
+            r#"{"name": "Frankfurt am Main Airport", "icao": "EDDF", "reporting": true}"#)]).await;
+        assert_eq!(text, "EDDF (Frankfurt am Main Airport) is reporting");
+        assert!(requests[0].starts_with("GET /api/station/EDDF "), "{}", requests[0]);
+        assert!(requests[0].to_lowercase().contains("authorization: bearer s3cr3t"), "{}", requests[0]);
+    }
+
+    #[tokio::test]
+    async fn decode_summarizes_the_report() {
+        let (text, requests) = invoke(&MetarCommand{}, &["EDDF", "decode"], vec![
+            http_response(200, "application/json", r#"{"name": "Frankfurt am Main Airport", "icao": "EDDF", "reporting": true}"#),
+            http_response(200, "application/json", &serde_json::json!({
+                "station": "EDDF",
+                "raw": "EDDF 121850Z VRB03KT 9999 FEW040 18/11 Q1015",
+                "wind_direction": {"repr": "VRB", "value": null},
+                "wind_speed": {"repr": "03", "value": 3},
+                "units": {"altimeter": "hPa", "temperature": "C", "visibility": "m", "wind_speed": "kt"},
+            }).to_string()),
+        ]).await;
+        assert_eq!(text, "EDDF: wind variable at 3kt");
+        assert!(requests[1].starts_with("GET /api/metar/EDDF"), "{}", requests[1]);
+    }
+
+    #[tokio::test]
+    async fn decode_falls_back_to_raw_report_on_schema_error() {
+        let station = r#"{"name": "Frankfurt am Main Airport", "icao": "EDDF", "reporting": true}"#;
+        let report = r#"{"raw": "EDDF 121850Z VRB03KT 9999 FEW040 18/11 Q1015"}"#;
+        let (text, requests) = invoke(&MetarCommand{}, &["EDDF", "decode"], vec![
+            http_response(200, "application/json", station),
+            http_response(200, "application/json", report),
+            http_response(200, "application/json", station),
+            http_response(200, "application/json", report),
+        ]).await;
+        assert_eq!(text, "EDDF 121850Z VRB03KT 9999 FEW040 18/11 Q1015");
+        assert_eq!(requests.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn search_without_match() {
+        let (text, requests) = invoke(&MetarCommand{}, &["atlantis"], vec![http_response(200, "application/json", "[]")]).await;
+        assert_eq!(text, "No reporting airport matches atlantis");
+        assert!(requests[0].starts_with("GET /api/search/station?text=atlantis&n=5&reporting=true "), "{}", requests[0]);
+    }
+
+    #[tokio::test]
+    async fn search_with_single_match_fetches_the_airport() {
+        // The report cache is shared by all tests, so each test uses its own airport
+        let mut responses = vec![http_response(200, "application/json",
+            r#"[{"name": "Hannover Airport", "icao": "EDDV", "reporting": true}]"#)];
+        responses.extend(metar_lookup("EDDV"));
+        let (text, requests) = invoke(&MetarCommand{}, &["hannover"], responses).await;
+        assert_eq!(text, "EDDV 121850Z 27010KT 9999 FEW040 18/11 Q1015");
+        assert!(requests[0].starts_with("GET /api/search/station?text=hannover&"), "{}", requests[0]);
+        assert!(requests[2].starts_with("GET /api/metar/EDDV"), "{}", requests[2]);
+    }
+
+    #[tokio::test]
+    async fn search_with_several_matches_lists_the_candidates() {
+        let (text, requests) = invoke(&MetarCommand{}, &["new", "york"], vec![http_response(200, "application/json",
+            r#"[{"name": "John F Kennedy International Airport", "icao": "KJFK", "reporting": true},
+                {"name": "La Guardia Airport", "icao": "KLGA", "reporting": true}]"#)]).await;
+        assert_eq!(text, "Several airports match new york: KJFK (John F Kennedy International Airport), KLGA (La Guardia Airport)");
+        assert_eq!(requests.len(), 1);
+    }
+
+    /**
+     * Invoke the sigmet command for EDDF in #weather with the given options against a mocked avwx API
+     * that sends the given response. Returns the lines of the response and the request the API received.
+     */
+    async fn sigmet(options: &[(&str, &str)], response: String) -> (Vec<String>, String) {
+        let (url, server) = http_server(vec![response]).await;
+        let mut options = options.to_vec();
+        options.extend(&[("avwx_base_url", url.as_str()), ("avwx_apikey", "s3cr3t")]);
+        let fixture = Fixture::with_options(&options);
+        let result = SigmetCommand{}.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&sigmet EDDF", &["EDDF"])).await;
+        let lines = match result {
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "#weather" => vec![text.clone()],
+            Ok(BotResponse::Multi(responses)) => responses.into_iter()
+                .map(|response| match response {
+                    BotResponse::Privmsg(ref target, ref text) if target == "#weather" => text.clone(),
+                    response => panic!("unexpected response {:?}", response),
+                })
+                .collect(),
+            result => panic!("unexpected result {:?}", result),
+        };
+        (lines, server.await.unwrap().remove(0))
+    }
+
+    /**
+     * An airsigmet response with the given raw reports.
+     */
+    fn advisory_response(reports: &[&str]) -> String {
+        let reports: Vec<serde_json::Value> = reports.iter().map(|raw| serde_json::json!({"raw": raw})).collect();
+        http_response(200, "application/json", &serde_json::json!({"reports": reports}).to_string())
+    }
+
+    #[tokio::test]
+    async fn sigmet_without_advisories() {
+        let (lines, request) = sigmet(&[], http_response(204, "application/json", "")).await;
+        assert_eq!(lines, vec!["No active advisories for EDDF"]);
+        assert!(request.starts_with("GET /api/airsigmet/EDDF "), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn sigmet_shows_the_first_reports() {
+        let (lines, _) = sigmet(&[], advisory_response(&["SIGMET A1", "SIGMET A2", "AIRMET B1", "AIRMET B2"])).await;
+        assert_eq!(lines, vec![
+            "4 active advisories for EDDF, showing the first 3",
+            "SIGMET A1",
+            "SIGMET A2",
+            "AIRMET B1",
+        ]);
+
+        let (lines, _) = sigmet(&[("sigmet_max_reports", "1")], advisory_response(&["SIGMET A1", "SIGMET A2"])).await;
+        assert_eq!(lines, vec!["2 active advisories for EDDF, showing the first 1", "SIGMET A1"]);
+    }
+
+    #[tokio::test]
+    async fn sigmet_splits_long_reports() {
+        let (lines, _) = sigmet(&[("max_line_length", "20")],
+            advisory_response(&["EDGG SIGMET 2 VALID\n121800/122200 EDZF-\nEDGG LANGEN FIR SEV TURB"])).await;
+        assert_eq!(lines, vec![
+            "1 active advisories for EDDF",
+            "EDGG SIGMET 2 VALID",
+            "121800/122200 EDZF-",
+            "EDGG LANGEN FIR SEV",
+            "TURB",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn status_of_station_that_is_not_reporting() {
+        let (text, requests) = status_of(vec![http_response(200, "application/json",
+            r#"{"name": "Egelsbach Airport", "icao": "EDFE", "reporting": false}"#)]).await;
+        assert_eq!(text, "EDFE (Egelsbach Airport) is not reporting");
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn http_statuses_are_explained_to_the_user() {
+        let text = |status: u16| MetarError::from_status(reqwest::StatusCode::from_u16(status).unwrap()).to_string();
+        assert_eq!(text(429), "Too many weather requests, please try again later");
+        assert_eq!(text(401), "The weather service is misconfigured");
+        assert_eq!(text(403), "The weather service is misconfigured");
+        assert_eq!(text(404), "Airport not found");
+        assert_eq!(text(500), "500 Internal Server Error");
+    }
+
+    #[test]
+    fn missing_field_is_a_schema_error() {
+        let result = TafMetarJson::from_json(serde_json::json!({"station": "EDDF", "sanitized": "EDDF 121850Z"}));
+        assert!(matches!(result, Err(MetarError::SchemaError(ref field)) if field == "field `raw`"));
+
+        let result = TafMetarJson::from_json(serde_json::json!({"raw": null}));
+        assert!(matches!(result, Err(MetarError::SchemaError(ref field)) if field == "field `raw`"));
+
+        let result = TafMetarJson::from_json(serde_json::json!({"raw": 42}));
+        assert!(matches!(result, Err(MetarError::SchemaError(_))));
+
+        let result = Station::from_json(serde_json::json!({"name": "Frankfurt am Main Airport", "icao": "EDDF"}));
+        assert!(matches!(result, Err(MetarError::SchemaError(ref field)) if field == "field `reporting`"));
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let report = TafMetarJson::from_json(serde_json::json!({
+            "raw": "EDDF 121850Z VRB03KT 9999 FEW040 18/11 Q1015",
+            "station": "EDDF",
+            "some_future_field": {"nested": [1, 2, 3]},
+        })).unwrap();
+        assert_eq!(report.raw, "EDDF 121850Z VRB03KT 9999 FEW040 18/11 Q1015");
+    }
+
+    static KSFO_STATION: &str = r#"{"name": "San Francisco International Airport", "icao": "KSFO", "iata": "SFO", "reporting": true}"#;
+
+    #[tokio::test]
+    async fn code_maps_iata_to_icao() {
+        let (text, requests) = invoke(&CodeCommand{}, &["sfo"], vec![http_response(200, "application/json", KSFO_STATION)]).await;
+        assert_eq!(text, "San Francisco International Airport: IATA SFO, ICAO KSFO");
+        assert!(requests[0].starts_with("GET /api/station/SFO "), "{}", requests[0]);
+    }
+
+    #[tokio::test]
+    async fn code_maps_icao_to_iata() {
+        let (text, requests) = invoke(&CodeCommand{}, &["KSFO"], vec![http_response(200, "application/json", KSFO_STATION)]).await;
+        assert_eq!(text, "San Francisco International Airport: IATA SFO, ICAO KSFO");
+        assert!(requests[0].starts_with("GET /api/station/KSFO "), "{}", requests[0]);
+
+        let (text, _) = invoke(&CodeCommand{}, &["EDFE"], vec![http_response(200, "application/json",
+            r#"{"name": "Egelsbach Airport", "icao": "EDFE", "iata": "", "reporting": false}"#)]).await;
+        assert_eq!(text, "Egelsbach Airport: ICAO EDFE, no IATA code");
+    }
+
+    #[tokio::test]
+    async fn code_reports_unknown_codes() {
+        let (text, _) = invoke(&CodeCommand{}, &["XYZ"], vec![http_response(400, "application/json",
+            r#"{"error": "XYZ is not a valid ICAO, IATA, or GPS code"}"#)]).await;
+        assert_eq!(text, "XYZ is not a known airport code");
+
+        let (text, requests) = invoke(&CodeCommand{}, &["S-F"], vec![]).await;
+        assert_eq!(text, "S-F is neither a 3-letter IATA nor a 4-letter ICAO airport code");
+        assert!(requests.is_empty());
+    }
+
+    async fn check_key_against(url: &str) -> String {
+        let fixture = Fixture::with_options(&[("avwx_base_url", url), ("avwx_apikey", "s3cr3t")]);
+        let result = CheckKeyCommand{}.handle(fixture.params(":owner!o@example.org PRIVMSG metarbot :metar check-key", &[])).await;
+        match result {
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "owner" => text.clone(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn check_key_is_an_owner_only_phrase() {
+        assert!(CheckKeyCommand{}.owner_only());
+        assert_eq!(CheckKeyCommand{}.triggers(), vec!["metar check-key"]);
+    }
+
+    #[tokio::test]
+    async fn check_key_accepts_valid_key() {
+        let (url, server) = http_server(vec![http_response(200, "application/json",
+            r#"{"name": "John F Kennedy International Airport", "icao": "KJFK", "reporting": true}"#)]).await;
+        assert_eq!(check_key_against(&url).await, "The avwx API key is valid");
+        assert!(server.await.unwrap()[0].starts_with("GET /api/station/KJFK "));
+    }
+
+    #[tokio::test]
+    async fn check_key_reports_rejected_key() {
+        let (url, _) = http_server(vec![http_response(401, "application/json", r#"{"error": "Token is invalid"}"#)]).await;
+        assert_eq!(check_key_against(&url).await, "The avwx API key was rejected: 401 Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn check_key_reports_network_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(check_key_against(&url).await.starts_with("Could not reach avwx: "));
+    }
+
+    /**
+     * The responses of avwx to a METAR lookup of the given airport: its station, then its report.
+     */
+    fn metar_lookup(icao: &str) -> Vec<String> {
+        vec![
+            http_response(200, "application/json",
+                &format!(r#"{{"name": "Test Airport", "icao": "{}", "reporting": true}}"#, icao)),
+            http_response(200, "application/json",
+                &format!(r#"{{"raw": "{} 121850Z 27010KT 9999 FEW040 18/11 Q1015"}}"#, icao)),
+        ]
+    }
+
+    #[tokio::test]
+    async fn cached_report_is_served_without_request() {
+        // The report cache is shared by all tests, so each test uses its own airport
+        let (url, server) = http_server(metar_lookup("EDDT")).await;
+        let fixture = Fixture::with_options(&[("avwx_base_url", &url), ("avwx_apikey", "s3cr3t")]);
+        let avwx = Avwx::from_options(&fixture.options).unwrap();
+        let ttl = time::Duration::from_secs(300);
+
+        let first = cached_report(&avwx, WeatherType::Metar, "EDDT", ttl).await.unwrap();
+        let second = cached_report(&avwx, WeatherType::Metar, "eddt", ttl).await.unwrap();
+        assert_eq!(first, "EDDT 121850Z 27010KT 9999 FEW040 18/11 Q1015");
+        assert_eq!(second, first);
+        assert_eq!(server.await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_report_is_fetched_again() {
+        let mut responses = metar_lookup("EDDM");
+        responses.extend(metar_lookup("EDDM"));
+        let (url, server) = http_server(responses).await;
+        let fixture = Fixture::with_options(&[("avwx_base_url", &url), ("avwx_apikey", "s3cr3t")]);
+        let avwx = Avwx::from_options(&fixture.options).unwrap();
+
+        cached_report(&avwx, WeatherType::Metar, "EDDM", time::Duration::from_secs(0)).await.unwrap();
+        cached_report(&avwx, WeatherType::Metar, "EDDM", time::Duration::from_secs(0)).await.unwrap();
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[3].starts_with("GET /api/metar/EDDM "), "{}", requests[3]);
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_are_user_messages() {
+        let (url, _) = http_server(vec![http_response(404, "application/json", r#"{"error": "Station not found"}"#)]).await;
+        let fixture = Fixture::with_options(&[("avwx_base_url", &url), ("avwx_apikey", "s3cr3t")]);
+        let result = MetarCommand{}.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&metar ZZZZ", &["ZZZZ"])).await;
+        assert!(matches!(result, Err(BotError::UserMessage(ref text)) if text == "Airport not found"), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn errors_of_several_airports_are_reported_inline() {
+        let mut responses = vec![http_response(404, "application/json", r#"{"error": "Station not found"}"#)];
+        responses.extend(metar_lookup("EDDK"));
+        let (url, _) = http_server(responses).await;
+        let fixture = Fixture::with_options(&[("avwx_base_url", &url), ("avwx_apikey", "s3cr3t")]);
+
+        // The airports are looked up concurrently, so either may get the error
+        let result = MetarCommand{}.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&metar ZZZY EDDK", &["ZZZY", "EDDK"])).await;
+        let texts: Vec<String> = match result {
+            Ok(BotResponse::Multi(responses)) => responses.into_iter()
+                .map(|response| match response {
+                    BotResponse::Privmsg(_, text) => text,
+                    response => panic!("unexpected response {:?}", response),
+                })
+                .collect(),
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert_eq!(texts.len(), 2);
+        assert!(texts.iter().any(|text| text.ends_with(": Error: Airport not found")), "{:?}", texts);
+    }
+
+    #[test]
+    fn avwx_base_url_and_timeout_are_configurable() {
+        let fixture = Fixture::with_options(&[("avwx_apikey", "s3cr3t")]);
+        let avwx = Avwx::from_options(&fixture.options).unwrap();
+        assert_eq!(avwx.url("metar", "EDDF"), "https://avwx.rest/api/metar/EDDF");
+        assert_eq!(avwx.timeout, time::Duration::from_secs(5));
+
+        let fixture = Fixture::with_options(&[
+            ("avwx_apikey", "s3cr3t"),
+            ("avwx_base_url", "http://avwx.example.org:8080/"),
+            ("avwx_timeout_secs", "20"),
+        ]);
+        let avwx = Avwx::from_options(&fixture.options).unwrap();
+        assert_eq!(avwx.url("station", "KSFO"), "http://avwx.example.org:8080/api/station/KSFO");
+        assert_eq!(avwx.timeout, time::Duration::from_secs(20));
+
+        assert!(matches!(Avwx::from_options(&HashMap::new()), Err(BotError::Unconfigured(_))));
+    }
+
+    #[test]
+    fn temperatures_convert_at_boundary_values() {
+        assert_eq!(celsius_to_fahrenheit(0.0), 32.0);
+        assert_eq!(celsius_to_fahrenheit(100.0), 212.0);
+        assert_eq!(celsius_to_fahrenheit(-40.0), -40.0);
+        assert_eq!(fahrenheit_to_celsius(32.0), 0.0);
+        assert_eq!(fahrenheit_to_celsius(212.0), 100.0);
+        assert_eq!(fahrenheit_to_celsius(-40.0), -40.0);
+
+        assert_eq!(convert_temperature(0.0, "C", Some("imperial")), (32.0, "F"));
+        assert_eq!(convert_temperature(32.0, "F", Some("metric")), (0.0, "C"));
+        assert_eq!(convert_temperature(0.0, "C", Some("metric")), (0.0, "C"));
+        assert_eq!(convert_temperature(0.0, "C", None), (0.0, "C"));
+        assert_eq!(convert_temperature(0.0, "C", Some("nautical")), (0.0, "C"));
+    }
+
+    #[test]
+    fn speeds_convert_from_knots() {
+        assert_eq!(format_number(knots_to_kmh(10.0)), "18.5");
+        assert_eq!(format_number(knots_to_mph(10.0)), "11.5");
+        assert_eq!(knots_to_kmh(0.0), 0.0);
+
+        let (kmh, unit) = convert_speed(10.0, "kt", Some("metric"));
+        assert_eq!((format_number(kmh).as_str(), unit), ("18.5", "km/h"));
+        let (mph, unit) = convert_speed(10.0, "kt", Some("imperial"));
+        assert_eq!((format_number(mph).as_str(), unit), ("11.5", "mph"));
+        assert_eq!(convert_speed(10.0, "kt", None), (10.0, "kt"));
+        assert_eq!(convert_speed(10.0, "mps", Some("metric")), (10.0, "mps"));
+    }
+
+    #[test]
+    fn numbers_are_formatted_with_at_most_one_decimal() {
+        assert_eq!(format_number(18.0), "18");
+        assert_eq!(format_number(18.52), "18.5");
+        assert_eq!(format_number(11.96), "12");
+    }
+
+    const LONG_TAF: &str = "TAF EDDW 140500Z 1406/1512 27010KT 9999 SCT030 \
+        TEMPO 1406/1410 4000 SHRA BKN012 BECMG 1410/1412 30015G25KT \
+        PROB30 TEMPO 1412/1418 TSRA BKN020CB BECMG 1418/1420 VRB03KT \
+        TEMPO 1420/1508 1500 BR BKN004 BECMG 1508/1510 24008KT CAVOK";
+
+    #[tokio::test]
+    async fn long_tafs_are_split_at_word_boundaries() {
+        let (url, server) = http_server(vec![
+            http_response(200, "application/json", r#"{"name": "Bremen", "icao": "EDDW", "reporting": true}"#),
+            http_response(200, "application/json", &format!(r#"{{"raw": "{}"}}"#, LONG_TAF)),
+        ]).await;
+        let fixture = Fixture::with_options(&[("avwx_base_url", &url), ("avwx_apikey", "s3cr3t"), ("max_line_length", "60")]);
+
+        let result = TafCommand{}.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&taf EDDW", &["EDDW"])).await;
+        let lines: Vec<String> = match result {
+            Ok(BotResponse::Multi(responses)) => responses.into_iter()
+                .map(|response| match response {
+                    BotResponse::Privmsg(ref target, text) if target == "#weather" => text,
+                    response => panic!("unexpected response {:?}", response),
+                })
+                .collect(),
+            result => panic!("unexpected result {:?}", result),
+        };
+        server.await.unwrap();
+
+        assert_eq!(lines.len(), 4, "{:?}", lines);
+        assert!(lines.iter().all(|line| line.len() <= 60), "{:?}", lines);
+        assert_eq!(lines.join(" "), LONG_TAF);
+        let words: Vec<&str> = LONG_TAF.split(' ').collect();
+        assert!(lines.iter().flat_map(|line| line.split(' ')).all(|word| words.contains(&word)), "{:?}", lines);
     }
 }
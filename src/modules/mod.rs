@@ -3,16 +3,62 @@
 #![deny(unsafe_code)]
 #![deny(missing_docs)]
 
+pub use self::basic::mk as basic;
+pub use self::dice::mk as dice;
+pub use self::help::mk as help;
 pub use self::ircactions::mk as ircactions;
 pub use self::metar::mk as metar;
+pub use self::remind::mk as remind;
+pub use self::seen::mk as seen;
+pub use self::translate::mk as translate;
+pub use self::urltitle::mk as urltitle;
+pub use self::winds::mk as winds;
 
 use crate::BotCommand;
 
+/// A module that provides basic commands, such as ping
+mod basic;
+
+/// A module that rolls dice
+mod dice;
+
+/// A module that lists the available commands
+mod help;
+
 /// A module that fetches METARs and TAFs from api.met.no
 mod metar;
 
 /// A module that provides standard IRC actions, such as join, part, and quit
 mod ircactions;
 
-/// A slice of functions that will create vectors of all implemented modules
-pub const ALL: &[fn() -> Vec<Box<dyn BotCommand>>] = &[ircactions, metar];
+/// A module that sends reminders
+mod remind;
+
+/// A module that tracks when users were last seen talking
+mod seen;
+
+/// A module that translates text
+mod translate;
+
+/// A module that shows the titles of web pages
+mod urltitle;
+
+/// A module that fetches winds and temperatures aloft forecasts from aviationweather.gov
+mod winds;
+
+/// A function that creates the commands implemented by a module
+pub type ModuleFactory = fn() -> Vec<Box<dyn BotCommand>>;
+
+/// A slice of the names of all implemented modules and the functions that will create their commands
+pub const ALL: &[(&str, ModuleFactory)] = &[
+    ("basic", basic),
+    ("dice", dice),
+    ("help", help),
+    ("ircactions", ircactions),
+    ("metar", metar),
+    ("remind", remind),
+    ("seen", seen),
+    ("translate", translate),
+    ("urltitle", urltitle),
+    ("winds", winds),
+];
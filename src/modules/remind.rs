@@ -0,0 +1,234 @@
+//! Module that sends reminders after a given time
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+extern crate tokio;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+};
+
+/** Reminders are kept in memory only, so they should not outlive a typical uptime by much */
+static MAX_DELAY_SECS: u64 = 7 * 24 * 3600;
+
+struct RemindCommand {
+    /** The number of reminders each user, by lowercase nickname, has pending */
+    pending: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(RemindCommand{ pending: Arc::new(Mutex::new(HashMap::new())) }),
+    ]
+}
+
+/**
+ * Parse a duration such as "10m", "2h", "1h30m" or "90s" into a number of seconds. Units are d, h,
+ * m and s, and every number needs a unit. Returns None if the duration is malformed or zero.
+ */
+fn parse_duration(duration: &str) -> Option<u64> {
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in duration.to_lowercase().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue
+        }
+
+        let multiplier = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        let value: u64 = number.parse().ok()?;
+        total = total.checked_add(value.checked_mul(multiplier)?)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        None
+    } else {
+        Some(total)
+    }
+}
+
+/**
+ * The maximum number of pending reminders per user, from the remind_max_pending option.
+ */
+fn max_pending(options: &HashMap<String, String>) -> usize {
+    options.get("remind_max_pending").and_then(|max| max.parse().ok()).unwrap_or(5)
+}
+
+#[async_trait::async_trait]
+impl BotCommand for RemindCommand {
+    fn trigger(&self) -> &'static str {
+        "remind"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send a reminder after the given time, e.g. 1h30m"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+        let source_nickname = params.message
+            .source_nickname()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let delay = match params.args.split_first() {
+            Some((duration, message)) if !message.is_empty() =>
+                parse_duration(duration).map(|secs| (secs, message.join(" "))),
+            _ =>
+                None,
+        };
+
+        let (secs, message) = match delay {
+            Some((secs, _)) if secs > MAX_DELAY_SECS =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Reminders can be at most {} days ahead", MAX_DELAY_SECS / 86400))),
+            Some(delay) =>
+                delay,
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <time, e.g. 10m, 2h or 1h30m> <message>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        // Each reminder waits in its own task, so the number of them is limited per user
+        let key = source_nickname.to_lowercase();
+        {
+            let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+            let count = pending.entry(key.clone()).or_insert(0);
+            if *count >= max_pending(params.options) {
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{}: you already have {} pending reminders", source_nickname, count)));
+            }
+            *count += 1;
+        }
+
+        // The reminder is sent through the responder when it is due
+        let (responder, target, nickname) = (params.responder.clone(), response_target.clone(), source_nickname.clone());
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_secs(secs)).await;
+            {
+                let mut pending = pending.lock().unwrap_or_else(|err| err.into_inner());
+                if let Some(count) = pending.get_mut(&key) {
+                    *count -= 1;
+                    if *count == 0 {
+                        pending.remove(&key);
+                    }
+                }
+            }
+            let _ = responder.unbounded_send(BotResponse::Privmsg(target, format!("{}: reminder: {}", nickname, message)));
+        });
+
+        Ok(BotResponse::Privmsg(
+            response_target,
+            format!("{}: I will remind you in {}", source_nickname, params.args[0])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use crate::testing::Fixture;
+
+    #[test]
+    fn durations_are_parsed_into_seconds() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("10m"), Some(600));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("1D2H"), Some(93600));
+    }
+
+    #[test]
+    fn malformed_durations_are_rejected() {
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("0h0m"), None);
+        assert_eq!(parse_duration("10x"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn overflowing_durations_are_rejected() {
+        assert_eq!(parse_duration("18446744073709551615s"), Some(u64::MAX));
+        assert_eq!(parse_duration("18446744073709551615d"), None);
+        assert_eq!(parse_duration("18446744073709551616s"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+    }
+
+    fn command() -> RemindCommand {
+        RemindCommand { pending: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    async fn remind(command: &RemindCommand, fixture: &Fixture, args: &[&str]) -> String {
+        match command.handle(fixture.params(":alice!a@example.org PRIVMSG #weather :&remind", args)).await {
+            Ok(BotResponse::Privmsg(ref target, ref text)) if target == "#weather" => text.clone(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn reminders_are_at_most_a_week_ahead() {
+        let fixture = Fixture::default();
+        assert_eq!(remind(&command(), &fixture, &["8d", "renew", "medical"]).await, "Reminders can be at most 7 days ahead");
+        assert_eq!(remind(&command(), &fixture, &["7d", "renew", "medical"]).await, "alice: I will remind you in 7d");
+    }
+
+    #[tokio::test]
+    async fn pending_reminders_are_limited_per_user() {
+        let fixture = Fixture::with_options(&[("remind_max_pending", "2")]);
+        let command = command();
+        assert_eq!(remind(&command, &fixture, &["1h", "first"]).await, "alice: I will remind you in 1h");
+        assert_eq!(remind(&command, &fixture, &["2h", "second"]).await, "alice: I will remind you in 2h");
+        assert_eq!(remind(&command, &fixture, &["3h", "third"]).await, "alice: you already have 2 pending reminders");
+
+        let other = command.handle(fixture.params(":bob!b@example.org PRIVMSG #weather :&remind", &["1h", "mine"])).await;
+        assert!(matches!(other, Ok(BotResponse::Privmsg(_, ref text)) if text == "bob: I will remind you in 1h"), "{:?}", other);
+    }
+
+    #[tokio::test]
+    async fn sent_reminder_is_no_longer_pending() {
+        let (responder, mut reminders) = mpsc::unbounded();
+        let fixture = Fixture { responder, ..Fixture::with_options(&[("remind_max_pending", "1")]) };
+        let command = command();
+        assert_eq!(remind(&command, &fixture, &["1s", "check", "the", "weather"]).await, "alice: I will remind you in 1s");
+        assert_eq!(remind(&command, &fixture, &["1s", "again"]).await, "alice: you already have 1 pending reminders");
+
+        let reminder = reminders.next().await;
+        assert!(matches!(reminder, Some(BotResponse::Privmsg(ref target, ref text))
+            if target == "#weather" && text == "alice: reminder: check the weather"), "{:?}", reminder);
+        assert_eq!(remind(&command, &fixture, &["1h", "again"]).await, "alice: I will remind you in 1h");
+    }
+}
@@ -0,0 +1,265 @@
+//! Module that tracks when users were last seen talking in a channel
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+extern crate irc;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use irc::proto::command::Command;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+    ObserveParameters,
+    util::is_public,
+};
+
+/**
+ * The last time a user was seen talking, and where.
+ */
+#[derive(Debug, Clone)]
+struct SeenRecord {
+    /** The nickname as the user last used it, preserving case */
+    nickname: String,
+    /** The time at which the user's last message was received */
+    time: Instant,
+    /** The channel the user's last message was sent to */
+    channel: String,
+}
+
+/**
+ * The last messages of users, keyed by their lowercase nickname. Records older than the maximum
+ * age are forgotten, and only the most recently active users are kept, so the log does not grow
+ * without bounds in busy channels.
+ */
+#[derive(Debug, Default)]
+struct SeenLog {
+    records: HashMap<String, SeenRecord>,
+}
+
+impl SeenLog {
+    /**
+     * Record a message, forgetting records older than max_age and the least recently active users
+     * beyond max_entries.
+     */
+    fn record(&mut self, record: SeenRecord, max_entries: usize, max_age: Duration) {
+        let now = record.time;
+        self.records.retain(|_, existing| now.duration_since(existing.time) < max_age);
+        self.records.insert(record.nickname.to_lowercase(), record);
+        while self.records.len() > max_entries {
+            let oldest = self.records.iter()
+                .min_by_key(|(_, record)| record.time)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => self.records.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    /**
+     * The last message of the user with the given nickname, unless it is older than max_age.
+     */
+    fn get(&self, nickname: &str, now: Instant, max_age: Duration) -> Option<&SeenRecord> {
+        self.records.get(&nickname.to_lowercase())
+            .filter(|record| now.duration_since(record.time) < max_age)
+    }
+}
+
+/**
+ * The maximum number of users remembered, from the seen_max_entries option.
+ */
+fn max_entries(options: &HashMap<String, String>) -> usize {
+    options.get("seen_max_entries").and_then(|max| max.parse().ok()).unwrap_or(1000)
+}
+
+/**
+ * How long a user's last message is remembered, from the seen_max_age_secs option.
+ */
+fn max_age(options: &HashMap<String, String>) -> Duration {
+    Duration::from_secs(options.get("seen_max_age_secs").and_then(|secs| secs.parse().ok()).unwrap_or(7 * 86400))
+}
+
+/**
+ * Describe when a user was last seen. The channel is only named when asked in that same channel,
+ * so the seen command does not reveal activity in other, possibly secret, channels.
+ */
+fn describe(record: &SeenRecord, asked_in: Option<&str>, now: Instant) -> String {
+    let elapsed = format_elapsed(now.duration_since(record.time).as_secs());
+    match asked_in {
+        Some(channel) if channel.eq_ignore_ascii_case(&record.channel) =>
+            format!("{} was last seen here {} ago", record.nickname, elapsed),
+        _ =>
+            format!("{} was last seen {} ago", record.nickname, elapsed),
+    }
+}
+
+struct SeenCommand {
+    seen: Arc<Mutex<SeenLog>>,
+}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(SeenCommand{ seen: Arc::new(Mutex::new(SeenLog::default())) }),
+    ]
+}
+
+/**
+ * Format a number of seconds as a rough human-readable duration, e.g. "2h 5m".
+ */
+fn format_elapsed(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for SeenCommand {
+    fn trigger(&self) -> &'static str {
+        "seen"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show when a user was last seen talking in a channel"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let nickname = match params.args.first() {
+            Some(nickname) => nickname,
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <nickname>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        if params.message.source_nickname().map_or(false, |source| source.eq_ignore_ascii_case(nickname)) {
+            return Ok(BotResponse::Privmsg(response_target, "Looking for yourself?".to_string()));
+        }
+
+        let now = Instant::now();
+        let record = self.seen.lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(nickname, now, max_age(params.options))
+            .cloned();
+
+        Ok(BotResponse::Privmsg(
+            response_target,
+            match record {
+                Some(record) =>
+                    describe(&record, params.context.channel.as_deref(), now),
+                None =>
+                    format!("I have not seen {}", nickname),
+            }))
+    }
+
+    async fn observe(&self, params: ObserveParameters<'_>) {
+        let message = params.message;
+        // Only channel messages are recorded, queries are private
+        if let (Command::PRIVMSG(ref target, _), Some(nickname)) = (&message.command, message.source_nickname()) {
+            if is_public(target) {
+                self.seen.lock().unwrap_or_else(|err| err.into_inner()).record(
+                    SeenRecord {
+                        nickname: nickname.to_string(),
+                        time: Instant::now(),
+                        channel: target.to_string(),
+                    },
+                    max_entries(params.options),
+                    max_age(params.options));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    fn record(nickname: &str, channel: &str, time: Instant) -> SeenRecord {
+        SeenRecord { nickname: nickname.to_string(), time, channel: channel.to_string() }
+    }
+
+    static DAY: Duration = Duration::from_secs(86400);
+
+    #[test]
+    fn channel_is_only_named_in_the_same_channel() {
+        let start = Instant::now();
+        let alice = record("Alice", "#secret", start);
+        let now = start + Duration::from_secs(125);
+
+        assert_eq!(describe(&alice, Some("#Secret"), now), "Alice was last seen here 2m ago");
+        assert_eq!(describe(&alice, Some("#weather"), now), "Alice was last seen 2m ago");
+        assert_eq!(describe(&alice, None, now), "Alice was last seen 2m ago");
+    }
+
+    #[test]
+    fn old_records_expire() {
+        let start = Instant::now();
+        let mut log = SeenLog::default();
+        log.record(record("alice", "#weather", start), 10, DAY);
+
+        assert!(log.get("ALICE", start + DAY / 2, DAY).is_some());
+        assert!(log.get("alice", start + DAY, DAY).is_none());
+
+        log.record(record("bob", "#weather", start + DAY * 2), 10, DAY);
+        assert_eq!(log.records.len(), 1);
+    }
+
+    #[test]
+    fn least_recently_active_users_are_evicted() {
+        let start = Instant::now();
+        let mut log = SeenLog::default();
+        log.record(record("alice", "#weather", start), 2, DAY);
+        log.record(record("bob", "#weather", start + Duration::from_secs(1)), 2, DAY);
+        log.record(record("alice", "#weather", start + Duration::from_secs(2)), 2, DAY);
+        log.record(record("carol", "#weather", start + Duration::from_secs(3)), 2, DAY);
+
+        let now = start + Duration::from_secs(4);
+        assert!(log.get("alice", now, DAY).is_some());
+        assert!(log.get("bob", now, DAY).is_none());
+        assert!(log.get("carol", now, DAY).is_some());
+    }
+
+    #[test]
+    fn seen_elsewhere_hides_the_channel() {
+        let fixture = Fixture::default();
+        let command = SeenCommand { seen: Arc::new(Mutex::new(SeenLog::default())) };
+        let said = crate::testing::message(":alice!a@example.org PRIVMSG #secret :hello");
+        futures::executor::block_on(command.observe(ObserveParameters {
+            message: &said,
+            leaders: &fixture.leaders,
+            options: &fixture.options,
+            responder: fixture.responder.clone(),
+        }));
+
+        let seen = |raw: &str, nickname: &str| match futures::executor::block_on(command.handle(fixture.params(raw, &[nickname]))) {
+            Ok(BotResponse::Privmsg(_, text)) => text,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert_eq!(seen(":bob!b@example.org PRIVMSG #secret :&seen alice", "alice"), "alice was last seen here 0s ago");
+        assert_eq!(seen(":bob!b@example.org PRIVMSG #weather :&seen alice", "alice"), "alice was last seen 0s ago");
+        assert_eq!(seen(":bob!b@example.org PRIVMSG metarbot :seen alice", "alice"), "alice was last seen 0s ago");
+        assert_eq!(seen(":bob!b@example.org PRIVMSG metarbot :seen carol", "carol"), "I have not seen carol");
+    }
+}
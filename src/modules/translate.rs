@@ -0,0 +1,245 @@
+//! Module that translates text using a LibreTranslate-compatible translation API
+//!
+//! The API is configured with the translate_url option, so the bot can use a public instance or a
+//! self-hosted one. Requests are posted as JSON with the text, the source and target languages and
+//! the optional API key; the response carries the translated text, or an error message.
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+extern crate serde_json;
+
+use std::fmt;
+use std::time;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+};
+
+lazy_static! {
+    static ref REQWEST: reqwest::Client = reqwest::Client::new();
+}
+
+struct TranslateCommand {}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(TranslateCommand{}),
+    ]
+}
+
+#[derive(Debug)]
+enum TranslateError {
+    NonSuccessResponse(reqwest::StatusCode),
+    Api(String),
+    ReqwestError(reqwest::Error),
+}
+
+/**
+ * Errors of the translation service are shown to the user that asked for the translation.
+ */
+impl From<TranslateError> for BotError {
+    fn from(err: TranslateError) -> BotError {
+        BotError::UserMessage(err.to_string())
+    }
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TranslateError::NonSuccessResponse(statuscode) =>
+                write!(f, "{}", statuscode),
+            TranslateError::Api(message) =>
+                write!(f, "{}", message),
+            TranslateError::ReqwestError(err) =>
+                write!(f, "ReqwestError: {}", err),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TranslateRequest<'a> {
+    q: &'a str,
+    source: &'a str,
+    target: &'a str,
+    format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct DetectedLanguage {
+    language: String,
+}
+
+#[derive(Deserialize)]
+struct TranslateResponse {
+    #[serde(rename = "translatedText")]
+    translated_text: String,
+    #[serde(rename = "detectedLanguage")]
+    detected_language: Option<DetectedLanguage>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/**
+ * Translate text from the source language, which may be "auto" to let the API detect it, to the
+ * target language. Returns the translation along with the detected source language, if any.
+ */
+async fn translate(url: &str, apikey: Option<&str>, timeout: time::Duration, source: &str, target: &str, text: &str)
+    -> Result<TranslateResponse, TranslateError>
+{
+    let request = TranslateRequest { q: text, source, target, format: "text", api_key: apikey };
+    let result = REQWEST.post(url)
+        .header("Accept", "application/json")
+        .json(&request)
+        .timeout(timeout)
+        .send()
+        .await;
+
+    match result {
+        Err(err) =>
+            Err(TranslateError::ReqwestError(err)),
+        Ok(response) =>
+            if !response.status().is_success() {
+                // Unknown languages and similar problems are explained in the body of the response
+                let statuscode = response.status();
+                match response.json::<ErrorResponse>().await {
+                    Ok(error) => Err(TranslateError::Api(error.error)),
+                    Err(_) => Err(TranslateError::NonSuccessResponse(statuscode)),
+                }
+            } else {
+                response.json::<TranslateResponse>().await.map_err(TranslateError::ReqwestError)
+            },
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for TranslateCommand {
+    fn trigger(&self) -> &'static str {
+        "translate"
+    }
+
+    fn description(&self) -> &'static str {
+        "Translate text to the given language, e.g. en or de:en to translate from German"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let url = params.options.get("translate_url").ok_or(BotError::Unconfigured("translate_url not set"))?;
+        let apikey = params.options.get("translate_apikey").map(String::as_str);
+        let timeout = time::Duration::from_secs(
+            params.options.get("translate_timeout_secs").and_then(|secs| secs.parse().ok()).unwrap_or(5));
+
+        let (languages, text) = match params.args.as_slice() {
+            [languages, text @ ..] if !text.is_empty() =>
+                (languages, text.join(" ")),
+            _ =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} [<source language>:]<target language> <text>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+        let mut languages_parts = languages.splitn(2, ':');
+        let (source, target) = match (languages_parts.next(), languages_parts.next()) {
+            (Some(source), Some(target)) => (source, target),
+            _ => ("auto", languages.as_str()),
+        };
+
+        Ok(BotResponse::Privmsg(
+            response_target,
+            match translate(url, apikey, timeout, source, target, &text).await? {
+                TranslateResponse { translated_text, detected_language: Some(detected) } =>
+                    format!("{} (from {})", translated_text, detected.language),
+                TranslateResponse { translated_text, detected_language: None } =>
+                    translated_text,
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{Fixture, http_response, http_server};
+
+    /**
+     * Translate the given arguments in #weather against a mocked translation API that sends the given
+     * response. Returns the result and the request the API received.
+     */
+    async fn invoke(options: &[(&str, &str)], args: &[&str], response: String) -> (BotCommandResult, String) {
+        let (url, server) = http_server(vec![response]).await;
+        let url = format!("{}/translate", url);
+        let mut options = options.to_vec();
+        options.push(("translate_url", &url));
+        let fixture = Fixture::with_options(&options);
+        let raw = format!(":alice!a@example.org PRIVMSG #weather :&translate {}", args.join(" "));
+        let result = TranslateCommand{}.handle(fixture.params(&raw, args)).await;
+        (result, server.await.unwrap().remove(0))
+    }
+
+    /**
+     * The JSON body of the given raw HTTP request.
+     */
+    fn body(request: &str) -> serde_json::Value {
+        let start = request.find("\r\n\r\n").unwrap() + 4;
+        serde_json::from_str(&request[start..]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn detected_language_is_shown() {
+        let (result, request) = invoke(&[], &["en", "Guten", "Morgen"], http_response(200, "application/json",
+            r#"{"translatedText": "Good morning", "detectedLanguage": {"confidence": 90, "language": "de"}}"#)).await;
+        assert!(matches!(result, Ok(BotResponse::Privmsg(ref target, ref text))
+            if target == "#weather" && text == "Good morning (from de)"), "{:?}", result);
+
+        assert!(request.starts_with("POST /translate "), "{}", request);
+        assert_eq!(body(&request), serde_json::json!({
+            "q": "Guten Morgen",
+            "source": "auto",
+            "target": "en",
+            "format": "text",
+        }));
+    }
+
+    #[tokio::test]
+    async fn explicit_source_language_and_api_key_are_sent() {
+        let (result, request) = invoke(&[("translate_apikey", "s3cr3t")], &["de:en", "Guten", "Morgen"],
+            http_response(200, "application/json", r#"{"translatedText": "Good morning"}"#)).await;
+        assert!(matches!(result, Ok(BotResponse::Privmsg(_, ref text)) if text == "Good morning"), "{:?}", result);
+
+        let body = body(&request);
+        assert_eq!(body["source"], "de");
+        assert_eq!(body["target"], "en");
+        assert_eq!(body["api_key"], "s3cr3t");
+    }
+
+    #[tokio::test]
+    async fn api_error_is_shown_to_the_user() {
+        let (result, _) = invoke(&[], &["xx", "Guten", "Morgen"], http_response(400, "application/json",
+            r#"{"error": "xx is not supported"}"#)).await;
+        assert!(matches!(result, Err(BotError::UserMessage(ref text)) if text == "xx is not supported"), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn error_without_message_shows_the_status() {
+        let (result, _) = invoke(&[], &["en", "Guten", "Morgen"], http_response(502, "text/html", "<h1>Bad Gateway</h1>")).await;
+        assert!(matches!(result, Err(BotError::UserMessage(ref text)) if text == "502 Bad Gateway"), "{:?}", result);
+    }
+}
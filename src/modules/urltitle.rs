@@ -0,0 +1,473 @@
+//! Module that fetches web pages and shows their titles
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+extern crate irc;
+extern crate regex;
+extern crate tokio;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time;
+
+use irc::proto::command::Command;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+    ObserveParameters,
+    util::is_public,
+};
+
+lazy_static! {
+    static ref URL_RE: regex::Regex = regex::Regex::new(r"^(?i)https?://\S+$").unwrap();
+    static ref EMBEDDED_URL_RE: regex::Regex = regex::Regex::new(r"(?i)\bhttps?://[^\s<>\x22]+").unwrap();
+    static ref TITLE_RE: regex::Regex = regex::Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    static ref ENTITY_RE: regex::Regex = regex::Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+    static ref REQWEST: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+}
+
+/** The number of redirects that are followed before giving up on a page */
+const MAX_REDIRECTS: usize = 10;
+
+struct TitleCommand {
+    /** The last time a title was posted automatically, per channel */
+    last_auto_title: Mutex<HashMap<String, time::Instant>>,
+}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(TitleCommand{ last_auto_title: Mutex::new(HashMap::new()) }),
+    ]
+}
+
+#[derive(Debug)]
+enum TitleError {
+    ForbiddenAddress(String),
+    NonSuccessResponse(reqwest::StatusCode),
+    NoTitle,
+    ReqwestError(reqwest::Error),
+    ResolveError(String, std::io::Error),
+    TooManyRedirects,
+    UnsupportedUrl(String),
+}
+
+/**
+ * Errors fetching a page are shown to the user that asked for its title.
+ */
+impl From<TitleError> for BotError {
+    fn from(err: TitleError) -> BotError {
+        BotError::UserMessage(err.to_string())
+    }
+}
+
+impl fmt::Display for TitleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TitleError::ForbiddenAddress(host) =>
+                write!(f, "Not fetching {}: it is not a public address", host),
+            TitleError::NonSuccessResponse(statuscode) =>
+                write!(f, "{}", statuscode),
+            TitleError::NoTitle =>
+                write!(f, "The page has no title"),
+            TitleError::ReqwestError(err) =>
+                write!(f, "ReqwestError: {}", err),
+            TitleError::ResolveError(host, err) =>
+                write!(f, "Failed to resolve {}: {}", host, err),
+            TitleError::TooManyRedirects =>
+                write!(f, "Too many redirects"),
+            TitleError::UnsupportedUrl(url) =>
+                write!(f, "Unsupported URL {}", url),
+        }
+    }
+}
+
+/**
+ * Decode the HTML character references in the given text, e.g. "&amp;" or "&#8211;". Unknown named
+ * references are left as they are.
+ */
+fn decode_entities(text: &str) -> String {
+    ENTITY_RE.replace_all(text, |captures: &regex::Captures| {
+        let entity = &captures[1];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") =>
+                u32::from_str_radix(&entity[2..], 16).ok().and_then(std::char::from_u32),
+            _ if entity.starts_with('#') =>
+                entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        decoded.map_or_else(|| captures[0].to_string(), |c| c.to_string())
+    }).into_owned()
+}
+
+/**
+ * Whether pages at the given address may be fetched. Loopback, private, link-local and unspecified
+ * addresses are refused, so the bot cannot be used to reach services that are only accessible from
+ * the host it runs on or its network.
+ */
+fn is_public_address(addr: &SocketAddr) -> bool {
+    fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+        let [first, second, _, _] = ip.octets();
+        !(ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || first == 0
+            || (first == 100 && second & 0xc0 == 64))
+    }
+
+    match addr.ip() {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.segments() {
+            // IPv4-mapped addresses, ::ffff:a.b.c.d
+            [0, 0, 0, 0, 0, 0xffff, high, low] =>
+                is_public_ipv4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+            // Unique local fc00::/7 and link-local fe80::/10 addresses
+            [first, ..] => !(ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || ip.is_multicast()),
+        },
+    }
+}
+
+/**
+ * Check that the given URL is an http(s) URL whose host only resolves to addresses allowed by the
+ * given filter.
+ */
+async fn check_url(url: &reqwest::Url, allow: &(dyn Fn(&SocketAddr) -> bool + Sync)) -> Result<(), TitleError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(TitleError::UnsupportedUrl(url.to_string()));
+    }
+    let host = url.host_str()
+        .ok_or_else(|| TitleError::UnsupportedUrl(url.to_string()))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|err| TitleError::ResolveError(host.to_string(), err))?
+        .collect::<Vec<SocketAddr>>();
+    if addrs.is_empty() || !addrs.iter().all(allow) {
+        Err(TitleError::ForbiddenAddress(host.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/**
+ * Fetch the given URL and describe it: the title of HTML pages, the content type otherwise. No
+ * more than the urltitle_max_bytes option (default 256 KiB) of the page are downloaded, and each
+ * request is aborted after urltitle_timeout_secs (default 5). Redirects are followed, and pages on
+ * addresses that are not public are refused, see is_public_address().
+ */
+async fn describe(url: &str, options: &HashMap<String, String>) -> Result<String, TitleError> {
+    describe_with(url, options, &is_public_address).await
+}
+
+/**
+ * Like describe(), but only fetch pages at addresses allowed by the given filter. Redirects are
+ * followed here rather than by reqwest, so that the target of each redirect is checked, too. Note
+ * that reqwest resolves the host again when connecting.
+ */
+async fn describe_with(
+    url: &str,
+    options: &HashMap<String, String>,
+    allow: &(dyn Fn(&SocketAddr) -> bool + Sync),
+) -> Result<String, TitleError> {
+    let max_bytes = options.get("urltitle_max_bytes").and_then(|bytes| bytes.parse().ok()).unwrap_or(256 * 1024);
+    let timeout = time::Duration::from_secs(
+        options.get("urltitle_timeout_secs").and_then(|secs| secs.parse().ok()).unwrap_or(5));
+
+    let mut url = reqwest::Url::parse(url).map_err(|_| TitleError::UnsupportedUrl(url.to_string()))?;
+    let mut redirects = 0;
+    let mut response = loop {
+        check_url(&url, allow).await?;
+        let response = REQWEST.get(url.clone())
+            .header("Accept", "text/html")
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(TitleError::ReqwestError)?;
+        if !response.status().is_redirection() {
+            break response;
+        }
+
+        let location = response.headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok());
+        match location {
+            Some(_) if redirects >= MAX_REDIRECTS =>
+                return Err(TitleError::TooManyRedirects),
+            Some(location) => {
+                url = location;
+                redirects += 1;
+            },
+            None =>
+                break response,
+        }
+    };
+
+    if !response.status().is_success() {
+        return Err(TitleError::NonSuccessResponse(response.status()));
+    }
+
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("unknown content type")
+        .to_string();
+    if !content_type.to_lowercase().starts_with("text/html") {
+        return Ok(content_type);
+    }
+
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(TitleError::ReqwestError)? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= max_bytes {
+            body.truncate(max_bytes);
+            break;
+        }
+    }
+
+    let body = String::from_utf8_lossy(&body);
+    let title = TITLE_RE.captures(&body).ok_or(TitleError::NoTitle)?;
+    let title = decode_entities(&title[1]).split_whitespace().collect::<Vec<&str>>().join(" ");
+    if title.is_empty() {
+        Err(TitleError::NoTitle)
+    } else {
+        Ok(title)
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for TitleCommand {
+    fn trigger(&self) -> &'static str {
+        "title"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the title of a web page"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        match params.args.first() {
+            Some(url) if URL_RE.is_match(url) =>
+                Ok(BotResponse::Privmsg(response_target, describe(url, params.options).await?)),
+            _ =>
+                Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <http(s) URL>",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        }
+    }
+
+    /**
+     * With the auto_title option enabled, post the title of the first URL in channel messages, at
+     * most once per auto_title_interval_secs (default 60) per channel. Commands are ignored, so
+     * the title command does not answer twice and URLs given to other commands are not fetched.
+     */
+    async fn observe(&self, params: ObserveParameters<'_>) {
+        if params.options.get("auto_title").map(String::as_str) != Some("true") {
+            return;
+        }
+
+        let (channel, text) = match params.message.command {
+            Command::PRIVMSG(ref target, ref text) if is_public(target) => (target, text),
+            _ => return,
+        };
+        if text.chars().next().map_or(false, |first_char| params.leaders.contains(&first_char)) {
+            return;
+        }
+        let url = match EMBEDDED_URL_RE.find(text) {
+            Some(url) => url.as_str().to_string(),
+            None => return,
+        };
+
+        let interval = time::Duration::from_secs(
+            params.options.get("auto_title_interval_secs").and_then(|secs| secs.parse().ok()).unwrap_or(60));
+        {
+            let mut last_auto_title = self.last_auto_title.lock().unwrap_or_else(|err| err.into_inner());
+            let now = time::Instant::now();
+            if last_auto_title.get(&channel.to_lowercase()).map_or(false, |last| now.duration_since(*last) < interval) {
+                return;
+            }
+            last_auto_title.insert(channel.to_lowercase(), now);
+        }
+
+        // Fetch in the background, so other messages are not held up by a slow page
+        let (channel, options, responder) = (channel.to_string(), params.options.clone(), params.responder);
+        tokio::spawn(async move {
+            match describe(&url, &options).await {
+                Ok(title) => {
+                    let _ = responder.unbounded_send(BotResponse::Privmsg(channel, format!("Title: {}", title)));
+                },
+                Err(err) =>
+                    debug!("not posting title of {}: {}", url, err),
+            }
+        });
+
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+    use futures::channel::mpsc;
+
+    use crate::testing::{http_response, http_server, message};
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn allow_all(_: &SocketAddr) -> bool {
+        true
+    }
+
+    fn forbidden_host(result: Result<String, TitleError>) -> String {
+        match result {
+            Err(TitleError::ForbiddenAddress(host)) => host,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn only_public_addresses_are_allowed() {
+        for forbidden in &[
+            "127.0.0.1:80", "10.1.2.3:80", "172.16.0.1:80", "192.168.1.1:80", "169.254.169.254:80",
+            "0.0.0.0:80", "100.64.0.1:80", "255.255.255.255:80", "[::1]:80", "[::]:80", "[fe80::1]:80",
+            "[fd00::1]:80", "[::ffff:127.0.0.1]:80", "[::ffff:10.0.0.1]:80",
+        ] {
+            assert!(!is_public_address(&addr(forbidden)), "{} is allowed", forbidden);
+        }
+        for allowed in &["93.184.216.34:443", "8.8.8.8:80", "[2606:4700::1111]:443", "[::ffff:8.8.8.8]:80"] {
+            assert!(is_public_address(&addr(allowed)), "{} is refused", allowed);
+        }
+    }
+
+    #[tokio::test]
+    async fn local_urls_are_refused() {
+        let options = HashMap::new();
+        assert_eq!(forbidden_host(describe("http://127.0.0.1:1/", &options).await), "127.0.0.1");
+        assert_eq!(forbidden_host(describe("http://[::1]:1/", &options).await), "::1");
+        assert_eq!(forbidden_host(describe("http://169.254.169.254/latest/meta-data/", &options).await), "169.254.169.254");
+        assert_eq!(forbidden_host(describe("http://localhost:1/", &options).await), "localhost");
+        assert!(matches!(describe("ftp://example.org/", &options).await, Err(TitleError::UnsupportedUrl(_))));
+    }
+
+    #[tokio::test]
+    async fn titles_are_decoded_behind_redirects() {
+        let (url, server) = http_server(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /page\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+            http_response(200, "text/html; charset=utf-8", "<html><title>\n Fish &amp; Chips &#8211; Menu </title></html>"),
+        ]).await;
+
+        let title = describe_with(&format!("{}/", url), &HashMap::new(), &allow_all).await.unwrap();
+        assert_eq!(title, "Fish & Chips \u{2013} Menu");
+
+        let requests = server.await.unwrap();
+        assert!(requests[1].starts_with("GET /page HTTP/1.1"), "{:?}", requests);
+    }
+
+    #[tokio::test]
+    async fn other_content_types_are_described_by_type() {
+        let (url, server) = http_server(vec![http_response(200, "image/png", "PNG")]).await;
+        assert_eq!(describe_with(&url, &HashMap::new(), &allow_all).await.unwrap(), "image/png");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn redirects_to_refused_addresses_are_not_followed() {
+        let (url, server) = http_server(vec![
+            "HTTP/1.1 301 Moved\r\nLocation: http://127.0.0.1:1/secret\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        ]).await;
+        let port = reqwest::Url::parse(&url).unwrap().port().unwrap();
+
+        let result = describe_with(&url, &HashMap::new(), &|addr: &SocketAddr| addr.port() == port).await;
+        assert_eq!(forbidden_host(result), "127.0.0.1");
+        server.await.unwrap();
+    }
+
+    /**
+     * Let a fresh title command observe the given raw message and return it along with the
+     * responses it sent, once any fetch in the background is done.
+     */
+    async fn observe(raw: &str, options: &[(&str, &str)]) -> (TitleCommand, Vec<BotResponse>) {
+        let command = TitleCommand{ last_auto_title: Mutex::new(HashMap::new()) };
+        let options = options.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        let (responder, responses) = mpsc::unbounded();
+        command.observe(ObserveParameters {
+            message: &message(raw),
+            leaders: &vec!['&'],
+            options: &options,
+            responder,
+        }).await;
+        (command, responses.collect().await)
+    }
+
+    fn auto_titled_channels(command: &TitleCommand) -> Vec<String> {
+        command.last_auto_title.lock().unwrap().keys().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn auto_title_is_off_by_default() {
+        let (command, responses) = observe(":alice!a@b PRIVMSG #chan :look at http://127.0.0.1:1/", &[]).await;
+        assert!(responses.is_empty());
+        assert!(auto_titled_channels(&command).is_empty());
+
+        let (command, _) = observe(":alice!a@b PRIVMSG #chan :look at http://127.0.0.1:1/", &[("auto_title", "yes")]).await;
+        assert!(auto_titled_channels(&command).is_empty());
+    }
+
+    #[tokio::test]
+    async fn auto_titles_of_local_urls_are_not_posted() {
+        let (command, responses) = observe(
+            ":alice!a@b PRIVMSG #Chan :look at http://127.0.0.1:1/ and http://[::1]/",
+            &[("auto_title", "true")]).await;
+        assert!(responses.is_empty(), "{:?}", responses);
+        assert_eq!(auto_titled_channels(&command), vec!["#chan"]);
+    }
+
+    #[tokio::test]
+    async fn commands_and_queries_are_not_auto_titled() {
+        let options = [("auto_title", "true")];
+        let (command, _) = observe(":alice!a@b PRIVMSG #chan :&title http://127.0.0.1:1/", &options).await;
+        assert!(auto_titled_channels(&command).is_empty());
+
+        let (command, _) = observe(":alice!a@b PRIVMSG metarbot :http://127.0.0.1:1/", &options).await;
+        assert!(auto_titled_channels(&command).is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+//! Module that provides winds and temperatures aloft forecasts, downloaded from aviationweather.gov
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate async_trait;
+extern crate irc;
+extern crate regex;
+
+use std::fmt;
+use std::time;
+
+use crate::{
+    BotCommand,
+    BotCommandResult,
+    BotError,
+    BotParameters,
+    BotResponse,
+};
+
+static WINDS_API_URL: &str = "https://aviationweather.gov/api/data/windtemp?region=all&level=low&fcst=06";
+static DEFAULT_ALTITUDES: &str = "3000,6000,9000,12000,18000";
+
+lazy_static! {
+    static ref STATION_RE: regex::Regex = regex::Regex::new(r"^(?i)[a-z0-9]{3,4}$").unwrap();
+    static ref REQWEST: reqwest::Client = reqwest::Client::new();
+}
+
+struct WindsCommand {}
+
+/**
+ * Factory function that will create instances of all implemented commands in this module.
+ */
+pub fn mk() -> Vec<Box<dyn BotCommand>> {
+    vec![
+        Box::new(WindsCommand{}),
+    ]
+}
+
+#[derive(Debug)]
+enum WindsError {
+    NonSuccessResponse(reqwest::StatusCode),
+    NoData(String),
+    ReqwestError(reqwest::Error),
+}
+
+/**
+ * Errors fetching the forecast are shown to the user that asked for it.
+ */
+impl From<WindsError> for BotError {
+    fn from(err: WindsError) -> BotError {
+        BotError::UserMessage(err.to_string())
+    }
+}
+
+impl fmt::Display for WindsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WindsError::NonSuccessResponse(statuscode) =>
+                write!(f, "{}", statuscode),
+            WindsError::NoData(station) =>
+                write!(f, "No winds aloft forecast for {}", station),
+            WindsError::ReqwestError(err) =>
+                write!(f, "ReqwestError: {}", err),
+        }
+    }
+}
+
+/**
+ * The forecast wind and temperature at a single altitude, decoded from an FB group such as
+ * "2725+05" or "731960".
+ */
+#[derive(Debug, PartialEq)]
+struct WindAloft {
+    /** Wind direction in degrees true, None if light and variable */
+    direction: Option<u32>,
+    /** Wind speed in knots, 0 if light and variable */
+    speed: u32,
+    /** Temperature in degrees Celsius, None if not forecast at this altitude */
+    temperature: Option<i32>,
+}
+
+impl fmt::Display for WindAloft {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.direction {
+            Some(direction) => write!(f, "{:03}@{}kt", direction, self.speed)?,
+            None => write!(f, "light and variable")?,
+        }
+        match self.temperature {
+            Some(temperature) => write!(f, " {:+}C", temperature),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * Decode a single FB group. Groups consist of a two-digit direction in tens of degrees, a two-digit
+ * speed in knots and an optional signed temperature. Directions of 51 to 86 encode speeds of 100kt
+ * and more, 9900 means light and variable. Above 24000ft, temperatures are always negative and
+ * the sign is omitted.
+ */
+fn decode_group(group: &str) -> Option<WindAloft> {
+    if group.len() < 4 || !group.is_char_boundary(4) {
+        return None;
+    }
+
+    let (wind, temperature) = group.split_at(4);
+    let dd: u32 = wind[0..2].parse().ok()?;
+    let ff: u32 = wind[2..4].parse().ok()?;
+
+    let temperature = match temperature {
+        "" => None,
+        t if t.starts_with('+') || t.starts_with('-') => Some(t.parse().ok()?),
+        t => Some(-t.parse::<i32>().ok()?),
+    };
+
+    let (direction, speed) = match dd {
+        99 => (None, 0),
+        51..=86 => (Some((dd - 50) * 10), ff + 100),
+        0..=36 => (Some(dd * 10), ff),
+        _ => return None,
+    };
+
+    Some(WindAloft { direction, speed, temperature })
+}
+
+/**
+ * Find the forecast for the given station in an FB winds aloft bulletin, returning the altitudes of
+ * the bulletin along with the group forecast at that altitude, if any. Groups are right-aligned with
+ * the altitude labels of the "FT" header line, and stations at a higher elevation leave the lower
+ * columns empty.
+ */
+fn parse_bulletin(bulletin: &str, station: &str) -> Option<Vec<(u32, Option<WindAloft>)>> {
+    // Return the whitespace-separated tokens of a line along with their end offsets
+    let tokens = |line: &str| {
+        let mut tokens = Vec::new();
+        let mut start = None;
+        for (idx, c) in line.char_indices().chain(std::iter::once((line.len(), ' '))) {
+            match (c.is_whitespace(), start) {
+                (true, Some(s)) => {
+                    tokens.push((line[s..idx].to_string(), idx));
+                    start = None;
+                },
+                (false, None) => start = Some(idx),
+                _ => (),
+            }
+        }
+        tokens
+    };
+
+    let mut lines = bulletin.lines();
+    let header = lines.find(|line| line.starts_with("FT "))?;
+    let altitudes: Vec<(u32, usize)> = tokens(header).into_iter()
+        .skip(1)
+        .filter_map(|(altitude, end)| altitude.parse().ok().map(|altitude| (altitude, end)))
+        .collect();
+
+    let line = lines.find(|line| line.split_whitespace().next().map_or(false, |id| id.eq_ignore_ascii_case(station)))?;
+    let groups = tokens(line);
+
+    Some(altitudes.into_iter()
+        .map(|(altitude, end)| {
+            let group = groups.iter().skip(1).find(|(_, group_end)| *group_end == end);
+            (altitude, group.and_then(|(group, _)| decode_group(group)))
+        })
+        .collect())
+}
+
+/**
+ * Format the forecast of a station at the requested altitudes, or list the available altitudes if
+ * the bulletin has none of the requested ones.
+ */
+fn format_forecast(station: &str, forecast: &[(u32, Option<WindAloft>)], requested: &[u32]) -> String {
+    let selected: Vec<String> = forecast.iter()
+        .filter(|(altitude, _)| requested.contains(altitude))
+        .map(|(altitude, wind)| match wind {
+            Some(wind) => format!("{}ft {}", altitude, wind),
+            None => format!("{}ft n/a", altitude),
+        })
+        .collect();
+    if selected.is_empty() {
+        format!("No winds aloft forecast for {} at the requested altitude; available: {}",
+            station,
+            forecast.iter().map(|(altitude, _)| altitude.to_string()).collect::<Vec<String>>().join(", "))
+    } else {
+        format!("{}: {}", station, selected.join(", "))
+    }
+}
+
+async fn winds(url: &str, station: &str) -> Result<Vec<(u32, Option<WindAloft>)>, WindsError> {
+    let result = REQWEST.get(url)
+        .header("Accept", "text/plain")
+        .timeout(time::Duration::from_secs(5))
+        .send()
+        .await;
+
+    match result {
+        Err(err) =>
+            Err(WindsError::ReqwestError(err)),
+        Ok(response) =>
+            if !response.status().is_success() {
+                Err(WindsError::NonSuccessResponse(response.status()))
+            } else {
+                match response.text().await {
+                    Err(err) =>
+                        Err(WindsError::ReqwestError(err)),
+                    Ok(bulletin) =>
+                        parse_bulletin(&bulletin, station).ok_or_else(|| WindsError::NoData(station.to_string())),
+                }
+            },
+    }
+}
+
+#[async_trait::async_trait]
+impl BotCommand for WindsCommand {
+    fn trigger(&self) -> &'static str {
+        "winds"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the winds and temperatures aloft forecast for a station"
+    }
+
+    async fn handle(&self, params: BotParameters<'_>) -> BotCommandResult {
+        let response_target = params.message
+            .response_target()
+            .ok_or(BotError::NoResponseTarget)?
+            .to_string();
+
+        let station = match params.args.first() {
+            Some(station) if STATION_RE.is_match(station) =>
+                station.to_uppercase(),
+            Some(station) =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("{} does not seem to be a valid station identifier", station))),
+            None =>
+                return Ok(BotResponse::Privmsg(
+                    response_target,
+                    format!("Usage: {}{} <station> [altitude in ft]",
+                        params.leaders.first().map_or("".to_string(), char::to_string),
+                        self.trigger()))),
+        };
+
+        // Winds aloft bulletins use three-letter identifiers for the contiguous US
+        let station = match station.strip_prefix('K') {
+            Some(id) if id.len() == 3 => id.to_string(),
+            _ => station,
+        };
+
+        let requested: Vec<u32> = match params.args.get(1) {
+            Some(altitude) =>
+                match altitude.trim_end_matches("ft").parse() {
+                    Ok(altitude) => vec![altitude],
+                    Err(_) =>
+                        return Ok(BotResponse::Privmsg(
+                            response_target,
+                            format!("{} is not a valid altitude", altitude))),
+                },
+            None =>
+                params.options.get("winds_altitudes").map_or(DEFAULT_ALTITUDES, String::as_str)
+                    .split(',')
+                    .filter_map(|altitude| altitude.trim().parse().ok())
+                    .collect(),
+        };
+
+        let url = params.options.get("winds_aloft_url").map_or(WINDS_API_URL, String::as_str);
+        let forecast = winds(url, &station).await?;
+        Ok(BotResponse::Privmsg(response_target, format_forecast(&station, &forecast, &requested)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static BULLETIN: &str = "000
+FBUS31 KWNO 121359
+FD1US1
+DATA BASED ON 121200Z
+VALID 121800Z   FOR USE 1400-2100Z. TEMPS NEG ABV 24000
+
+FT  3000    6000    9000   12000   18000   24000  30000  34000  39000
+BOS 2714 2725+05 2735+00 2745-05 2766-17 2781-29 780542 781052 771561
+DEN              2719+09 2727+03 2741-12 2753-24 256438 256948 257357
+MIA 9900 1108+19 1210+14 1312+09 1417-04 1520-16 162132 162442 162553
+";
+
+    #[test]
+    fn groups_are_decoded() {
+        assert_eq!(decode_group("2714"), Some(WindAloft { direction: Some(270), speed: 14, temperature: None }));
+        assert_eq!(decode_group("2745-05"), Some(WindAloft { direction: Some(270), speed: 45, temperature: Some(-5) }));
+        assert_eq!(decode_group("780542"), Some(WindAloft { direction: Some(280), speed: 105, temperature: Some(-42) }));
+        assert_eq!(decode_group("9900"), Some(WindAloft { direction: None, speed: 0, temperature: None }));
+        assert_eq!(decode_group("4012"), None);
+        assert_eq!(decode_group("27"), None);
+    }
+
+    #[test]
+    fn bulletin_is_parsed_by_column() {
+        let forecast = parse_bulletin(BULLETIN, "den").unwrap();
+        assert_eq!(forecast.len(), 9);
+        assert_eq!(forecast[0], (3000, None));
+        assert_eq!(forecast[1], (6000, None));
+        assert_eq!(forecast[2], (9000, Some(WindAloft { direction: Some(270), speed: 19, temperature: Some(9) })));
+        assert_eq!(forecast[8], (39000, Some(WindAloft { direction: Some(250), speed: 73, temperature: Some(-57) })));
+
+        assert!(parse_bulletin(BULLETIN, "SFO").is_none());
+    }
+
+    #[test]
+    fn requested_altitudes_are_selected() {
+        let forecast = parse_bulletin(BULLETIN, "BOS").unwrap();
+        assert_eq!(format_forecast("BOS", &forecast, &[6000]), "BOS: 6000ft 270@25kt +5C");
+        assert_eq!(format_forecast("BOS", &forecast, &[3000, 34000]), "BOS: 3000ft 270@14kt, 34000ft 280@110kt -52C");
+
+        let forecast = parse_bulletin(BULLETIN, "MIA").unwrap();
+        assert_eq!(format_forecast("MIA", &forecast, &[3000]), "MIA: 3000ft light and variable");
+
+        let forecast = parse_bulletin(BULLETIN, "DEN").unwrap();
+        assert_eq!(format_forecast("DEN", &forecast, &[3000]), "DEN: 3000ft n/a");
+        assert_eq!(format_forecast("DEN", &forecast, &[5000]),
+            "No winds aloft forecast for DEN at the requested altitude; available: 3000, 6000, 9000, 12000, 18000, 24000, 30000, 34000, 39000");
+    }
+}
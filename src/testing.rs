@@ -0,0 +1,161 @@
+//! Helpers shared by the tests of the modules, e.g. to invoke a command without a connection
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use futures::channel::mpsc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use irc::client::prelude::Prefix;
+use irc::proto::message::Message;
+
+use crate::{
+    BotParameters,
+    ErrorLog,
+    InvocationContext,
+    Stats,
+    VerboseChannels,
+};
+
+/**
+ * The state a command invocation borrows from the dispatcher. Tests set the fields they need and
+ * create the parameters of an invocation using params().
+ */
+pub struct Fixture {
+    pub leaders: Vec<char>,
+    pub owners: RwLock<Vec<Prefix>>,
+    pub owner_accounts: Vec<String>,
+    pub ignored: RwLock<Vec<Prefix>>,
+    pub options: HashMap<String, String>,
+    pub errors: ErrorLog,
+    pub verbose: VerboseChannels,
+    pub stats: Stats,
+    pub channels: Vec<String>,
+    pub responder: mpsc::UnboundedSender<crate::BotResponse>,
+}
+
+impl Default for Fixture {
+    fn default() -> Fixture {
+        let (responder, _) = mpsc::unbounded();
+        Fixture {
+            leaders: vec!['&'],
+            owners: RwLock::new(vec![Prefix::new_from_str("owner!*@*")]),
+            owner_accounts: Vec::new(),
+            ignored: RwLock::new(Vec::new()),
+            options: HashMap::new(),
+            errors: ErrorLog::default(),
+            verbose: VerboseChannels::default(),
+            stats: Stats::default(),
+            channels: Vec::new(),
+            responder,
+        }
+    }
+}
+
+impl Fixture {
+    /**
+     * Create a fixture with the given options.
+     */
+    pub fn with_options(options: &[(&str, &str)]) -> Fixture {
+        Fixture {
+            options: options.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            ..Fixture::default()
+        }
+    }
+
+    /**
+     * The parameters of an invocation by the given raw IRC message, e.g. ":alice!a@b PRIVMSG #chan
+     * :&metar KSFO", with the given arguments.
+     */
+    pub fn params(&self, raw: &str, args: &[&str]) -> BotParameters<'_> {
+        let message = message(raw);
+        let context = InvocationContext::new(&message, None);
+        BotParameters {
+            message,
+            leaders: &self.leaders,
+            owners: &self.owners,
+            owner_accounts: &self.owner_accounts,
+            ignored: &self.ignored,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            options: &self.options,
+            responder: self.responder.clone(),
+            errors: &self.errors,
+            verbose: &self.verbose,
+            stats: &self.stats,
+            network: "irc.example.org",
+            current_nick: "metarbot".to_string(),
+            channels: self.channels.clone(),
+            commands: &[],
+            modules: &[],
+            context,
+        }
+    }
+}
+
+/**
+ * Parse a raw IRC message.
+ */
+pub fn message(raw: &str) -> Message {
+    raw.parse().unwrap()
+}
+
+/**
+ * A raw HTTP response with the given status, content type and body that closes the connection.
+ */
+pub fn http_response(status: u16, content_type: &str, body: &str) -> String {
+    format!("HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body)
+}
+
+/**
+ * Return true iff the given raw HTTP request has been received completely: its head and as many
+ * bytes of body as its Content-Length header announces.
+ */
+fn is_complete(request: &[u8]) -> bool {
+    let head_length = match request.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(position) => position + 4,
+        None => return false,
+    };
+    let body_length = String::from_utf8_lossy(&request[..head_length]).lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("content-length") => value.trim().parse().ok(),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(0);
+    request.len() >= head_length + body_length
+}
+
+/**
+ * Serve the given raw HTTP responses on a local port, one per connection and in order. Returns the
+ * base URL of the server, e.g. http://127.0.0.1:1234, and a handle that resolves to the requests it
+ * received, including their bodies, once all responses have been sent.
+ */
+pub async fn http_server(responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !is_complete(&request) {
+                match socket.read(&mut buffer).await.unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            requests.push(String::from_utf8_lossy(&request).into_owned());
+            socket.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    });
+    (url, server)
+}
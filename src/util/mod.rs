@@ -5,10 +5,51 @@
 
 extern crate irc;
 
+use std::collections::HashMap;
+use std::fs;
+use std::io;
 use std::vec::Vec;
 
 use irc::client::prelude::Prefix;
 use irc::client::prelude::ChannelExt;
+use irc::proto::message::Message;
+
+use crate::{
+    BotCommandResult,
+    BotParameters,
+    BotResponse,
+};
+
+/**
+ * How the empty components of an owner entry are matched against the prefix of a user.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OwnerMatching {
+    /**
+     * Empty components implicitly match everything, so "alice" matches alice regardless of
+     * username and hostname. This is the default.
+     */
+    Wildcard,
+
+    /**
+     * Empty components are taken literally and only match empty strings, so "alice" only
+     * matches a prefix that consists of the nickname alice without username or hostname.
+     */
+    Strict,
+}
+
+impl OwnerMatching {
+    /**
+     * Determine the owner matching mode from the strict_owner_matching configuration option,
+     * defaulting to OwnerMatching::Wildcard.
+     */
+    pub fn from_options(options: &HashMap<String, String>) -> OwnerMatching {
+        match options.get("strict_owner_matching").map(String::as_str) {
+            Some("true") => OwnerMatching::Strict,
+            _ => OwnerMatching::Wildcard,
+        }
+    }
+}
 
 /**
  * Determine whether the given IRC prefix (i.e. tuple of (nickname, username, hostname)) matches
@@ -17,8 +58,32 @@ use irc::client::prelude::ChannelExt;
  * strings will implicitly match everything, unless all three parts are empty, in which case the
  * entry is ignored.
  */
-pub fn is_owner(prefix: &Prefix, owners: &Vec<Prefix>) -> bool {
-    let compile_and_test = |pattern, haystack| {
+pub fn is_owner(prefix: &Prefix, owners: &[Prefix]) -> bool {
+    is_owner_with(prefix, owners, OwnerMatching::Wildcard)
+}
+
+/**
+ * Like is_owner, but lets the caller choose how empty components of the owner entries are
+ * matched. With OwnerMatching::Wildcard, this behaves exactly like is_owner. With
+ * OwnerMatching::Strict, an empty component only matches an empty component of the prefix. In both
+ * modes, entries where all three parts are empty are ignored.
+ */
+pub fn is_owner_with(prefix: &Prefix, owners: &[Prefix], matching: OwnerMatching) -> bool {
+    owners.iter().any(|owner| prefix_matches(prefix, owner, matching))
+}
+
+/**
+ * Determine whether the given IRC prefix matches the given pattern, whose nickname, username and
+ * hostname are glob expressions. Empty components of the pattern are matched according to
+ * matching, as described for is_owner_with. Patterns where all three parts are empty never match,
+ * and neither do server names.
+ */
+pub fn prefix_matches(prefix: &Prefix, pattern: &Prefix, matching: OwnerMatching) -> bool {
+    let compile_and_test = |pattern: &str, haystack: &str| {
+        if pattern.is_empty() {
+            return matching == OwnerMatching::Wildcard || haystack.is_empty();
+        }
+
         match glob::Pattern::new(pattern) {
             Err(err) => {
                 warn!("Failed to compile pattern '{}': {}", pattern, err);
@@ -30,35 +95,141 @@ pub fn is_owner(prefix: &Prefix, owners: &Vec<Prefix>) -> bool {
         }
     };
 
-    match prefix {
-        Prefix::ServerName(_) =>
+    match (prefix, pattern) {
+        (Prefix::Nickname(nick, user, host), Prefix::Nickname(pattern_nick, pattern_user, pattern_host)) => {
+            if pattern_nick.is_empty() && pattern_user.is_empty() && pattern_host.is_empty() {
+                return false;
+            }
+            compile_and_test(pattern_nick, nick)
+                && compile_and_test(pattern_user, user)
+                && compile_and_test(pattern_host, host)
+        },
+        _ =>
             false,
-        Prefix::Nickname(nick, user, host) => {
-            for owner in owners {
-                if let Prefix::Nickname(owner_nick, owner_user, owner_host) = owner {
-                    if owner_nick.is_empty() && owner_user.is_empty() && owner_host.is_empty() {
-                        continue
-                    }
-                    if !owner_nick.is_empty() {
-                        if !compile_and_test(&owner_nick, &nick) {
-                            continue
-                        }
-                    }
-                    if !owner_user.is_empty() {
-                        if !compile_and_test(&owner_user, &user) {
-                            continue
-                        }
-                    }
-                    if !owner_host.is_empty() {
-                        if !compile_and_test(&owner_host, &host) {
-                            continue
-                        }
-                    }
-                    return true;
-                }
+    }
+}
+
+/**
+ * Determine whether the services account given in the account tag of the message is one of the
+ * given owner accounts. Account names are compared case-insensitively. Messages without an account
+ * tag never match, e.g. when the server does not support the account-tag capability or the user is
+ * not logged in.
+ */
+pub fn is_owner_by_account(message: &Message, owner_accounts: &[String]) -> bool {
+    match message_tag(message, "account") {
+        None | Some("*") =>
+            false,
+        Some(account) =>
+            owner_accounts.iter().any(|owner| owner.eq_ignore_ascii_case(account)),
+    }
+}
+
+/**
+ * Determine whether the sender of the given message is an owner, i.e. whether either its prefix
+ * matches one of the owners according to is_owner_with, or its account is one of the owner_accounts.
+ */
+pub fn is_message_from_owner(message: &Message, owners: &[Prefix], owner_accounts: &[String], matching: OwnerMatching) -> bool {
+    let prefix = message.prefix.clone().unwrap_or_else(|| Prefix::new_from_str(""));
+    is_owner_with(&prefix, owners, matching) || is_owner_by_account(message, owner_accounts)
+}
+
+/**
+ * Load the list of ignored users from the semicolon-separated ignore option, in the same format as
+ * the owners option.
+ */
+pub fn load_ignored(options: &HashMap<String, String>) -> Vec<Prefix> {
+    options.get("ignore").map_or("", String::as_str)
+        .split(';')
+        .filter(|pattern| !pattern.trim().is_empty())
+        .map(|pattern| Prefix::new_from_str(pattern.trim()))
+        .collect()
+}
+
+/**
+ * Load the list of owner accounts from the comma-separated owner_accounts option. Leading and
+ * trailing whitespace and empty entries are ignored.
+ */
+pub fn load_owner_accounts(options: &HashMap<String, String>) -> Vec<String> {
+    options.get("owner_accounts").map_or("", String::as_str)
+        .split(',')
+        .map(str::trim)
+        .filter(|account| !account.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/**
+ * Load the list of owners from the configuration: the semicolon-separated owners option, followed
+ * by the entries of the file given in the owners_file option, if any. The file contains one owner
+ * per line; empty lines and lines starting with # are ignored, and so are entries that are not
+ * valid glob patterns, with a warning.
+ */
+pub fn load_owners(options: &HashMap<String, String>) -> io::Result<Vec<Prefix>> {
+    let mut owners: Vec<Prefix> = options.get("owners").map_or("", String::as_str)
+        .split(';')
+        .map(Prefix::new_from_str)
+        .collect();
+
+    if let Some(owners_file) = options.get("owners_file") {
+        for line in fs::read_to_string(owners_file)?.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            let owner = Prefix::new_from_str(line);
+            let invalid = match owner {
+                Prefix::Nickname(ref nick, ref user, ref host) =>
+                    [nick, user, host].iter().find_map(|pattern| glob::Pattern::new(pattern).err()),
+                Prefix::ServerName(_) =>
+                    None,
             };
-            false
-        },
+            match invalid {
+                Some(err) => warn!("Skipping owner '{}' in {}: {}", line, owners_file, err),
+                None => owners.push(owner),
+            }
+        }
+    }
+    Ok(owners)
+}
+
+/**
+ * Function to ensure that the person sending the message is the owner of the bot. If that is the
+ * case, None will be returned, and execution of the command should continue. Otherwise, a suitable
+ * error message is returned as a BotCommandResult, which should be bubbled up to the caller.
+ */
+pub fn ensure_owner(command: &str, params: &BotParameters<'_>) -> Option<BotCommandResult> {
+    let matching = OwnerMatching::from_options(params.options);
+    let owners = params.owners.read().unwrap_or_else(|err| err.into_inner());
+    if !is_message_from_owner(&params.message, &owners, params.owner_accounts, matching) {
+        if let Some(source_nickname) = params.message.source_nickname() {
+            Some(Ok(BotResponse::Notice(
+                source_nickname.to_string(),
+                format!("You are not authorized to use the {} command", command))))
+        } else {
+            Some(Ok(BotResponse::Ignore))
+        }
+    } else {
+        None
+    }
+}
+
+/**
+ * Function to ensure that the command has been invoked in a query rather than in a channel, for
+ * commands whose output should not be shown to everybody in the channel. Like ensure_owner, None is
+ * returned if execution of the command should continue, and a suitable error message otherwise.
+ */
+pub fn ensure_query(command: &str, params: &BotParameters<'_>) -> Option<BotCommandResult> {
+    if params.message.response_target().map_or(false, is_public) {
+        Some(Ok(match params.message.source_nickname() {
+            Some(source_nickname) =>
+                BotResponse::Notice(
+                    source_nickname.to_string(),
+                    format!("The {} command is only available in a query", command)),
+            None =>
+                BotResponse::Ignore,
+        }))
+    } else {
+        None
     }
 }
 
@@ -69,3 +240,521 @@ pub fn is_owner(prefix: &Prefix, owners: &Vec<Prefix>) -> bool {
 pub fn is_public(target: &str) -> bool {
     target.is_channel_name()
 }
+
+/**
+ * The maximum length of a nickname accepted by is_valid_nickname. Servers announce their actual
+ * limit in ISUPPORT, which is commonly between 9 and 30 characters.
+ */
+const MAX_NICKNAME_LENGTH: usize = 30;
+
+/**
+ * Return true iff the given string follows the basic IRC nickname rules: it starts with a letter or
+ * one of the special characters []\`_^{|}, continues with letters, digits, special characters or
+ * dashes, and is not longer than 30 characters. The server may still reject the nickname, e.g. if
+ * its own length limit is lower.
+ */
+pub fn is_valid_nickname(nickname: &str) -> bool {
+    let special = |c: char| "[]\\`_^{|}".contains(c);
+    let mut chars = nickname.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || special(first) =>
+            nickname.len() <= MAX_NICKNAME_LENGTH
+                && chars.all(|c| c.is_ascii_alphanumeric() || special(c) || c == '-'),
+        _ =>
+            false,
+    }
+}
+
+/**
+ * Look up the value of the IRCv3 message tag with the given key, e.g. "time", "account" or "msgid".
+ * Returns None if the message does not have the tag, or if the tag has no value.
+ */
+pub fn message_tag<'a>(message: &'a Message, key: &str) -> Option<&'a str> {
+    message.tags.as_ref()?
+        .iter()
+        .find(|tag| tag.0 == key)?
+        .1.as_deref()
+}
+
+/**
+ * Return true iff the given message was sent by the bot itself, i.e. its source nickname matches
+ * the bot's current nickname. Servers that negotiated the echo-message capability send our own
+ * PRIVMSGs back to us, and those must never be dispatched as commands.
+ */
+pub fn is_own_message(message: &Message, current_nickname: &str) -> bool {
+    match message.source_nickname() {
+        Some(nickname) => nickname.eq_ignore_ascii_case(current_nickname),
+        None => false,
+    }
+}
+
+/**
+ * Replace the values of configuration options that look like secrets (API keys, passwords and
+ * tokens) in the given text, so it can be shown to users or stored without leaking them.
+ */
+pub fn redact(text: &str, options: &HashMap<String, String>) -> String {
+    let mut text = text.to_string();
+    for (key, value) in options {
+        let key = key.to_lowercase();
+        if !value.is_empty() && ["key", "password", "secret", "token"].iter().any(|secret| key.contains(secret)) {
+            text = text.replace(value.as_str(), "<redacted>");
+        }
+    }
+    text
+}
+
+/**
+ * Compute the Levenshtein distance between two strings, i.e. the minimum number of characters that
+ * have to be inserted, deleted or substituted to turn one into the other.
+ */
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/**
+ * Return true iff the given message text starts with a leader that has been escaped, i.e. a leader
+ * followed by whitespace or by another leader (e.g. "& metar" or "&&metar"). Such messages refer to
+ * a command literally, e.g. when explaining how to use the bot, and should not be dispatched.
+ */
+pub fn is_escaped_leader(text: &str, leaders: &[char]) -> bool {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(first), Some(second)) =>
+            leaders.contains(&first) && (second.is_whitespace() || leaders.contains(&second)),
+        _ =>
+            false,
+    }
+}
+
+/**
+ * Return true iff the command with the given trigger may be used in the given channel. Commands can
+ * be restricted per channel with options such as "channel_commands.#chan" = "metar,taf"; channels
+ * without such an option, and queries (a channel of None), allow all commands.
+ */
+pub fn is_command_enabled(channel: Option<&str>, trigger: &str, options: &HashMap<String, String>) -> bool {
+    let channel = match channel {
+        Some(channel) => channel,
+        None => return true,
+    };
+
+    let restriction = options.iter()
+        .find(|(key, _)| key.strip_prefix("channel_commands.").map_or(false, |key| key.eq_ignore_ascii_case(channel)));
+    match restriction {
+        Some((_, commands)) =>
+            commands.split(',').map(str::trim).any(|command| command.eq_ignore_ascii_case(trigger)),
+        None =>
+            true,
+    }
+}
+
+/**
+ * Split command text into arguments at whitespace, like str::split_whitespace, but keep text in
+ * single or double quotes together as one argument, e.g. `"in 5 minutes" do it` yields the three
+ * arguments "in 5 minutes", "do" and "it". Quotes are only recognized at the start of an argument,
+ * so apostrophes in words like "don't" are kept. Within double quotes, a backslash escapes the next
+ * character. Returns None if a quote is not terminated.
+ */
+pub fn tokenize(text: &str) -> Option<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut token = String::new();
+        match chars.next() {
+            None =>
+                return Some(tokens),
+            Some(quote) if quote == '"' || quote == '\'' => {
+                loop {
+                    match chars.next() {
+                        None => return None,
+                        Some(c) if c == quote => break,
+                        Some('\\') if quote == '"' => token.push(chars.next()?),
+                        Some(c) => token.push(c),
+                    }
+                }
+            },
+            Some(c) => {
+                token.push(c);
+                while let Some(c) = chars.peek().filter(|c| !c.is_whitespace()) {
+                    token.push(*c);
+                    chars.next();
+                }
+            },
+        }
+        tokens.push(token);
+    }
+}
+
+/**
+ * Return the text of a CTCP ACTION, i.e. a message sent using /me, without the CTCP framing, or the
+ * given text unchanged if it is not an ACTION.
+ */
+pub fn strip_action(text: &str) -> &str {
+    match text.strip_prefix("\u{1}ACTION ") {
+        Some(action) => action.strip_suffix('\u{1}').unwrap_or(action),
+        None => text,
+    }
+}
+
+/**
+ * Parse a CTCP request, e.g. "\x01PING 1234\x01", into its command and parameters, e.g. ("PING",
+ * "1234"). Returns None for text without CTCP framing and for ACTIONs, which are not requests.
+ */
+pub fn ctcp_request(text: &str) -> Option<(&str, &str)> {
+    let request = text.strip_prefix('\u{1}')?;
+    let request = request.strip_suffix('\u{1}').unwrap_or(request);
+    let (command, params) = match request.find(' ') {
+        Some(space) => (&request[..space], &request[space + 1..]),
+        None => (request, ""),
+    };
+    if command.is_empty() || command == "ACTION" {
+        None
+    } else {
+        Some((command, params))
+    }
+}
+
+/**
+ * Strip trailing "?", "!" and "." from the last of the given command tokens, so natural phrasing
+ * such as "metar KSFO?" works. The command itself, i.e. a single token, is left alone, and a last
+ * token that consists only of punctuation is removed.
+ */
+pub fn strip_trailing_punctuation(tokens: &mut Vec<String>) {
+    if tokens.len() < 2 {
+        return;
+    }
+    if let Some(last) = tokens.last_mut() {
+        last.truncate(last.trim_end_matches(&['?', '!', '.'][..]).len());
+        if last.is_empty() {
+            tokens.pop();
+        }
+    }
+}
+
+/**
+ * The maximum length of an IRC line including the trailing CRLF.
+ */
+const MAX_LINE_LENGTH: usize = 512;
+
+/**
+ * A conservative estimate of the length of the prefix the server adds to our messages when relaying
+ * them: a colon, a nickname, a username and a hostname of the usual maximum lengths, and the
+ * separators.
+ */
+const PREFIX_LENGTH_ESTIMATE: usize = 1 + 30 + 1 + 10 + 1 + 63 + 1;
+
+/**
+ * The maximum number of bytes of text that can be sent in a single PRIVMSG or NOTICE to the given
+ * target without being truncated by the server when it relays the message. Can be lowered with the
+ * max_line_length option, which takes the number of bytes of text per message.
+ */
+pub fn max_message_length(target: &str, options: &HashMap<String, String>) -> usize {
+    // Targets longer than the line leave no room for text, but at least one byte is still sent
+    let limit = (MAX_LINE_LENGTH - PREFIX_LENGTH_ESTIMATE - "PRIVMSG  :\r\n".len()).saturating_sub(target.len());
+    options.get("max_line_length")
+        .and_then(|length| length.parse().ok())
+        .map_or(limit, |length: usize| length.min(limit))
+        .max(1)
+}
+
+/**
+ * Split the given text into lines of at most max_length bytes at word boundaries. Text that fits is
+ * returned unchanged; otherwise, the text is broken at the last whitespace that fits on each line,
+ * which is dropped, and all other whitespace is kept. Words that are longer than max_length on their
+ * own are split at a character boundary.
+ */
+pub fn split_message(text: &str, max_length: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while rest.len() > max_length {
+        let space = rest.char_indices()
+            .take_while(|(index, _)| *index <= max_length)
+            .filter(|(index, c)| *index > 0 && c.is_whitespace())
+            .last();
+        match space {
+            Some((index, space)) => {
+                lines.push(rest[..index].to_string());
+                rest = &rest[index + space.len_utf8()..];
+            },
+            None => {
+                let mut split = max_length;
+                while !rest.is_char_boundary(split) {
+                    split -= 1;
+                }
+                if split == 0 {
+                    split = rest.chars().next().map_or(rest.len(), char::len_utf8);
+                }
+                lines.push(rest[..split].to_string());
+                rest = &rest[split..];
+            },
+        }
+    }
+    if !rest.is_empty() || lines.is_empty() {
+        lines.push(rest.to_string());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(raw: &str) -> Message {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn own_message_is_detected() {
+        assert!(is_own_message(&message(":metarbot!bot@example.org PRIVMSG #weather :&metar KSFO"), "metarbot"));
+        assert!(is_own_message(&message(":MetarBot!bot@example.org PRIVMSG #weather :&metar KSFO"), "metarbot"));
+    }
+
+    #[test]
+    fn message_from_others_is_not_own() {
+        assert!(!is_own_message(&message(":alice!alice@example.org PRIVMSG #weather :&metar KSFO"), "metarbot"));
+        assert!(!is_own_message(&message("PRIVMSG #weather :&metar KSFO"), "metarbot"));
+    }
+
+    #[test]
+    fn empty_owner_components_match_everything_by_default() {
+        let owners = vec![Prefix::new_from_str("alice")];
+        assert!(is_owner_with(&Prefix::new_from_str("alice!alice@example.org"), &owners, OwnerMatching::Wildcard));
+        assert!(is_owner(&Prefix::new_from_str("alice!alice@example.org"), &owners));
+        assert!(!is_owner_with(&Prefix::new_from_str("bob!alice@example.org"), &owners, OwnerMatching::Wildcard));
+    }
+
+    #[test]
+    fn empty_owner_components_are_literal_in_strict_mode() {
+        let owners = vec![Prefix::new_from_str("alice")];
+        assert!(!is_owner_with(&Prefix::new_from_str("alice!alice@example.org"), &owners, OwnerMatching::Strict));
+        assert!(is_owner_with(&Prefix::Nickname("alice".to_string(), "".to_string(), "".to_string()), &owners, OwnerMatching::Strict));
+
+        let owners = vec![Prefix::new_from_str("alice!*@*.example.org")];
+        assert!(is_owner_with(&Prefix::new_from_str("alice!alice@home.example.org"), &owners, OwnerMatching::Strict));
+    }
+
+    #[test]
+    fn entirely_empty_owner_never_matches() {
+        let owners = vec![Prefix::Nickname("".to_string(), "".to_string(), "".to_string())];
+        for matching in &[OwnerMatching::Wildcard, OwnerMatching::Strict] {
+            assert!(!is_owner_with(&Prefix::new_from_str("alice!alice@example.org"), &owners, *matching));
+        }
+    }
+
+    #[test]
+    fn strict_owner_matching_option() {
+        let mut options = HashMap::new();
+        assert_eq!(OwnerMatching::from_options(&options), OwnerMatching::Wildcard);
+        options.insert("strict_owner_matching".to_string(), "true".to_string());
+        assert_eq!(OwnerMatching::from_options(&options), OwnerMatching::Strict);
+    }
+
+    #[test]
+    fn escaped_leaders_are_detected() {
+        let leaders = ['&', '!'];
+        assert!(is_escaped_leader("&&metar KSFO", &leaders));
+        assert!(is_escaped_leader("& metar KSFO", &leaders));
+        assert!(is_escaped_leader("&!metar", &leaders));
+        assert!(!is_escaped_leader("&metar KSFO", &leaders));
+        assert!(!is_escaped_leader("&", &leaders));
+        assert!(!is_escaped_leader("", &leaders));
+        assert!(!is_escaped_leader("hello && goodbye", &leaders));
+        assert!(!is_escaped_leader("&& metar", &[]));
+    }
+
+    fn stripped(text: &str) -> Vec<String> {
+        let mut tokens = tokenize(text).unwrap();
+        strip_trailing_punctuation(&mut tokens);
+        tokens
+    }
+
+    #[test]
+    fn trailing_punctuation_is_stripped_from_the_last_argument() {
+        assert_eq!(stripped("metar KSFO?"), vec!["metar", "KSFO"]);
+        assert_eq!(stripped("metar KSFO EDDF!?"), vec!["metar", "KSFO", "EDDF"]);
+        assert_eq!(stripped("metar KSFO.?"), vec!["metar", "KSFO"]);
+        assert_eq!(stripped("metar KSFO ?"), vec!["metar", "KSFO"]);
+        assert_eq!(stripped("metar K?SFO"), vec!["metar", "K?SFO"]);
+        assert_eq!(stripped("help?"), vec!["help?"]);
+    }
+
+    #[test]
+    fn action_framing_is_stripped() {
+        assert_eq!(strip_action("\u{1}ACTION &metar KSFO\u{1}"), "&metar KSFO");
+        assert_eq!(strip_action("\u{1}ACTION &metar KSFO"), "&metar KSFO");
+        assert_eq!(strip_action("&metar KSFO"), "&metar KSFO");
+        assert_eq!(strip_action("\u{1}VERSION\u{1}"), "\u{1}VERSION\u{1}");
+    }
+
+    fn options(options: &[(&str, &str)]) -> HashMap<String, String> {
+        options.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn commands_are_restricted_per_channel() {
+        let options = options(&[("channel_commands.#Weather", "metar, taf")]);
+        assert!(is_command_enabled(Some("#weather"), "metar", &options));
+        assert!(is_command_enabled(Some("#WEATHER"), "taf", &options));
+        assert!(!is_command_enabled(Some("#weather"), "roll", &options));
+        assert!(is_command_enabled(Some("#games"), "roll", &options));
+        assert!(is_command_enabled(None, "roll", &options));
+    }
+
+    #[test]
+    fn empty_restriction_disables_all_commands() {
+        let options = options(&[("channel_commands.#quiet", "")]);
+        assert!(!is_command_enabled(Some("#quiet"), "metar", &options));
+        assert!(is_command_enabled(Some("#weather"), "metar", &HashMap::new()));
+    }
+
+    #[test]
+    fn quoted_arguments_are_kept_together() {
+        assert_eq!(tokenize(r#""in 5 minutes" do the thing"#).unwrap(), vec!["in 5 minutes", "do", "the", "thing"]);
+        assert_eq!(tokenize("'single  quoted'\tnext").unwrap(), vec!["single  quoted", "next"]);
+        assert_eq!(tokenize(r#"say "" and ''"#).unwrap(), vec!["say", "", "and", ""]);
+        assert_eq!(tokenize("  plain\twhitespace  \n").unwrap(), vec!["plain", "whitespace"]);
+        assert_eq!(tokenize("").unwrap(), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn quotes_inside_words_are_literal() {
+        assert_eq!(tokenize("don't stop").unwrap(), vec!["don't", "stop"]);
+        assert_eq!(tokenize(r#"say"hi" there"#).unwrap(), vec![r#"say"hi""#, "there"]);
+    }
+
+    #[test]
+    fn backslashes_escape_in_double_quotes_only() {
+        assert_eq!(tokenize(r#""a \"quoted\" word" x"#).unwrap(), vec![r#"a "quoted" word"#, "x"]);
+        assert_eq!(tokenize(r#""back\\slash""#).unwrap(), vec![r"back\slash"]);
+        assert_eq!(tokenize(r"'it\' s").unwrap(), vec![r"it\", "s"]);
+        assert_eq!(tokenize(r"C:\temp").unwrap(), vec![r"C:\temp"]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert_eq!(tokenize(r#""in 5 minutes do"#), None);
+        assert_eq!(tokenize("'open"), None);
+        assert_eq!(tokenize(r#""escaped end\""#), None);
+        assert_eq!(tokenize(r#""trailing backslash \"#), None);
+    }
+
+    #[test]
+    fn ctcp_requests_are_parsed_from_their_framing() {
+        assert_eq!(ctcp_request("\u{1}VERSION\u{1}"), Some(("VERSION", "")));
+        assert_eq!(ctcp_request("\u{1}PING 1602662400 123\u{1}"), Some(("PING", "1602662400 123")));
+        assert_eq!(ctcp_request("\u{1}TIME"), Some(("TIME", "")));
+        assert_eq!(ctcp_request("\u{1}ACTION waves\u{1}"), None);
+        assert_eq!(ctcp_request("\u{1}\u{1}"), None);
+        assert_eq!(ctcp_request("VERSION"), None);
+        assert_eq!(ctcp_request("&metar \u{1}VERSION\u{1}"), None);
+    }
+
+    #[test]
+    fn messages_that_fit_are_unchanged() {
+        assert_eq!(split_message("", 10), vec![""]);
+        assert_eq!(split_message("  two  spaces ", 14), vec!["  two  spaces "]);
+        assert_eq!(split_message("exactly 10", 10), vec!["exactly 10"]);
+        assert_eq!(split_message("line\tbreak", 10), vec!["line\tbreak"]);
+    }
+
+    #[test]
+    fn long_messages_are_split_at_the_last_fitting_space() {
+        assert_eq!(split_message("exactly 10!", 10), vec!["exactly", "10!"]);
+        assert_eq!(split_message("TAF EDDF  140500Z 1406/1512", 10), vec!["TAF EDDF ", "140500Z", "1406/1512"]);
+        assert_eq!(split_message("one two three", 8), vec!["one two", "three"]);
+        assert_eq!(split_message("trailing ", 8), vec!["trailing"]);
+
+        let text = "BECMG 1410/1412 27010KT TEMPO 1412/1418 4000 SHRA BKN012 ".repeat(20);
+        for line in split_message(&text, 60) {
+            assert!(line.len() <= 60, "{:?}", line);
+            assert!(text.contains(&line), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn long_words_are_split_at_character_boundaries() {
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(split_message("über", 1), vec!["ü", "b", "e", "r"]);
+        assert_eq!(split_message("äöü äöü", 6), vec!["äöü", "äöü"]);
+        assert_eq!(split_message("äöü äöü", 5), vec!["äö", "ü", "äö", "ü"]);
+        assert_eq!(split_message("日本語", 4), vec!["日", "本", "語"]);
+        for line in split_message(&"ÄÖÜ ".repeat(50), 17) {
+            assert!(line.len() <= 17, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn message_length_accounts_for_target_and_overhead() {
+        let overhead = PREFIX_LENGTH_ESTIMATE + "PRIVMSG  :\r\n".len();
+        assert_eq!(max_message_length("#weather", &HashMap::new()), MAX_LINE_LENGTH - overhead - "#weather".len());
+        assert_eq!(max_message_length("#a-much-longer-channel", &HashMap::new()) + "#a-much-longer-channel".len(),
+            MAX_LINE_LENGTH - overhead);
+
+        let options = |length: &str| vec![("max_line_length".to_string(), length.to_string())].into_iter().collect();
+        assert_eq!(max_message_length("#weather", &options("100")), 100);
+        assert_eq!(max_message_length("#weather", &options("1000")), MAX_LINE_LENGTH - overhead - "#weather".len());
+        assert_eq!(max_message_length("#weather", &options("0")), 1);
+        assert_eq!(max_message_length("#weather", &options("many")), max_message_length("#weather", &HashMap::new()));
+    }
+
+    #[test]
+    fn message_length_of_overlong_target_is_one() {
+        let target = format!("#{}", "a".repeat(399));
+        assert_eq!(max_message_length(&target, &HashMap::new()), 1);
+        assert_eq!(max_message_length(&target, &[("max_line_length".to_string(), "100".to_string())].iter().cloned().collect()), 1);
+    }
+
+    #[test]
+    fn message_tags_are_looked_up_by_key() {
+        let tagged = message("@time=2020-10-14T05:43:29.000Z;account=alice;+draft/reply=abc;solanum.chat/ip;msgid= :alice!a@example.org PRIVMSG #weather :&metar KSFO");
+        assert_eq!(message_tag(&tagged, "time"), Some("2020-10-14T05:43:29.000Z"));
+        assert_eq!(message_tag(&tagged, "account"), Some("alice"));
+        assert_eq!(message_tag(&tagged, "+draft/reply"), Some("abc"));
+        assert_eq!(message_tag(&tagged, "Account"), None);
+        assert_eq!(message_tag(&tagged, "batch"), None);
+        assert_eq!(message_tag(&tagged, "solanum.chat/ip"), None);
+
+        let untagged = message(":alice!a@example.org PRIVMSG #weather :&metar KSFO");
+        assert_eq!(message_tag(&untagged, "time"), None);
+    }
+
+    #[test]
+    fn edit_distance_counts_insertions_deletions_and_substitutions() {
+        assert_eq!(edit_distance("metar", "metar"), 0);
+        assert_eq!(edit_distance("", "taf"), 3);
+        assert_eq!(edit_distance("taf", ""), 3);
+        assert_eq!(edit_distance("metr", "metar"), 1);
+        assert_eq!(edit_distance("metaar", "metar"), 1);
+        assert_eq!(edit_distance("netar", "metar"), 1);
+        assert_eq!(edit_distance("mtear", "metar"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("wïnds", "winds"), 1);
+    }
+
+    #[test]
+    fn nicknames_follow_the_basic_rules() {
+        let longest = "a".repeat(MAX_NICKNAME_LENGTH);
+        for valid in &["alice", "Bob", "carol-2", "[away]", "_dave_", "`e^{|}\\", "x", longest.as_str()] {
+            assert!(is_valid_nickname(valid), "{} should be valid", valid);
+        }
+
+        let too_long = "a".repeat(MAX_NICKNAME_LENGTH + 1);
+        for invalid in &["", "2fast", "-dash", "al ice", "alice!", "#weather", "bob@host", "ålice", too_long.as_str()] {
+            assert!(!is_valid_nickname(invalid), "{} should be invalid", invalid);
+        }
+    }
+}